@@ -0,0 +1,1403 @@
+//! Decoder for the WebAssembly binary format.
+
+use std::convert::TryInto;
+use std::fmt;
+
+use crate::module::{
+    ConstExpr, Data, Elem, ElemMode, Export, ExportDesc, FuncType, Global, GlobalType, Import,
+    ImportDesc, Limits, Module, TableType,
+};
+use crate::{BlockType, Function, Instruction, MemArg, RefType, ValType, Value};
+
+const MAGIC: &[u8] = b"\0asm";
+const VERSION: u32 = 1;
+
+/// Most locals a function may declare, as in other engines. Bodies declare
+/// them as counts, so a few bytes could otherwise ask for gigabytes.
+const MAX_LOCALS: u64 = 50_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    UnexpectedEof,
+    BadMagic,
+    UnknownVersion(u32),
+    IntegerTooLong,
+    IntegerTooLarge,
+    InvalidSectionId(u8),
+    SectionOutOfOrder(u8),
+    SectionSizeMismatch,
+    InvalidValType(u8),
+    InvalidFuncType(u8),
+    InvalidLimits(u8),
+    InvalidMutability(u8),
+    InvalidRefType(u8),
+    InvalidImportKind(u8),
+    InvalidExportKind(u8),
+    InvalidElemSegment,
+    InvalidDataSegment,
+    ConstantExpressionRequired,
+    IllegalOpcode(u8),
+    IllegalPrefixedOpcode(u8, u32),
+    ZeroByteExpected,
+    MalformedUtf8,
+    FunctionCodeMismatch,
+    DataCountMismatch,
+    DataCountRequired,
+    TooManyLocals,
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::UnexpectedEof => write!(f, "unexpected end"),
+            ErrorKind::BadMagic => write!(f, "magic header not detected"),
+            ErrorKind::UnknownVersion(v) => write!(f, "unknown binary version {}", v),
+            ErrorKind::IntegerTooLong => write!(f, "integer representation too long"),
+            ErrorKind::IntegerTooLarge => write!(f, "integer too large"),
+            ErrorKind::InvalidSectionId(id) => write!(f, "malformed section id {}", id),
+            ErrorKind::SectionOutOfOrder(id) => {
+                write!(f, "unexpected content after section {}", id)
+            }
+            ErrorKind::SectionSizeMismatch => write!(f, "section size mismatch"),
+            ErrorKind::InvalidValType(b) => write!(f, "malformed value type {:#04x}", b),
+            ErrorKind::InvalidFuncType(b) => write!(f, "malformed function type {:#04x}", b),
+            ErrorKind::InvalidLimits(b) => write!(f, "malformed limits flags {:#04x}", b),
+            ErrorKind::InvalidMutability(b) => write!(f, "malformed mutability {:#04x}", b),
+            ErrorKind::InvalidRefType(b) => write!(f, "malformed reference type {:#04x}", b),
+            ErrorKind::InvalidImportKind(b) => write!(f, "malformed import kind {:#04x}", b),
+            ErrorKind::InvalidExportKind(b) => write!(f, "malformed export kind {:#04x}", b),
+            ErrorKind::InvalidElemSegment => write!(f, "malformed elements segment"),
+            ErrorKind::InvalidDataSegment => write!(f, "malformed data segment"),
+            ErrorKind::ConstantExpressionRequired => write!(f, "constant expression required"),
+            ErrorKind::IllegalOpcode(op) => write!(f, "illegal opcode {:#04x}", op),
+            ErrorKind::IllegalPrefixedOpcode(prefix, op) => {
+                write!(f, "illegal opcode {:#04x} {}", prefix, op)
+            }
+            ErrorKind::ZeroByteExpected => write!(f, "zero byte expected"),
+            ErrorKind::MalformedUtf8 => write!(f, "malformed UTF-8 encoding"),
+            ErrorKind::FunctionCodeMismatch => {
+                write!(f, "function and code section have inconsistent lengths")
+            }
+            ErrorKind::DataCountMismatch => {
+                write!(f, "data count and data section have inconsistent lengths")
+            }
+            ErrorKind::DataCountRequired => write!(f, "data count section required"),
+            ErrorKind::TooManyLocals => write!(f, "too many locals"),
+            ErrorKind::Unsupported(what) => write!(f, "unsupported: {}", what),
+        }
+    }
+}
+
+/// A malformed module, with the byte offset at which decoding failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    pub offset: usize,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at byte offset {}", self.kind, self.offset)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+type Result<T> = std::result::Result<T, DecodeError>;
+
+pub(crate) fn decode(bytes: &[u8]) -> Result<Module> {
+    Decoder::new(bytes).module()
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Decoder { bytes, pos: 0 }
+    }
+
+    fn error<T>(&self, kind: ErrorKind) -> Result<T> {
+        Err(DecodeError {
+            offset: self.pos,
+            kind,
+        })
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    /// A decoder over the next `len` bytes, keeping absolute offsets.
+    fn sub(&self, len: usize) -> Result<Decoder<'a>> {
+        match self.pos.checked_add(len) {
+            Some(end) if end <= self.bytes.len() => Ok(Decoder {
+                bytes: &self.bytes[..end],
+                pos: self.pos,
+            }),
+            _ => self.error(ErrorKind::UnexpectedEof),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        match self.bytes.get(self.pos) {
+            Some(b) => {
+                self.pos += 1;
+                Ok(*b)
+            }
+            None => self.error(ErrorKind::UnexpectedEof),
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let sub = self.sub(len)?;
+        self.pos += len;
+        Ok(&sub.bytes[sub.pos..])
+    }
+
+    fn leb(&mut self, bits: u32, signed: bool) -> Result<u64> {
+        let max_len = bits.div_ceil(7);
+        let mut result: u64 = 0;
+        let mut shift = 0;
+        for i in 0..max_len {
+            let byte = self.byte()?;
+            result |= u64::from(byte & 0x7f) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if i == max_len - 1 {
+                    // Unused bits of the final byte must be zero, or for signed
+                    // integers a sign extension of the value.
+                    let used = bits - 7 * (max_len - 1);
+                    let unused = byte & 0x7f & (0x7f << (used - u32::from(signed)));
+                    let expected = if signed && byte & (1 << (used - 1)) != 0 {
+                        0x7f & (0x7f << (used - 1))
+                    } else {
+                        0
+                    };
+                    if unused != expected {
+                        self.pos -= 1;
+                        return self.error(ErrorKind::IntegerTooLarge);
+                    }
+                }
+                if signed && shift < 64 && byte & 0x40 != 0 {
+                    result |= !0 << shift;
+                }
+                return Ok(result);
+            }
+        }
+        self.pos -= 1;
+        self.error(ErrorKind::IntegerTooLong)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(self.leb(32, false)? as u32)
+    }
+
+    fn usize(&mut self) -> Result<usize> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(self.leb(32, true)? as i32)
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        Ok(self.leb(64, true)? as i64)
+    }
+
+    fn f32(&mut self) -> Result<f32> {
+        let bytes = self.take(4)?;
+        Ok(f32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64> {
+        let bytes = self.take(8)?;
+        Ok(f64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let len = self.usize()?;
+        let start = self.pos;
+        let bytes = self.take(len)?;
+        match std::str::from_utf8(bytes) {
+            Ok(s) => Ok(s.to_string()),
+            Err(_) => Err(DecodeError {
+                offset: start,
+                kind: ErrorKind::MalformedUtf8,
+            }),
+        }
+    }
+
+    fn vec<T>(&mut self, mut item: impl FnMut(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+        let len = self.u32()?;
+        // Don't trust the length for preallocation, it may be bogus.
+        let mut items = Vec::with_capacity(len.min(1024) as usize);
+        for _ in 0..len {
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn val_type(&mut self) -> Result<ValType> {
+        match self.byte()? {
+            0x7f => Ok(ValType::I32),
+            0x7e => Ok(ValType::I64),
+            0x7d => Ok(ValType::F32),
+            0x7c => Ok(ValType::F64),
+            0x70 => Ok(ValType::FuncRef),
+            0x6f => Ok(ValType::ExternRef),
+            b => {
+                self.pos -= 1;
+                self.error(ErrorKind::InvalidValType(b))
+            }
+        }
+    }
+
+    fn ref_type(&mut self) -> Result<RefType> {
+        match self.byte()? {
+            0x70 => Ok(RefType::Func),
+            0x6f => Ok(RefType::Extern),
+            b => {
+                self.pos -= 1;
+                self.error(ErrorKind::InvalidRefType(b))
+            }
+        }
+    }
+
+    fn func_type(&mut self) -> Result<FuncType> {
+        match self.byte()? {
+            0x60 => {}
+            b => {
+                self.pos -= 1;
+                return self.error(ErrorKind::InvalidFuncType(b));
+            }
+        }
+        let params = self.vec(Self::val_type)?;
+        let results = self.vec(Self::val_type)?;
+        Ok(FuncType { params, results })
+    }
+
+    fn limits(&mut self) -> Result<Limits> {
+        match self.byte()? {
+            0x00 => Ok(Limits {
+                min: self.u32()?,
+                max: None,
+            }),
+            0x01 => Ok(Limits {
+                min: self.u32()?,
+                max: Some(self.u32()?),
+            }),
+            b => {
+                self.pos -= 1;
+                self.error(ErrorKind::InvalidLimits(b))
+            }
+        }
+    }
+
+    fn table(&mut self) -> Result<TableType> {
+        let elem = self.ref_type()?;
+        let limits = self.limits()?;
+        Ok(TableType { elem, limits })
+    }
+
+    fn import(&mut self) -> Result<Import> {
+        let module = self.name()?;
+        let name = self.name()?;
+        let desc = match self.byte()? {
+            0x00 => ImportDesc::Func(self.usize()?),
+            0x01 => ImportDesc::Table(self.table()?),
+            0x02 => ImportDesc::Memory(self.limits()?),
+            0x03 => ImportDesc::Global(self.global_type()?),
+            b => {
+                self.pos -= 1;
+                return self.error(ErrorKind::InvalidImportKind(b));
+            }
+        };
+        Ok(Import { module, name, desc })
+    }
+
+    fn export(&mut self) -> Result<Export> {
+        let name = self.name()?;
+        let kind = self.byte()?;
+        let index = self.usize()?;
+        let desc = match kind {
+            0x00 => ExportDesc::Func(index),
+            0x01 => ExportDesc::Table(index),
+            0x02 => ExportDesc::Memory(index),
+            0x03 => ExportDesc::Global(index),
+            b => return self.error(ErrorKind::InvalidExportKind(b)),
+        };
+        Ok(Export { name, desc })
+    }
+
+    /// An initializer made of a single constant instruction and `end`.
+    fn const_expr(&mut self) -> Result<ConstExpr> {
+        let expr = match self.byte()? {
+            0x41 => ConstExpr::Const(Value::I32(self.i32()?)),
+            0x42 => ConstExpr::Const(Value::I64(self.i64()?)),
+            0x43 => ConstExpr::Const(Value::F32(self.f32()?)),
+            0x44 => ConstExpr::Const(Value::F64(self.f64()?)),
+            0x23 => ConstExpr::GlobalGet(self.usize()?),
+            0xd0 => ConstExpr::RefNull(self.ref_type()?),
+            0xd2 => ConstExpr::RefFunc(self.usize()?),
+            _ => {
+                self.pos -= 1;
+                return self.error(ErrorKind::ConstantExpressionRequired);
+            }
+        };
+        if self.byte()? != 0x0b {
+            self.pos -= 1;
+            return self.error(ErrorKind::ConstantExpressionRequired);
+        }
+        Ok(expr)
+    }
+
+    fn global_type(&mut self) -> Result<GlobalType> {
+        let ty = self.val_type()?;
+        let mutable = match self.byte()? {
+            0x00 => false,
+            0x01 => true,
+            b => {
+                self.pos -= 1;
+                return self.error(ErrorKind::InvalidMutability(b));
+            }
+        };
+        Ok(GlobalType { ty, mutable })
+    }
+
+    fn global(&mut self) -> Result<Global> {
+        let ty = self.global_type()?;
+        let init = self.const_expr()?;
+        Ok(Global { ty, init })
+    }
+
+    /// An element segment, whose flags select between active, passive and
+    /// declarative segments, whether an active one has an explicit table
+    /// index, and whether the elements are function indices or initializers.
+    fn elem(&mut self) -> Result<Elem> {
+        let flags = self.u32()?;
+        if flags > 7 {
+            return self.error(ErrorKind::InvalidElemSegment);
+        }
+        let mode = match flags & 3 {
+            0 => ElemMode::Active {
+                table: 0,
+                offset: self.const_expr()?,
+            },
+            1 => ElemMode::Passive,
+            2 => ElemMode::Active {
+                table: self.usize()?,
+                offset: self.const_expr()?,
+            },
+            _ => ElemMode::Declarative,
+        };
+        // Only segments for table 0 in the MVP encoding leave out the element
+        // type, which is then `funcref`.
+        let implicit = flags & 3 == 0;
+        if flags & 4 == 0 {
+            if !implicit && self.byte()? != 0x00 {
+                self.pos -= 1;
+                return self.error(ErrorKind::InvalidElemSegment);
+            }
+            let init = self.vec(|d| Ok(ConstExpr::RefFunc(d.usize()?)))?;
+            return Ok(Elem {
+                mode,
+                ty: RefType::Func,
+                init,
+            });
+        }
+        let ty = if implicit {
+            RefType::Func
+        } else {
+            self.ref_type()?
+        };
+        let init = self.vec(Self::const_expr)?;
+        Ok(Elem { mode, ty, init })
+    }
+
+    fn data(&mut self) -> Result<Data> {
+        match self.u32()? {
+            0 => {}
+            1 => {
+                let len = self.usize()?;
+                let init = self.take(len)?.to_vec();
+                return Ok(Data { offset: None, init });
+            }
+            2 => {
+                if self.u32()? != 0 {
+                    return self.error(ErrorKind::Unsupported("multiple memories"));
+                }
+            }
+            _ => return self.error(ErrorKind::InvalidDataSegment),
+        }
+        let offset = Some(self.const_expr()?);
+        let len = self.usize()?;
+        let init = self.take(len)?.to_vec();
+        Ok(Data { offset, init })
+    }
+
+    /// Reads a reserved memory index byte, as of `memory.size` and
+    /// `memory.grow`.
+    fn zero_byte(&mut self) -> Result<()> {
+        if self.byte()? != 0 {
+            self.pos -= 1;
+            return self.error(ErrorKind::ZeroByteExpected);
+        }
+        Ok(())
+    }
+
+    /// Memory immediates: the alignment exponent followed by the offset.
+    fn memarg(&mut self) -> Result<MemArg> {
+        let align = self.u32()?;
+        let offset = self.u32()?;
+        Ok(MemArg { align, offset })
+    }
+
+    fn block_type(&mut self) -> Result<BlockType> {
+        match self.bytes.get(self.pos) {
+            Some(0x40) => {
+                self.pos += 1;
+                Ok(BlockType::Empty)
+            }
+            Some(b) if b & 0xc0 == 0x40 => Ok(BlockType::Value(self.val_type()?)),
+            _ => {
+                // A type index, encoded as a positive signed 33-bit integer.
+                let start = self.pos;
+                let index = self.leb(33, true)? as i64;
+                if index < 0 {
+                    self.pos = start;
+                    return self.error(ErrorKind::InvalidValType(self.bytes[start]));
+                }
+                Ok(BlockType::Type(index as usize))
+            }
+        }
+    }
+
+    fn instruction(&mut self, opcode: u8) -> Result<Instruction> {
+        let instruction = match opcode {
+            0x00 => Instruction::Unreachable,
+            0x01 => Instruction::Nop,
+            0x02 => Instruction::Block(self.block_type()?),
+            0x03 => Instruction::Loop(self.block_type()?),
+            0x04 => Instruction::If(self.block_type()?),
+            0x05 => Instruction::Else,
+            0x0b => Instruction::End,
+            0x0c => Instruction::Br(self.usize()?),
+            0x0d => Instruction::BrIf(self.usize()?),
+            0x0e => {
+                let table = self.vec(Self::usize)?;
+                Instruction::BrTable(table, self.usize()?)
+            }
+            0x0f => Instruction::Return,
+            0x1a => Instruction::Drop,
+            0x1b => Instruction::Select,
+            0x1c => Instruction::SelectTyped(self.vec(Self::val_type)?),
+            0x10 => Instruction::CallFunc(self.usize()?),
+            0x11 => {
+                let ty = self.usize()?;
+                Instruction::CallIndirect(ty, self.usize()?)
+            }
+            0x20 => Instruction::LocalGet(self.usize()?),
+            0x21 => Instruction::LocalSet(self.usize()?),
+            0x22 => Instruction::LocalTee(self.usize()?),
+            0x23 => Instruction::GlobalGet(self.usize()?),
+            0x24 => Instruction::GlobalSet(self.usize()?),
+            0x25 => Instruction::TableGet(self.usize()?),
+            0x26 => Instruction::TableSet(self.usize()?),
+            0x28 => Instruction::I32Load(self.memarg()?),
+            0x29 => Instruction::I64Load(self.memarg()?),
+            0x2a => Instruction::F32Load(self.memarg()?),
+            0x2b => Instruction::F64Load(self.memarg()?),
+            0x2c => Instruction::I32Load8S(self.memarg()?),
+            0x2d => Instruction::I32Load8U(self.memarg()?),
+            0x2e => Instruction::I32Load16S(self.memarg()?),
+            0x2f => Instruction::I32Load16U(self.memarg()?),
+            0x30 => Instruction::I64Load8S(self.memarg()?),
+            0x31 => Instruction::I64Load8U(self.memarg()?),
+            0x32 => Instruction::I64Load16S(self.memarg()?),
+            0x33 => Instruction::I64Load16U(self.memarg()?),
+            0x34 => Instruction::I64Load32S(self.memarg()?),
+            0x35 => Instruction::I64Load32U(self.memarg()?),
+            0x36 => Instruction::I32Store(self.memarg()?),
+            0x37 => Instruction::I64Store(self.memarg()?),
+            0x38 => Instruction::F32Store(self.memarg()?),
+            0x39 => Instruction::F64Store(self.memarg()?),
+            0x3a => Instruction::I32Store8(self.memarg()?),
+            0x3b => Instruction::I32Store16(self.memarg()?),
+            0x3c => Instruction::I64Store8(self.memarg()?),
+            0x3d => Instruction::I64Store16(self.memarg()?),
+            0x3e => Instruction::I64Store32(self.memarg()?),
+            0x3f => {
+                self.zero_byte()?;
+                Instruction::MemorySize
+            }
+            0x40 => {
+                self.zero_byte()?;
+                Instruction::MemoryGrow
+            }
+            0x41 => Instruction::I32Const(self.i32()?),
+            0x42 => Instruction::I64Const(self.i64()?),
+            0x43 => Instruction::F32Const(self.f32()?),
+            0x44 => Instruction::F64Const(self.f64()?),
+            0x45 => Instruction::I32Eqz,
+            0x46 => Instruction::I32Eq,
+            0x47 => Instruction::I32Ne,
+            0x48 => Instruction::I32LtS,
+            0x49 => Instruction::I32LtU,
+            0x4a => Instruction::I32GtS,
+            0x4b => Instruction::I32GtU,
+            0x4c => Instruction::I32LeS,
+            0x4d => Instruction::I32LeU,
+            0x4e => Instruction::I32GeS,
+            0x4f => Instruction::I32GeU,
+            0x50 => Instruction::I64Eqz,
+            0x51 => Instruction::I64Eq,
+            0x52 => Instruction::I64Ne,
+            0x53 => Instruction::I64LtS,
+            0x54 => Instruction::I64LtU,
+            0x55 => Instruction::I64GtS,
+            0x56 => Instruction::I64GtU,
+            0x57 => Instruction::I64LeS,
+            0x58 => Instruction::I64LeU,
+            0x59 => Instruction::I64GeS,
+            0x5a => Instruction::I64GeU,
+            0x5b => Instruction::F32Eq,
+            0x5c => Instruction::F32Ne,
+            0x5d => Instruction::F32Lt,
+            0x5e => Instruction::F32Gt,
+            0x5f => Instruction::F32Le,
+            0x60 => Instruction::F32Ge,
+            0x61 => Instruction::F64Eq,
+            0x62 => Instruction::F64Ne,
+            0x63 => Instruction::F64Lt,
+            0x64 => Instruction::F64Gt,
+            0x65 => Instruction::F64Le,
+            0x66 => Instruction::F64Ge,
+            0x67 => Instruction::I32Clz,
+            0x68 => Instruction::I32Ctz,
+            0x69 => Instruction::I32Popcnt,
+            0x6a => Instruction::I32Add,
+            0x6b => Instruction::I32Sub,
+            0x6c => Instruction::I32Mul,
+            0x6d => Instruction::I32DivS,
+            0x6e => Instruction::I32DivU,
+            0x6f => Instruction::I32RemS,
+            0x70 => Instruction::I32RemU,
+            0x71 => Instruction::I32And,
+            0x72 => Instruction::I32Or,
+            0x73 => Instruction::I32Xor,
+            0x74 => Instruction::I32Shl,
+            0x75 => Instruction::I32ShrS,
+            0x76 => Instruction::I32ShrU,
+            0x77 => Instruction::I32Rotl,
+            0x78 => Instruction::I32Rotr,
+            0x79 => Instruction::I64Clz,
+            0x7a => Instruction::I64Ctz,
+            0x7b => Instruction::I64Popcnt,
+            0x7c => Instruction::I64Add,
+            0x7d => Instruction::I64Sub,
+            0x7e => Instruction::I64Mul,
+            0x7f => Instruction::I64DivS,
+            0x80 => Instruction::I64DivU,
+            0x81 => Instruction::I64RemS,
+            0x82 => Instruction::I64RemU,
+            0x83 => Instruction::I64And,
+            0x84 => Instruction::I64Or,
+            0x85 => Instruction::I64Xor,
+            0x86 => Instruction::I64Shl,
+            0x87 => Instruction::I64ShrS,
+            0x88 => Instruction::I64ShrU,
+            0x89 => Instruction::I64Rotl,
+            0x8a => Instruction::I64Rotr,
+            0x8b => Instruction::F32Abs,
+            0x8c => Instruction::F32Neg,
+            0x8d => Instruction::F32Ceil,
+            0x8e => Instruction::F32Floor,
+            0x8f => Instruction::F32Trunc,
+            0x90 => Instruction::F32Nearest,
+            0x91 => Instruction::F32Sqrt,
+            0x92 => Instruction::F32Add,
+            0x93 => Instruction::F32Sub,
+            0x94 => Instruction::F32Mul,
+            0x95 => Instruction::F32Div,
+            0x96 => Instruction::F32Min,
+            0x97 => Instruction::F32Max,
+            0x98 => Instruction::F32Copysign,
+            0x99 => Instruction::F64Abs,
+            0x9a => Instruction::F64Neg,
+            0x9b => Instruction::F64Ceil,
+            0x9c => Instruction::F64Floor,
+            0x9d => Instruction::F64Trunc,
+            0x9e => Instruction::F64Nearest,
+            0x9f => Instruction::F64Sqrt,
+            0xa0 => Instruction::F64Add,
+            0xa1 => Instruction::F64Sub,
+            0xa2 => Instruction::F64Mul,
+            0xa3 => Instruction::F64Div,
+            0xa4 => Instruction::F64Min,
+            0xa5 => Instruction::F64Max,
+            0xa6 => Instruction::F64Copysign,
+            0xa7 => Instruction::I32WrapI64,
+            0xa8 => Instruction::I32TruncF32S,
+            0xa9 => Instruction::I32TruncF32U,
+            0xaa => Instruction::I32TruncF64S,
+            0xab => Instruction::I32TruncF64U,
+            0xac => Instruction::I64ExtendI32S,
+            0xad => Instruction::I64ExtendI32U,
+            0xae => Instruction::I64TruncF32S,
+            0xaf => Instruction::I64TruncF32U,
+            0xb0 => Instruction::I64TruncF64S,
+            0xb1 => Instruction::I64TruncF64U,
+            0xb2 => Instruction::F32ConvertI32S,
+            0xb3 => Instruction::F32ConvertI32U,
+            0xb4 => Instruction::F32ConvertI64S,
+            0xb5 => Instruction::F32ConvertI64U,
+            0xb6 => Instruction::F32DemoteF64,
+            0xb7 => Instruction::F64ConvertI32S,
+            0xb8 => Instruction::F64ConvertI32U,
+            0xb9 => Instruction::F64ConvertI64S,
+            0xba => Instruction::F64ConvertI64U,
+            0xbb => Instruction::F64PromoteF32,
+            0xbc => Instruction::I32ReinterpretF32,
+            0xbd => Instruction::I64ReinterpretF64,
+            0xbe => Instruction::F32ReinterpretI32,
+            0xbf => Instruction::F64ReinterpretI64,
+            0xc0 => Instruction::I32Extend8S,
+            0xc1 => Instruction::I32Extend16S,
+            0xc2 => Instruction::I64Extend8S,
+            0xc3 => Instruction::I64Extend16S,
+            0xc4 => Instruction::I64Extend32S,
+            0xd0 => Instruction::RefNull(self.ref_type()?),
+            0xd1 => Instruction::RefIsNull,
+            0xd2 => Instruction::RefFunc(self.usize()?),
+            0xfc => self.misc_instruction()?,
+            op => {
+                self.pos -= 1;
+                return self.error(ErrorKind::IllegalOpcode(op));
+            }
+        };
+        Ok(instruction)
+    }
+
+    /// Decodes an instruction with the 0xfc prefix, selected by a following
+    /// u32.
+    fn misc_instruction(&mut self) -> Result<Instruction> {
+        let start = self.pos;
+        let instruction = match self.u32()? {
+            0 => Instruction::I32TruncSatF32S,
+            1 => Instruction::I32TruncSatF32U,
+            2 => Instruction::I32TruncSatF64S,
+            3 => Instruction::I32TruncSatF64U,
+            4 => Instruction::I64TruncSatF32S,
+            5 => Instruction::I64TruncSatF32U,
+            6 => Instruction::I64TruncSatF64S,
+            7 => Instruction::I64TruncSatF64U,
+            8 => {
+                let data = self.usize()?;
+                self.zero_byte()?;
+                Instruction::MemoryInit(data)
+            }
+            9 => Instruction::DataDrop(self.usize()?),
+            10 => {
+                self.zero_byte()?;
+                self.zero_byte()?;
+                Instruction::MemoryCopy
+            }
+            11 => {
+                self.zero_byte()?;
+                Instruction::MemoryFill
+            }
+            12 => Instruction::TableInit(self.usize()?, self.usize()?),
+            13 => Instruction::ElemDrop(self.usize()?),
+            14 => Instruction::TableCopy(self.usize()?, self.usize()?),
+            15 => Instruction::TableGrow(self.usize()?),
+            16 => Instruction::TableSize(self.usize()?),
+            17 => Instruction::TableFill(self.usize()?),
+            op => {
+                self.pos = start;
+                return self.error(ErrorKind::IllegalPrefixedOpcode(0xfc, op));
+            }
+        };
+        Ok(instruction)
+    }
+
+    /// Decodes instructions up to and including the final `end`, which is
+    /// not included in the result.
+    fn expr(&mut self) -> Result<Vec<Instruction>> {
+        let mut code = Vec::new();
+        let mut depth = 0;
+        loop {
+            let opcode = self.byte()?;
+            match opcode {
+                0x0b if depth == 0 => return Ok(code),
+                0x0b => depth -= 1,
+                0x02..=0x04 => depth += 1,
+                _ => {}
+            }
+            code.push(self.instruction(opcode)?);
+        }
+    }
+
+    fn code(&mut self) -> Result<(Vec<ValType>, Vec<Instruction>)> {
+        let size = self.usize()?;
+        let mut body = self.sub(size)?;
+        let mut locals = Vec::new();
+        let mut total: u64 = 0;
+        for (count, ty) in body.vec(|d| Ok((d.u32()?, d.val_type()?)))? {
+            total += u64::from(count);
+            if total > MAX_LOCALS {
+                return body.error(ErrorKind::TooManyLocals);
+            }
+            locals.extend(std::iter::repeat_n(ty, count as usize));
+        }
+        let code = body.expr()?;
+        if !body.at_end() {
+            return body.error(ErrorKind::SectionSizeMismatch);
+        }
+        self.pos = body.pos;
+        Ok((locals, code))
+    }
+
+    fn module(&mut self) -> Result<Module> {
+        if self.bytes.len() < 4 || &self.bytes[..4] != MAGIC {
+            return self.error(ErrorKind::BadMagic);
+        }
+        self.pos = 4;
+        let version = u32::from_le_bytes(self.take(4)?.try_into().unwrap());
+        if version != VERSION {
+            self.pos -= 4;
+            return self.error(ErrorKind::UnknownVersion(version));
+        }
+
+        let mut module = Module::default();
+        let mut func_types = Vec::new();
+        let mut bodies = Vec::new();
+        let mut data_count = None;
+        let mut last_order = 0;
+
+        while !self.at_end() {
+            let id = self.byte()?;
+            let size = self.usize()?;
+            let mut section = self.sub(size)?;
+            if id != 0 {
+                let order = match section_order(id) {
+                    Some(order) => order,
+                    None => {
+                        self.pos -= 1;
+                        return self.error(ErrorKind::InvalidSectionId(id));
+                    }
+                };
+                if order <= last_order {
+                    return self.error(ErrorKind::SectionOutOfOrder(id));
+                }
+                last_order = order;
+            }
+
+            match id {
+                0 => {
+                    // Custom sections only need a well-formed name.
+                    section.name()?;
+                    section.pos = section.bytes.len();
+                }
+                1 => module.types = section.vec(Self::func_type)?,
+                2 => module.imports = section.vec(Self::import)?,
+                3 => func_types = section.vec(Self::usize)?,
+                4 => module.tables = section.vec(Self::table)?,
+                5 => {
+                    let mut memories = section.vec(Self::limits)?;
+                    if memories.len() > 1 {
+                        return section.error(ErrorKind::Unsupported("multiple memories"));
+                    }
+                    module.memory = memories.pop();
+                }
+                6 => module.globals = section.vec(Self::global)?,
+                7 => module.exports = section.vec(Self::export)?,
+                8 => module.start = Some(section.usize()?),
+                9 => module.elems = section.vec(Self::elem)?,
+                10 => bodies = section.vec(Self::code)?,
+                11 => module.data = section.vec(Self::data)?,
+                12 => data_count = Some(section.usize()?),
+                _ => unreachable!(),
+            }
+            if !section.at_end() {
+                return section.error(ErrorKind::SectionSizeMismatch);
+            }
+            self.pos = section.pos;
+        }
+
+        if func_types.len() != bodies.len() {
+            return self.error(ErrorKind::FunctionCodeMismatch);
+        }
+        if data_count.is_some_and(|count| count != module.data.len()) {
+            return self.error(ErrorKind::DataCountMismatch);
+        }
+        // Segment indices in code are only allowed if the data count section
+        // declares how many segments there are ahead of the code section.
+        let uses_data = |code: &Vec<Instruction>| {
+            code.iter().any(|instruction| {
+                matches!(
+                    instruction,
+                    Instruction::MemoryInit(_) | Instruction::DataDrop(_)
+                )
+            })
+        };
+        if data_count.is_none() && bodies.iter().any(|(_, code)| uses_data(code)) {
+            return self.error(ErrorKind::DataCountRequired);
+        }
+        for (ty, (locals, code)) in func_types.into_iter().zip(bodies) {
+            module.functions.push(Function::new(ty, locals, code));
+        }
+        Ok(module)
+    }
+}
+
+/// Position of a non-custom section in the required section order.
+fn section_order(id: u8) -> Option<u8> {
+    match id {
+        1..=9 => Some(id),
+        12 => Some(10),
+        10 | 11 => Some(id + 1),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Instance, Value};
+
+    fn header() -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes
+    }
+
+    fn section(bytes: &mut Vec<u8>, id: u8, contents: &[u8]) {
+        bytes.push(id);
+        bytes.push(contents.len() as u8);
+        bytes.extend_from_slice(contents);
+    }
+
+    #[test]
+    fn decodes_functions_memory_exports_and_data() {
+        let mut bytes = header();
+        // (func (param f64 f64 f64) (result f64))
+        section(&mut bytes, 1, &[1, 0x60, 3, 0x7c, 0x7c, 0x7c, 1, 0x7c]);
+        section(&mut bytes, 3, &[1, 0]);
+        section(&mut bytes, 5, &[1, 0x01, 1, 2]);
+        section(&mut bytes, 7, &[1, 3, b'r', b'u', b'n', 0x00, 0]);
+        // local.get 0, local.get 1, local.get 2, f64.mul, f64.add, end
+        #[rustfmt::skip]
+        section(&mut bytes, 10, &[
+            1, 10, 0,
+            0x20, 0, 0x20, 1, 0x20, 2, 0xa2, 0xa0, 0x0b,
+        ]);
+        section(&mut bytes, 11, &[1, 0, 0x41, 8, 0x0b, 2, 0xaa, 0xbb]);
+
+        let module = decode(&bytes).unwrap();
+        assert_eq!(module.functions.len(), 1);
+        assert_eq!(
+            module.memory,
+            Some(Limits {
+                min: 1,
+                max: Some(2)
+            })
+        );
+        assert_eq!(module.mem_size(), 65536);
+        assert_eq!(
+            module.exports,
+            vec![Export {
+                name: "run".to_string(),
+                desc: ExportDesc::Func(0)
+            }]
+        );
+        assert_eq!(
+            module.data,
+            vec![Data {
+                offset: Some(ConstExpr::Const(Value::I32(8))),
+                init: vec![0xaa, 0xbb]
+            }]
+        );
+
+        let mut m = Instance::new(module).unwrap();
+        let args = vec![Value::F64(2.0), Value::F64(3.0), Value::F64(0.5)];
+        assert_eq!(m.call(0, args).unwrap(), vec![Value::F64(3.5)]);
+    }
+
+    #[test]
+    fn decodes_start_and_passive_data() {
+        let mut bytes = header();
+        section(&mut bytes, 1, &[1, 0x60, 0, 0]);
+        section(&mut bytes, 3, &[1, 0]);
+        section(&mut bytes, 5, &[1, 0x00, 1]);
+        section(&mut bytes, 8, &[0]);
+        section(&mut bytes, 10, &[1, 2, 0, 0x0b]);
+        section(&mut bytes, 11, &[1, 1, 2, 0xaa, 0xbb]);
+
+        let module = decode(&bytes).unwrap();
+        assert_eq!(module.start, Some(0));
+        assert_eq!(
+            module.data,
+            vec![Data {
+                offset: None,
+                init: vec![0xaa, 0xbb]
+            }]
+        );
+        module.validate().unwrap();
+    }
+
+    #[test]
+    fn decodes_bulk_instructions() {
+        let mut bytes = header();
+        section(&mut bytes, 1, &[1, 0x60, 0, 0]);
+        section(&mut bytes, 3, &[1, 0]);
+        section(&mut bytes, 4, &[1, 0x70, 0x00, 1]);
+        section(&mut bytes, 5, &[1, 0x00, 1]);
+        // (elem func 0)
+        section(&mut bytes, 9, &[1, 1, 0x00, 1, 0]);
+        let data_count = bytes.len();
+        section(&mut bytes, 12, &[1]);
+        #[rustfmt::skip]
+        let code = [
+            1, 27, 0,
+            0xfc, 8, 0, 0x00, 0xfc, 9, 0, 0xfc, 10, 0x00, 0x00, 0xfc, 11, 0x00,
+            0xfc, 12, 0, 0, 0xfc, 13, 0, 0xfc, 14, 0, 0, 0x0b,
+        ];
+        section(&mut bytes, 10, &code);
+        section(&mut bytes, 11, &[1, 1, 0]);
+
+        let module = decode(&bytes).unwrap();
+        assert_eq!(module.elems[0].mode, ElemMode::Passive);
+        assert_eq!(
+            module.functions[0].code,
+            vec![
+                Instruction::MemoryInit(0),
+                Instruction::DataDrop(0),
+                Instruction::MemoryCopy,
+                Instruction::MemoryFill,
+                Instruction::TableInit(0, 0),
+                Instruction::ElemDrop(0),
+                Instruction::TableCopy(0, 0),
+            ]
+        );
+
+        let mut mismatched = bytes.clone();
+        mismatched[data_count + 2] = 2;
+        assert_eq!(
+            decode(&mismatched).unwrap_err().kind,
+            ErrorKind::DataCountMismatch
+        );
+        let mut missing = bytes[..data_count].to_vec();
+        missing.extend_from_slice(&bytes[data_count + 3..]);
+        assert_eq!(
+            decode(&missing).unwrap_err().kind,
+            ErrorKind::DataCountRequired
+        );
+    }
+
+    #[test]
+    fn decodes_structured_control() {
+        let mut d = Decoder::new(&[
+            0x02, 0x7f, 0x03, 0x40, 0x41, 0x01, 0x0d, 0x00, 0x0b, 0x41, 0x02, 0x0e, 0x02, 0x00,
+            0x01, 0x00, 0x0b, 0x0b,
+        ]);
+        assert_eq!(
+            d.expr().unwrap(),
+            vec![
+                Instruction::Block(BlockType::Value(ValType::I32)),
+                Instruction::Loop(BlockType::Empty),
+                Instruction::I32Const(1),
+                Instruction::BrIf(0),
+                Instruction::End,
+                Instruction::I32Const(2),
+                Instruction::BrTable(vec![0, 1], 0),
+                Instruction::End,
+            ]
+        );
+        assert!(d.at_end());
+
+        // Block types may also be type indices, as positive signed integers.
+        let mut d = Decoder::new(&[0x04, 0x02, 0x0b, 0x0b]);
+        assert_eq!(
+            d.expr().unwrap(),
+            vec![Instruction::If(BlockType::Type(2)), Instruction::End]
+        );
+        let mut d = Decoder::new(&[0x02, 0x80, 0x01, 0x0b, 0x0b]);
+        assert_eq!(
+            d.expr().unwrap(),
+            vec![Instruction::Block(BlockType::Type(128)), Instruction::End]
+        );
+    }
+
+    #[test]
+    fn decodes_prefixed_instructions() {
+        let mut d = Decoder::new(&[0xfc, 0x00, 0xfc, 0x87, 0x00, 0xc4, 0x0b]);
+        assert_eq!(
+            d.expr().unwrap(),
+            vec![
+                Instruction::I32TruncSatF32S,
+                Instruction::I64TruncSatF64U,
+                Instruction::I64Extend32S,
+            ]
+        );
+        let mut d = Decoder::new(&[0xfc, 0x7f, 0x0b]);
+        assert_eq!(
+            d.expr().unwrap_err(),
+            DecodeError {
+                offset: 1,
+                kind: ErrorKind::IllegalPrefixedOpcode(0xfc, 0x7f)
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_memory_instructions() {
+        let mut d = Decoder::new(&[0x3f, 0x00, 0x40, 0x00, 0x28, 0x02, 0x10, 0x0b]);
+        assert_eq!(
+            d.expr().unwrap(),
+            vec![
+                Instruction::MemorySize,
+                Instruction::MemoryGrow,
+                Instruction::I32Load(MemArg {
+                    align: 2,
+                    offset: 16
+                }),
+            ]
+        );
+        let mut d = Decoder::new(&[0x40, 0x01, 0x0b]);
+        assert_eq!(
+            d.expr().unwrap_err(),
+            DecodeError {
+                offset: 1,
+                kind: ErrorKind::ZeroByteExpected
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_globals() {
+        let mut bytes = header();
+        // (global (mut i32) (i32.const 42)) (global f64 (global.get 0))
+        section(
+            &mut bytes,
+            6,
+            &[2, 0x7f, 0x01, 0x41, 42, 0x0b, 0x7c, 0x00, 0x23, 0, 0x0b],
+        );
+        let module = decode(&bytes).unwrap();
+        assert_eq!(
+            module.globals,
+            vec![
+                Global {
+                    ty: GlobalType {
+                        ty: ValType::I32,
+                        mutable: true
+                    },
+                    init: ConstExpr::Const(Value::I32(42))
+                },
+                Global {
+                    ty: GlobalType {
+                        ty: ValType::F64,
+                        mutable: false
+                    },
+                    init: ConstExpr::GlobalGet(0)
+                },
+            ]
+        );
+
+        let mut bytes = header();
+        section(&mut bytes, 6, &[1, 0x7f, 0x02, 0x41, 0, 0x0b]);
+        assert_eq!(
+            decode(&bytes).unwrap_err().kind,
+            ErrorKind::InvalidMutability(2)
+        );
+
+        let mut bytes = header();
+        section(
+            &mut bytes,
+            6,
+            &[1, 0x7f, 0x00, 0x41, 0, 0x41, 0, 0x6a, 0x0b],
+        );
+        assert_eq!(
+            decode(&bytes).unwrap_err(),
+            DecodeError {
+                offset: 15,
+                kind: ErrorKind::ConstantExpressionRequired
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_tables_and_elements() {
+        let mut bytes = header();
+        // (type (func)) (func (type 0) (call_indirect (type 0) (i32.const 0)))
+        section(&mut bytes, 1, &[1, 0x60, 0, 0]);
+        section(&mut bytes, 3, &[1, 0]);
+        section(&mut bytes, 4, &[1, 0x70, 0x00, 2]);
+        section(&mut bytes, 9, &[1, 0, 0x41, 1, 0x0b, 1, 0]);
+        section(&mut bytes, 10, &[1, 7, 0, 0x41, 0, 0x11, 0, 0, 0x0b]);
+        let module = decode(&bytes).unwrap();
+        assert_eq!(module.types, vec![FuncType::default()]);
+        assert_eq!(
+            module.tables,
+            vec![TableType {
+                elem: RefType::Func,
+                limits: Limits { min: 2, max: None }
+            }]
+        );
+        assert_eq!(
+            module.elems,
+            vec![Elem {
+                mode: ElemMode::Active {
+                    table: 0,
+                    offset: ConstExpr::Const(Value::I32(1)),
+                },
+                ty: RefType::Func,
+                init: vec![ConstExpr::RefFunc(0)]
+            }]
+        );
+        assert_eq!(
+            module.functions[0].code,
+            vec![Instruction::I32Const(0), Instruction::CallIndirect(0, 0)]
+        );
+
+        let mut bytes = header();
+        section(&mut bytes, 4, &[1, 0x6f, 0x00, 0]);
+        assert_eq!(decode(&bytes).unwrap().tables[0].elem, RefType::Extern);
+
+        let mut bytes = header();
+        section(&mut bytes, 4, &[1, 0x7f, 0x00, 0]);
+        assert_eq!(
+            decode(&bytes).unwrap_err().kind,
+            ErrorKind::InvalidRefType(0x7f)
+        );
+    }
+
+    #[test]
+    fn decodes_reference_types() {
+        let mut bytes = header();
+        // (type (func (param externref) (result funcref)))
+        section(&mut bytes, 1, &[1, 0x60, 1, 0x6f, 1, 0x70]);
+        section(&mut bytes, 3, &[1, 0]);
+        section(&mut bytes, 4, &[2, 0x70, 0x00, 1, 0x6f, 0x00, 0]);
+        #[rustfmt::skip]
+        section(&mut bytes, 9, &[
+            4,
+            4, 0x41, 0, 0x0b, 1, 0xd2, 0, 0x0b,
+            5, 0x6f, 1, 0xd0, 0x6f, 0x0b,
+            6, 1, 0x41, 0, 0x0b, 0x6f, 0,
+            7, 0x70, 1, 0xd2, 0, 0x0b,
+        ]);
+        #[rustfmt::skip]
+        section(&mut bytes, 10, &[
+            1, 44, 0,
+            0x20, 0, 0xd1, 0x1a,
+            0x41, 0, 0x25, 1,
+            0x41, 0, 0xfc, 15, 1, 0x1a,
+            0x41, 0, 0xd0, 0x6f, 0x26, 1,
+            0x41, 0, 0xd0, 0x6f, 0x41, 0, 0xfc, 17, 1,
+            0xfc, 16, 0, 0x1a,
+            0xd0, 0x70, 0xd2, 0, 0x41, 1, 0x1c, 1, 0x70,
+            0x0b,
+        ]);
+
+        let module = decode(&bytes).unwrap();
+        module.validate().unwrap();
+        assert_eq!(module.types[0].params, vec![ValType::ExternRef]);
+        let offset = ConstExpr::Const(Value::I32(0));
+        assert_eq!(
+            module.elems,
+            vec![
+                Elem {
+                    mode: ElemMode::Active { table: 0, offset },
+                    ty: RefType::Func,
+                    init: vec![ConstExpr::RefFunc(0)]
+                },
+                Elem {
+                    mode: ElemMode::Passive,
+                    ty: RefType::Extern,
+                    init: vec![ConstExpr::RefNull(RefType::Extern)]
+                },
+                Elem {
+                    mode: ElemMode::Active { table: 1, offset },
+                    ty: RefType::Extern,
+                    init: vec![]
+                },
+                Elem {
+                    mode: ElemMode::Declarative,
+                    ty: RefType::Func,
+                    init: vec![ConstExpr::RefFunc(0)]
+                },
+            ]
+        );
+        let code = &module.functions[0].code;
+        assert_eq!(
+            code[..3],
+            [
+                Instruction::LocalGet(0),
+                Instruction::RefIsNull,
+                Instruction::Drop
+            ]
+        );
+        assert_eq!(code[4], Instruction::TableGet(1));
+        assert_eq!(code[6], Instruction::TableGrow(1));
+        assert_eq!(code[10], Instruction::TableSet(1));
+        assert_eq!(code[14], Instruction::TableFill(1));
+        assert_eq!(code[15], Instruction::TableSize(0));
+        assert_eq!(
+            code[17..],
+            [
+                Instruction::RefNull(RefType::Func),
+                Instruction::RefFunc(0),
+                Instruction::I32Const(1),
+                Instruction::SelectTyped(vec![ValType::FuncRef]),
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_imports() {
+        let mut bytes = header();
+        section(&mut bytes, 1, &[1, 0x60, 1, 0x7f, 0]);
+        // (import "env" "log" (func (type 0)))
+        section(
+            &mut bytes,
+            2,
+            &[1, 3, b'e', b'n', b'v', 3, b'l', b'o', b'g', 0x00, 0],
+        );
+        let module = decode(&bytes).unwrap();
+        assert_eq!(
+            module.imports,
+            vec![Import {
+                module: "env".to_string(),
+                name: "log".to_string(),
+                desc: ImportDesc::Func(0)
+            }]
+        );
+
+        // (import "m" "t" (table 1 funcref)) (import "m" "mem" (memory 1 2))
+        // (import "m" "g" (global (mut i64)))
+        let mut bytes = header();
+        section(
+            &mut bytes,
+            2,
+            &[
+                3, 1, b'm', 1, b't', 0x01, 0x70, 0x00, 1, 1, b'm', 3, b'm', b'e', b'm', 0x02, 0x01,
+                1, 2, 1, b'm', 1, b'g', 0x03, 0x7e, 0x01,
+            ],
+        );
+        let descs: Vec<_> = decode(&bytes)
+            .unwrap()
+            .imports
+            .into_iter()
+            .map(|import| import.desc)
+            .collect();
+        assert_eq!(
+            descs,
+            vec![
+                ImportDesc::Table(TableType {
+                    elem: RefType::Func,
+                    limits: Limits { min: 1, max: None }
+                }),
+                ImportDesc::Memory(Limits {
+                    min: 1,
+                    max: Some(2)
+                }),
+                ImportDesc::Global(GlobalType {
+                    ty: ValType::I64,
+                    mutable: true
+                }),
+            ]
+        );
+
+        let mut bytes = header();
+        section(&mut bytes, 2, &[1, 1, b'm', 1, b'n', 0x04, 0]);
+        assert_eq!(
+            decode(&bytes).unwrap_err(),
+            DecodeError {
+                offset: 15,
+                kind: ErrorKind::InvalidImportKind(4)
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_header() {
+        assert_eq!(decode(b"\0asx").unwrap_err().kind, ErrorKind::BadMagic);
+        assert_eq!(
+            decode(b"\0asm\x02\0\0\0").unwrap_err(),
+            DecodeError {
+                offset: 4,
+                kind: ErrorKind::UnknownVersion(2)
+            }
+        );
+        assert_eq!(
+            decode(b"\0asm\x01\0").unwrap_err().kind,
+            ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn reports_offset_of_malformed_input() {
+        let mut bytes = header();
+        section(&mut bytes, 1, &[1, 0x60, 1, 0x7b, 0]);
+        assert_eq!(
+            decode(&bytes).unwrap_err(),
+            DecodeError {
+                offset: 13,
+                kind: ErrorKind::InvalidValType(0x7b)
+            }
+        );
+
+        let mut bytes = header();
+        section(&mut bytes, 3, &[1, 0x80, 0x80, 0x80, 0x80, 0x80, 0]);
+        assert_eq!(decode(&bytes).unwrap_err().kind, ErrorKind::IntegerTooLong);
+
+        let mut bytes = header();
+        section(&mut bytes, 3, &[1, 0xff, 0xff, 0xff, 0xff, 0x1f]);
+        assert_eq!(decode(&bytes).unwrap_err().kind, ErrorKind::IntegerTooLarge);
+    }
+
+    #[test]
+    fn rejects_too_many_locals() {
+        let mut bytes = header();
+        section(&mut bytes, 1, &[1, 0x60, 0, 0]);
+        section(&mut bytes, 3, &[1, 0]);
+        // (local i32 x 50000)
+        section(&mut bytes, 10, &[1, 6, 1, 0xd0, 0x86, 0x03, 0x7f, 0x0b]);
+        assert_eq!(decode(&bytes).unwrap().functions[0].locals.len(), 50_000);
+
+        let mut bytes = header();
+        section(&mut bytes, 1, &[1, 0x60, 0, 0]);
+        section(&mut bytes, 3, &[1, 0]);
+        // (local i32 x 50000) (local i64)
+        section(
+            &mut bytes,
+            10,
+            &[1, 8, 2, 0xd0, 0x86, 0x03, 0x7f, 1, 0x7e, 0x0b],
+        );
+        assert_eq!(decode(&bytes).unwrap_err().kind, ErrorKind::TooManyLocals);
+
+        let mut bytes = header();
+        section(&mut bytes, 1, &[1, 0x60, 0, 0]);
+        section(&mut bytes, 3, &[1, 0]);
+        // (local i32 x 0xffffffff)
+        #[rustfmt::skip]
+        section(&mut bytes, 10, &[1, 8, 1, 0xff, 0xff, 0xff, 0xff, 0x0f, 0x7f, 0x0b]);
+        assert_eq!(decode(&bytes).unwrap_err().kind, ErrorKind::TooManyLocals);
+    }
+
+    #[test]
+    fn decodes_signed_leb128() {
+        let mut d = Decoder::new(&[0x7f, 0x80, 0x7f, 0xff, 0xff, 0xff, 0xff, 0x07]);
+        assert_eq!(d.i32().unwrap(), -1);
+        assert_eq!(d.i32().unwrap(), -128);
+        assert_eq!(d.i32().unwrap(), i32::MAX);
+        let mut d = Decoder::new(&[0x80, 0x80, 0x80, 0x80, 0x78]);
+        assert_eq!(d.i32().unwrap(), i32::MIN);
+        let mut d = Decoder::new(&[0x80, 0x80, 0x80, 0x80, 0x70]);
+        assert_eq!(d.i32().unwrap_err().kind, ErrorKind::IntegerTooLarge);
+    }
+}
@@ -0,0 +1,109 @@
+use std::fmt;
+
+use crate::binary::DecodeError;
+use crate::text::ParseError;
+use crate::{Trap, ValType, ValidationError};
+
+/// An error loading, instantiating or running a module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    Decode(DecodeError),
+    Parse(ParseError),
+    Invalid(ValidationError),
+    /// An import that isn't provided.
+    UnknownImport {
+        module: String,
+        name: String,
+    },
+    /// An import provided with a different type than the module declares.
+    IncompatibleImportType {
+        module: String,
+        name: String,
+    },
+    /// A different number of definitions than the module has imports.
+    ImportCountMismatch {
+        expected: usize,
+        found: usize,
+    },
+    UnknownExport(String),
+    /// An export used as a function that is some other kind of definition.
+    NotAFunction(String),
+    /// Arguments that don't match the parameters of the function called.
+    ArgumentTypeMismatch {
+        expected: Vec<ValType>,
+        found: Vec<ValType>,
+    },
+    Trap(Trap),
+    /// A table or memory too large to allocate at its initial size.
+    AllocationFailed(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Decode(err) => err.fmt(f),
+            Error::Parse(err) => err.fmt(f),
+            Error::Invalid(err) => err.fmt(f),
+            Error::UnknownImport { module, name } => {
+                write!(f, "unknown import {:?} {:?}", module, name)
+            }
+            Error::IncompatibleImportType { module, name } => {
+                write!(f, "incompatible import type for {:?} {:?}", module, name)
+            }
+            Error::ImportCountMismatch { expected, found } => {
+                write!(f, "expected {} imports, found {}", expected, found)
+            }
+            Error::UnknownExport(name) => write!(f, "unknown export {:?}", name),
+            Error::NotAFunction(name) => write!(f, "export {:?} is not a function", name),
+            Error::ArgumentTypeMismatch { expected, found } => write!(
+                f,
+                "argument type mismatch: expected [{}], found [{}]",
+                join(expected),
+                join(found)
+            ),
+            Error::Trap(trap) => trap.fmt(f),
+            Error::AllocationFailed(what) => write!(f, "failed to allocate {}", what),
+        }
+    }
+}
+
+fn join(types: &[ValType]) -> String {
+    let names: Vec<_> = types.iter().map(ValType::to_string).collect();
+    names.join(" ")
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Decode(err) => Some(err),
+            Error::Parse(err) => Some(err),
+            Error::Invalid(err) => Some(err),
+            Error::Trap(trap) => Some(trap),
+            _ => None,
+        }
+    }
+}
+
+impl From<Trap> for Error {
+    fn from(trap: Trap) -> Self {
+        Error::Trap(trap)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(err: DecodeError) -> Self {
+        Error::Decode(err)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Self {
+        Error::Parse(err)
+    }
+}
+
+impl From<ValidationError> for Error {
+    fn from(err: ValidationError) -> Self {
+        Error::Invalid(err)
+    }
+}
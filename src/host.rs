@@ -0,0 +1,95 @@
+//! Rust functions that guest code can import and call.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::module::FuncType;
+use crate::{Memory, Trap, Value};
+
+/// The parts of the calling instance a host function can access.
+pub struct Caller<'a> {
+    pub(crate) memory: &'a mut Memory,
+}
+
+impl Caller<'_> {
+    pub fn memory(&self) -> &Memory {
+        self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        self.memory
+    }
+}
+
+type Callback = dyn Fn(&mut Caller, &[Value]) -> Result<Vec<Value>, Trap>;
+
+/// A Rust closure with a WebAssembly signature.
+#[derive(Clone)]
+pub struct HostFunc {
+    ty: FuncType,
+    func: Rc<Callback>,
+}
+
+impl HostFunc {
+    pub fn new<F>(ty: FuncType, func: F) -> Self
+    where
+        F: Fn(&mut Caller, &[Value]) -> Result<Vec<Value>, Trap> + 'static,
+    {
+        HostFunc {
+            ty,
+            func: Rc::new(func),
+        }
+    }
+
+    pub fn ty(&self) -> &FuncType {
+        &self.ty
+    }
+
+    /// Calls the closure with arguments of the right types, and traps if it
+    /// returns results of the wrong types.
+    pub(crate) fn call(&self, caller: &mut Caller, args: &[Value]) -> Result<Vec<Value>, Trap> {
+        let results = (self.func)(caller, args)?;
+        if !results
+            .iter()
+            .map(Value::ty)
+            .eq(self.ty.results.iter().copied())
+        {
+            return Err(Trap::TypeMismatch);
+        }
+        Ok(results)
+    }
+}
+
+impl fmt::Debug for HostFunc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HostFunc").field("ty", &self.ty).finish()
+    }
+}
+
+/// Host functions to satisfy a module's imports, keyed by module and name.
+#[derive(Debug, Clone, Default)]
+pub struct Imports {
+    funcs: HashMap<(String, String), HostFunc>,
+}
+
+impl Imports {
+    pub fn new() -> Self {
+        Imports::default()
+    }
+
+    /// Provides `func` as the import `name` from `module`, replacing any
+    /// function already registered there.
+    pub fn func<F>(&mut self, module: &str, name: &str, ty: FuncType, func: F) -> &mut Self
+    where
+        F: Fn(&mut Caller, &[Value]) -> Result<Vec<Value>, Trap> + 'static,
+    {
+        let key = (module.to_string(), name.to_string());
+        self.funcs.insert(key, HostFunc::new(ty, func));
+        self
+    }
+
+    pub fn get_func(&self, module: &str, name: &str) -> Option<&HostFunc> {
+        self.funcs.get(&(module.to_string(), name.to_string()))
+    }
+}
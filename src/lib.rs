@@ -1,212 +1,3103 @@
+use std::cell::{Ref, RefCell, RefMut};
 use std::collections::HashMap;
-use std::convert::TryInto;
+use std::convert::TryFrom;
+use std::ops::Range;
+use std::rc::Rc;
 
-#[derive(Debug)]
+use numeric::Float;
+
+pub mod binary;
+mod error;
+mod host;
+mod linker;
+mod memory;
+mod module;
+mod numeric;
+pub mod text;
+mod trap;
+mod validate;
+mod value;
+pub mod wast;
+
+pub use error::Error;
+pub use host::{Caller, HostFunc, Imports};
+pub use linker::Linker;
+pub use memory::{LittleEndian, Memory};
+pub use module::{
+    ConstExpr, Data, Elem, ElemMode, Export, ExportDesc, FuncType, Global, GlobalType, Import,
+    ImportDesc, Limits, Module, TableType, MAX_PAGES, MAX_TABLE_SIZE, PAGE_SIZE,
+};
+pub use trap::Trap;
+pub use validate::ValidationError;
+pub use value::{RefType, ValType, Value};
+
+/// The values a structured block takes from and leaves on the stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockType {
+    Empty,
+    Value(ValType),
+    /// Parameters and results given by the module's type at this index.
+    Type(usize),
+}
+
+/// The static immediates of a load or store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemArg {
+    /// Alignment hint, as a power of two.
+    pub align: u32,
+    /// Added to the dynamic address to form the effective address.
+    pub offset: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
-    Const(f64),
-    Mul,
-    Add,
-    Load,
-    Store,
+    Unreachable,
+    Nop,
+    Block(BlockType),
+    Loop(BlockType),
+    If(BlockType),
+    Else,
+    End,
+    Br(usize),
+    BrIf(usize),
+    BrTable(Vec<usize>, usize),
+    Return,
+    Drop,
+    Select,
+    /// `select` with the type of its operands given, as references need.
+    SelectTyped(Vec<ValType>),
+    I32Const(i32),
+    I64Const(i64),
+    F32Const(f32),
+    F64Const(f64),
+    RefNull(RefType),
+    RefIsNull,
+    RefFunc(usize),
+    I32Eqz,
+    I32Eq,
+    I32Ne,
+    I32LtS,
+    I32LtU,
+    I32GtS,
+    I32GtU,
+    I32LeS,
+    I32LeU,
+    I32GeS,
+    I32GeU,
+    I64Eqz,
+    I64Eq,
+    I64Ne,
+    I64LtS,
+    I64LtU,
+    I64GtS,
+    I64GtU,
+    I64LeS,
+    I64LeU,
+    I64GeS,
+    I64GeU,
+    F32Eq,
+    F32Ne,
+    F32Lt,
+    F32Gt,
+    F32Le,
+    F32Ge,
+    F64Eq,
+    F64Ne,
+    F64Lt,
+    F64Gt,
+    F64Le,
+    F64Ge,
+    I32Clz,
+    I32Ctz,
+    I32Popcnt,
+    I32Add,
+    I32Sub,
+    I32Mul,
+    I32DivS,
+    I32DivU,
+    I32RemS,
+    I32RemU,
+    I32And,
+    I32Or,
+    I32Xor,
+    I32Shl,
+    I32ShrS,
+    I32ShrU,
+    I32Rotl,
+    I32Rotr,
+    I64Clz,
+    I64Ctz,
+    I64Popcnt,
+    I64Add,
+    I64Sub,
+    I64Mul,
+    I64DivS,
+    I64DivU,
+    I64RemS,
+    I64RemU,
+    I64And,
+    I64Or,
+    I64Xor,
+    I64Shl,
+    I64ShrS,
+    I64ShrU,
+    I64Rotl,
+    I64Rotr,
+    F32Abs,
+    F32Neg,
+    F32Ceil,
+    F32Floor,
+    F32Trunc,
+    F32Nearest,
+    F32Sqrt,
+    F32Add,
+    F32Sub,
+    F32Mul,
+    F32Div,
+    F32Min,
+    F32Max,
+    F32Copysign,
+    F64Abs,
+    F64Neg,
+    F64Ceil,
+    F64Floor,
+    F64Trunc,
+    F64Nearest,
+    F64Sqrt,
+    F64Add,
+    F64Sub,
+    F64Mul,
+    F64Div,
+    F64Min,
+    F64Max,
+    F64Copysign,
+    I32WrapI64,
+    I32TruncF32S,
+    I32TruncF32U,
+    I32TruncF64S,
+    I32TruncF64U,
+    I64ExtendI32S,
+    I64ExtendI32U,
+    I64TruncF32S,
+    I64TruncF32U,
+    I64TruncF64S,
+    I64TruncF64U,
+    F32ConvertI32S,
+    F32ConvertI32U,
+    F32ConvertI64S,
+    F32ConvertI64U,
+    F32DemoteF64,
+    F64ConvertI32S,
+    F64ConvertI32U,
+    F64ConvertI64S,
+    F64ConvertI64U,
+    F64PromoteF32,
+    I32ReinterpretF32,
+    I64ReinterpretF64,
+    F32ReinterpretI32,
+    F64ReinterpretI64,
+    I32Extend8S,
+    I32Extend16S,
+    I64Extend8S,
+    I64Extend16S,
+    I64Extend32S,
+    I32TruncSatF32S,
+    I32TruncSatF32U,
+    I32TruncSatF64S,
+    I32TruncSatF64U,
+    I64TruncSatF32S,
+    I64TruncSatF32U,
+    I64TruncSatF64S,
+    I64TruncSatF64U,
+    I32Load(MemArg),
+    I64Load(MemArg),
+    F32Load(MemArg),
+    F64Load(MemArg),
+    I32Load8S(MemArg),
+    I32Load8U(MemArg),
+    I32Load16S(MemArg),
+    I32Load16U(MemArg),
+    I64Load8S(MemArg),
+    I64Load8U(MemArg),
+    I64Load16S(MemArg),
+    I64Load16U(MemArg),
+    I64Load32S(MemArg),
+    I64Load32U(MemArg),
+    I32Store(MemArg),
+    I64Store(MemArg),
+    F32Store(MemArg),
+    F64Store(MemArg),
+    I32Store8(MemArg),
+    I32Store16(MemArg),
+    I64Store8(MemArg),
+    I64Store16(MemArg),
+    I64Store32(MemArg),
+    MemorySize,
+    MemoryGrow,
+    /// Copies from the data segment at this index into memory.
+    MemoryInit(usize),
+    DataDrop(usize),
+    MemoryCopy,
+    MemoryFill,
+    /// Copies from an element segment into a table, given their indices.
+    TableInit(usize, usize),
+    ElemDrop(usize),
+    /// Copies between tables, given the destination and source.
+    TableCopy(usize, usize),
+    TableGet(usize),
+    TableSet(usize),
+    TableSize(usize),
+    TableGrow(usize),
+    TableFill(usize),
     LocalGet(usize),
     LocalSet(usize),
+    LocalTee(usize),
+    GlobalGet(usize),
+    GlobalSet(usize),
     CallFunc(usize),
+    /// Calls a function from a table, given the expected type and the table.
+    CallIndirect(usize, usize),
 }
 
+#[derive(Debug)]
 pub struct Function {
-    nparams: usize,
-    returns: bool,
+    /// Index of the function's signature in the module's types.
+    ty: usize,
+    /// Declared locals, following the parameters in the local index space.
+    locals: Vec<ValType>,
     code: Vec<Instruction>,
+    targets: Vec<usize>,
 }
 
 impl Function {
-    pub fn new(nparams: usize, returns: bool, code: Vec<Instruction>) -> Self {
+    pub fn new(ty: usize, locals: Vec<ValType>, code: Vec<Instruction>) -> Self {
+        let targets = block_targets(&code);
         Function {
-            nparams,
-            returns,
+            ty,
+            locals,
             code,
+            targets,
+        }
+    }
+
+    /// Index of the function's type in the module's types.
+    pub fn ty(&self) -> usize {
+        self.ty
+    }
+
+    /// Type of the local at `index`, counting the parameters of the
+    /// function's type `ty` first.
+    pub fn local(&self, ty: &FuncType, index: usize) -> Option<ValType> {
+        ty.params.iter().chain(&self.locals).nth(index).copied()
+    }
+}
+
+/// For each `Block`, `Loop` and `If` the index of its matching `End`, or of
+/// the `Else` for an `If` that has one, which in turn maps to the `End`.
+fn block_targets(code: &[Instruction]) -> Vec<usize> {
+    let mut targets = vec![0; code.len()];
+    let mut open = Vec::new();
+    for (pc, instruction) in code.iter().enumerate() {
+        match instruction {
+            Instruction::Block(_) | Instruction::Loop(_) | Instruction::If(_) => open.push(pc),
+            Instruction::Else => {
+                if let Some(start) = open.pop() {
+                    targets[start] = pc;
+                }
+                open.push(pc);
+            }
+            Instruction::End => {
+                if let Some(start) = open.pop() {
+                    targets[start] = pc;
+                }
+            }
+            _ => {}
+        }
+    }
+    targets
+}
+
+/// Passes through a divisor, trapping if it is zero.
+fn nonzero<T: Default + PartialEq>(divisor: T) -> Result<T, Trap> {
+    if divisor == T::default() {
+        return Err(Trap::IntegerDivideByZero);
+    }
+    Ok(divisor)
+}
+
+/// Call depth at which `Instance` traps with `Trap::CallStackExhausted`,
+/// unless configured otherwise with `Instance::set_max_call_depth`.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
+
+/// An active function invocation on the store's call stack.
+struct Frame {
+    /// The instance whose function is being executed.
+    instance: usize,
+    /// Index of the function among its module's functions, not counting
+    /// imported ones.
+    func: usize,
+    /// Where execution continues in the caller once this function returns.
+    return_pc: usize,
+    /// Start of the function's parameters and locals in `Store::locals`.
+    locals_base: usize,
+    /// Operand stack height once the arguments were popped.
+    height: usize,
+    /// Start of the function's labels in `Store::labels`.
+    labels_base: usize,
+}
+
+/// A branch target within the function being executed.
+struct Label {
+    arity: usize,
+    /// Operand stack height when the block was entered.
+    height: usize,
+    /// Where execution continues after branching to this label.
+    cont: usize,
+}
+
+enum FuncInst {
+    Host(HostFunc),
+    /// A function defined by the module of the instance at `instance`, by
+    /// its index among the module's functions.
+    Wasm {
+        instance: usize,
+        index: usize,
+    },
+}
+
+struct Table {
+    ty: RefType,
+    /// What each element refers to, or `None` for null references.
+    elems: Vec<Option<usize>>,
+    max: Option<u32>,
+}
+
+impl Table {
+    /// A table of `ty.limits.min` null elements, or `None` if that's more
+    /// than `MAX_TABLE_SIZE` or can't be allocated.
+    fn new(ty: &TableType) -> Option<Self> {
+        if ty.limits.min > MAX_TABLE_SIZE {
+            return None;
+        }
+        let mut elems = Vec::new();
+        elems.try_reserve_exact(ty.limits.min as usize).ok()?;
+        elems.resize(ty.limits.min as usize, None);
+        Some(Table {
+            ty: ty.elem,
+            elems,
+            max: ty.limits.max,
+        })
+    }
+
+    /// Adds `delta` elements set to `init` and returns the previous size,
+    /// or `None` if the table can't grow that much.
+    fn grow(&mut self, delta: u32, init: Option<usize>) -> Option<u32> {
+        let size = self.elems.len() as u32;
+        let new_size = size.checked_add(delta)?;
+        if new_size > self.max.unwrap_or(MAX_TABLE_SIZE).min(MAX_TABLE_SIZE) {
+            return None;
+        }
+        self.elems.resize(new_size as usize, init);
+        Some(size)
+    }
+}
+
+struct GlobalInst {
+    ty: GlobalType,
+    val: Value,
+}
+
+/// A definition in a store, by its address among those of its kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Extern {
+    Func(usize),
+    Table(usize),
+    Memory(usize),
+    Global(usize),
+}
+
+/// The addresses of an instance's definitions in its store, in the order of
+/// its module's index spaces.
+struct InstanceData {
+    module: Rc<Module>,
+    funcs: Vec<usize>,
+    tables: Vec<usize>,
+    memory: usize,
+    globals: Vec<usize>,
+    exports: HashMap<String, ExportDesc>,
+    /// The references in each element segment, emptied once dropped.
+    elems: Vec<Vec<Option<usize>>>,
+    /// Whether each data segment has been dropped.
+    dropped_data: Vec<bool>,
+}
+
+/// Whether a table or memory of `size` and `max` can be imported where
+/// `limits` are expected.
+fn limits_match(size: u32, max: Option<u32>, limits: Limits) -> bool {
+    size >= limits.min
+        && match (max, limits.max) {
+            (_, None) => true,
+            (Some(max), Some(expected)) => max <= expected,
+            (None, Some(_)) => false,
         }
+}
+
+/// The indices `start..start + len`, if they are all below `size`.
+fn range(start: u32, len: u32, size: usize) -> Option<Range<usize>> {
+    let end = (start as usize).checked_add(len as usize)?;
+    if end > size {
+        return None;
     }
+    Some(start as usize..end)
 }
-pub struct Machine {
-    stack: Vec<f64>,
-    memory: Vec<u8>,
-    functions: Vec<Function>,
+
+fn func_type<'a>(module: &'a Module, func: &Function) -> Result<&'a FuncType, Trap> {
+    module
+        .types
+        .get(func.ty)
+        .ok_or(Trap::UndefinedType(func.ty))
+}
+
+/// The runtime state of any number of instances that can call each other
+/// and share tables, memories and globals, along with the stacks of the
+/// code they are running.
+pub(crate) struct Store {
+    stack: Vec<Value>,
+    /// Locals of every active frame, each frame's starting at its `locals_base`.
+    locals: Vec<Value>,
+    /// Labels of every active frame, each frame's starting at its `labels_base`.
+    labels: Vec<Label>,
+    frames: Vec<Frame>,
+    max_call_depth: usize,
+    funcs: Vec<FuncInst>,
+    tables: Vec<Table>,
+    memories: Vec<Memory>,
+    globals: Vec<GlobalInst>,
+    instances: Vec<InstanceData>,
 }
 
-impl Machine {
-    pub fn new(functions: Vec<Function>, mem_size: usize) -> Self {
-        Machine {
+impl Default for Store {
+    fn default() -> Self {
+        Store {
             stack: Vec::new(),
-            memory: vec![0; mem_size],
-            functions,
+            locals: Vec::new(),
+            labels: Vec::new(),
+            frames: Vec::new(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            funcs: Vec::new(),
+            tables: Vec::new(),
+            memories: Vec::new(),
+            globals: Vec::new(),
+            instances: Vec::new(),
+        }
+    }
+}
+
+impl Store {
+    pub(crate) fn alloc_host(&mut self, func: HostFunc) -> usize {
+        self.funcs.push(FuncInst::Host(func));
+        self.funcs.len() - 1
+    }
+
+    /// Adds an instance of `module`, with `externs` supplying its imports in
+    /// order, and returns its index. The instance's globals are initialized,
+    /// its active element and data segments copied into its tables and
+    /// memory, and then its start function called. A module without a memory
+    /// gets an empty one that can't grow.
+    ///
+    /// If that fails before any segment is copied, what was allocated for the
+    /// instance is removed again. Once copying has begun a failure leaves the
+    /// instance in the store, as the spec requires: segments already copied
+    /// into imported tables and memories stay, and may refer to its
+    /// functions.
+    pub(crate) fn instantiate(
+        &mut self,
+        module: Rc<Module>,
+        externs: &[Extern],
+    ) -> Result<usize, Error> {
+        let sizes = (
+            self.funcs.len(),
+            self.tables.len(),
+            self.memories.len(),
+            self.globals.len(),
+        );
+        let instance = match self.alloc_instance(&module, externs) {
+            Ok(instance) => instance,
+            Err(err) => {
+                let (funcs, tables, memories, globals) = sizes;
+                self.funcs.truncate(funcs);
+                self.tables.truncate(tables);
+                self.memories.truncate(memories);
+                self.globals.truncate(globals);
+                return Err(err);
+            }
+        };
+
+        // Active segments are copied as if by `table.init` and `memory.init`,
+        // and then dropped along with declarative ones.
+        for (index, elem) in module.elems.iter().enumerate() {
+            if let ElemMode::Active { table, offset } = &elem.mode {
+                let offset = self.eval_offset(instance, offset)?;
+                let len = elem.init.len() as u32;
+                self.table_init(instance, index, *table, offset, 0, len)?;
+            }
+            if elem.mode != ElemMode::Passive {
+                self.instances[instance].elems[index].clear();
+            }
+        }
+        for (index, segment) in module.data.iter().enumerate() {
+            if let Some(offset) = &segment.offset {
+                let offset = self.eval_offset(instance, offset)?;
+                let len = segment.init.len() as u32;
+                self.memory_init(instance, index, offset, 0, len)?;
+                self.instances[instance].dropped_data[index] = true;
+            }
+        }
+        let data = &self.instances[instance];
+        if let Some(start) = module.start {
+            let addr = match data.funcs.get(start) {
+                Some(&addr) => addr,
+                None => return Err(Trap::UndefinedFunction(start).into()),
+            };
+            self.call(instance, addr, Vec::new())?;
+        }
+        Ok(instance)
+    }
+
+    /// Resolves the imports of `module` to `externs`, allocates its own
+    /// definitions and adds its instance, whose index is returned.
+    fn alloc_instance(&mut self, module: &Rc<Module>, externs: &[Extern]) -> Result<usize, Error> {
+        if externs.len() != module.imports.len() {
+            return Err(Error::ImportCountMismatch {
+                expected: module.imports.len(),
+                found: externs.len(),
+            });
+        }
+        let mut funcs = Vec::new();
+        let mut tables = Vec::new();
+        let mut memory = None;
+        let mut globals = Vec::new();
+        for (import, ext) in module.imports.iter().zip(externs) {
+            let compatible = match (import.desc, *ext) {
+                (ImportDesc::Func(ty), Extern::Func(addr)) => {
+                    funcs.push(addr);
+                    module.types.get(ty) == Some(self.type_of(addr)?)
+                }
+                (ImportDesc::Table(ty), Extern::Table(addr)) => {
+                    tables.push(addr);
+                    let table = &self.tables[addr];
+                    table.ty == ty.elem
+                        && limits_match(table.elems.len() as u32, table.max, ty.limits)
+                }
+                (ImportDesc::Memory(limits), Extern::Memory(addr)) => {
+                    memory = Some(addr);
+                    let memory = &self.memories[addr];
+                    limits_match(memory.size(), memory.max(), limits)
+                }
+                (ImportDesc::Global(ty), Extern::Global(addr)) => {
+                    globals.push(addr);
+                    self.globals[addr].ty == ty
+                }
+                _ => false,
+            };
+            if !compatible {
+                return Err(Error::IncompatibleImportType {
+                    module: import.module.clone(),
+                    name: import.name.clone(),
+                });
+            }
+        }
+
+        let instance = self.instances.len();
+        for index in 0..module.functions.len() {
+            funcs.push(self.funcs.len());
+            self.funcs.push(FuncInst::Wasm { instance, index });
+        }
+        for ty in &module.tables {
+            let table = Table::new(ty).ok_or(Error::AllocationFailed("table"))?;
+            tables.push(self.tables.len());
+            self.tables.push(table);
+        }
+        let memory = match memory {
+            Some(memory) => memory,
+            None => {
+                let limits = module.memory.unwrap_or(Limits {
+                    min: 0,
+                    max: Some(0),
+                });
+                let memory = Memory::new(limits).ok_or(Error::AllocationFailed("memory"))?;
+                self.memories.push(memory);
+                self.memories.len() - 1
+            }
+        };
+        for global in &module.globals {
+            let val = self.eval_const(&global.init, &funcs, &globals)?;
+            globals.push(self.globals.len());
+            self.globals.push(GlobalInst { ty: global.ty, val });
+        }
+        let mut elems = Vec::with_capacity(module.elems.len());
+        for elem in &module.elems {
+            let refs = elem
+                .init
+                .iter()
+                .map(|expr| {
+                    let val = self.eval_const(expr, &funcs, &globals)?;
+                    val.ref_addr().ok_or(Trap::TypeMismatch)
+                })
+                .collect::<Result<_, Trap>>()?;
+            elems.push(refs);
+        }
+        self.instances.push(InstanceData {
+            module: Rc::clone(module),
+            funcs,
+            tables,
+            memory,
+            globals,
+            exports: module
+                .exports
+                .iter()
+                .map(|export| (export.name.clone(), export.desc))
+                .collect(),
+            elems,
+            dropped_data: vec![false; module.data.len()],
+        });
+        Ok(instance)
+    }
+
+    /// Evaluates the offset of an active segment of `instance`.
+    fn eval_offset(&self, instance: usize, offset: &ConstExpr) -> Result<u32, Trap> {
+        let data = &self.instances[instance];
+        match self.eval_const(offset, &data.funcs, &data.globals)? {
+            Value::I32(offset) => Ok(offset as u32),
+            _ => Err(Trap::TypeMismatch),
+        }
+    }
+
+    /// Evaluates an initializer given the addresses of the functions and
+    /// globals it may refer to.
+    fn eval_const(
+        &self,
+        expr: &ConstExpr,
+        funcs: &[usize],
+        globals: &[usize],
+    ) -> Result<Value, Trap> {
+        match *expr {
+            ConstExpr::Const(val) => Ok(val),
+            ConstExpr::GlobalGet(index) => match globals.get(index) {
+                Some(&addr) => Ok(self.globals[addr].val),
+                None => Err(Trap::UndefinedGlobal(index)),
+            },
+            ConstExpr::RefNull(ty) => Ok(Value::reference(ty, None)),
+            ConstExpr::RefFunc(index) => match funcs.get(index) {
+                Some(&addr) => Ok(Value::FuncRef(Some(addr))),
+                None => Err(Trap::UndefinedFunction(index)),
+            },
+        }
+    }
+
+    /// The store address of what `instance` exports as `desc`.
+    pub(crate) fn export_extern(&self, instance: usize, desc: ExportDesc) -> Option<Extern> {
+        let data = &self.instances[instance];
+        match desc {
+            ExportDesc::Func(index) => data.funcs.get(index).copied().map(Extern::Func),
+            ExportDesc::Table(index) => data.tables.get(index).copied().map(Extern::Table),
+            ExportDesc::Memory(0) => Some(Extern::Memory(data.memory)),
+            ExportDesc::Memory(_) => None,
+            ExportDesc::Global(index) => data.globals.get(index).copied().map(Extern::Global),
+        }
+    }
+
+    /// The memory of the instance running the current frame.
+    fn memory(&mut self) -> &mut Memory {
+        let instance = &self.instances[self.current_instance()];
+        &mut self.memories[instance.memory]
+    }
+
+    /// Copies `n` bytes from `s` in the data segment at index `data` of
+    /// `instance` into its memory at `d`. A dropped segment is empty.
+    fn memory_init(
+        &mut self,
+        instance: usize,
+        data: usize,
+        d: u32,
+        s: u32,
+        n: u32,
+    ) -> Result<(), Trap> {
+        let instance = &self.instances[instance];
+        let bytes: &[u8] = match instance.module.data.get(data) {
+            Some(segment) if !instance.dropped_data[data] => &segment.init,
+            _ => &[],
+        };
+        let memory = self.memories[instance.memory].data_mut();
+        match (range(s, n, bytes.len()), range(d, n, memory.len())) {
+            (Some(src), Some(dst)) => {
+                memory[dst].copy_from_slice(&bytes[src]);
+                Ok(())
+            }
+            _ => Err(Trap::MemoryOutOfBounds),
+        }
+    }
+
+    /// Copies `n` references from `s` in the element segment at index `elem`
+    /// of `instance` into its table at index `table`, starting at `d`.
+    fn table_init(
+        &mut self,
+        instance: usize,
+        elem: usize,
+        table: usize,
+        d: u32,
+        s: u32,
+        n: u32,
+    ) -> Result<(), Trap> {
+        let instance = &self.instances[instance];
+        let refs = instance.elems.get(elem).map_or(&[][..], Vec::as_slice);
+        let table = match instance.tables.get(table) {
+            Some(&addr) => &mut self.tables[addr].elems,
+            None => return Err(Trap::TableOutOfBounds),
+        };
+        match (range(s, n, refs.len()), range(d, n, table.len())) {
+            (Some(src), Some(dst)) => {
+                table[dst].copy_from_slice(&refs[src]);
+                Ok(())
+            }
+            _ => Err(Trap::TableOutOfBounds),
+        }
+    }
+
+    /// Copies `n` elements from `s` in the current instance's table at index
+    /// `src` to `d` in the one at `dst`, which may be the same table.
+    fn table_copy(&mut self, dst: usize, src: usize, d: u32, s: u32, n: u32) -> Result<(), Trap> {
+        let tables = &self.current().tables;
+        let (dst, src) = match (tables.get(dst), tables.get(src)) {
+            (Some(&dst), Some(&src)) => (dst, src),
+            _ => return Err(Trap::TableOutOfBounds),
+        };
+        let src_len = self.tables[src].elems.len();
+        let dst_len = self.tables[dst].elems.len();
+        match (range(s, n, src_len), range(d, n, dst_len)) {
+            (Some(from), Some(to)) if src == dst => {
+                self.tables[dst].elems.copy_within(from, to.start);
+                Ok(())
+            }
+            (Some(from), Some(to)) => {
+                let elems = self.tables[src].elems[from].to_vec();
+                self.tables[dst].elems[to].copy_from_slice(&elems);
+                Ok(())
+            }
+            _ => Err(Trap::TableOutOfBounds),
         }
     }
 
-    pub fn load(&mut self, addr: usize) -> f64 {
-        f64::from_le_bytes(self.memory[addr..addr + 8].try_into().unwrap())
+    /// Pops the destination, source and length operands of a bulk
+    /// instruction.
+    fn pop_bulk_args(&mut self) -> Result<(u32, u32, u32), Trap> {
+        let n = self.pop_as::<i32>()? as u32;
+        let s = self.pop_as::<i32>()? as u32;
+        let d = self.pop_as::<i32>()? as u32;
+        Ok((d, s, n))
     }
 
-    pub fn store(&mut self, addr: usize, val: f64) {
-        self.memory[addr..addr + 8].copy_from_slice(&val.to_le_bytes());
+    /// Pops an address and adds the static offset, which can't wrap around
+    /// as both are 32-bit.
+    fn effective_address(&mut self, memarg: &MemArg) -> Result<usize, Trap> {
+        let addr = self.pop_as::<i32>()? as u32 as u64 + memarg.offset as u64;
+        usize::try_from(addr).map_err(|_| Trap::MemoryOutOfBounds)
     }
 
-    pub fn push(&mut self, item: f64) {
+    /// Loads a `T` and extends it to a value.
+    fn load_op<T, R>(&mut self, memarg: &MemArg, extend: impl FnOnce(T) -> R) -> Result<(), Trap>
+    where
+        T: LittleEndian,
+        R: Into<Value>,
+    {
+        let addr = self.effective_address(memarg)?;
+        let val = self.memory().load::<T>(addr)?;
+        self.push(extend(val).into());
+        Ok(())
+    }
+
+    /// Wraps a value to an `S` and stores it.
+    fn store_op<T, S>(&mut self, memarg: &MemArg, wrap: impl FnOnce(T) -> S) -> Result<(), Trap>
+    where
+        T: TryFrom<Value>,
+        S: LittleEndian,
+    {
+        let val = self.pop_as::<T>()?;
+        let addr = self.effective_address(memarg)?;
+        self.memory().store(addr, wrap(val))
+    }
+
+    fn push(&mut self, item: Value) {
         self.stack.push(item);
     }
 
-    pub fn pop(&mut self) -> Option<f64> {
-        self.stack.pop()
+    fn pop_value(&mut self) -> Result<Value, Trap> {
+        self.stack.pop().ok_or(Trap::StackUnderflow)
+    }
+
+    fn pop_as<T: TryFrom<Value>>(&mut self) -> Result<T, Trap> {
+        T::try_from(self.pop_value()?).map_err(|_| Trap::TypeMismatch)
+    }
+
+    /// Pops a reference and returns what it refers to.
+    fn pop_ref(&mut self) -> Result<Option<usize>, Trap> {
+        self.pop_value()?.ref_addr().ok_or(Trap::TypeMismatch)
+    }
+
+    fn unop<T, R>(&mut self, op: impl FnOnce(T) -> R) -> Result<(), Trap>
+    where
+        T: TryFrom<Value>,
+        R: Into<Value>,
+    {
+        let val = self.pop_as::<T>()?;
+        self.push(op(val).into());
+        Ok(())
+    }
+
+    fn try_unop<T, R>(&mut self, op: impl FnOnce(T) -> Result<R, Trap>) -> Result<(), Trap>
+    where
+        T: TryFrom<Value>,
+        R: Into<Value>,
+    {
+        let val = self.pop_as::<T>()?;
+        self.push(op(val)?.into());
+        Ok(())
+    }
+
+    fn binop<T, R>(&mut self, op: impl FnOnce(T, T) -> R) -> Result<(), Trap>
+    where
+        T: TryFrom<Value>,
+        R: Into<Value>,
+    {
+        self.try_binop(|left, right| Ok(op(left, right)))
+    }
+
+    fn try_binop<T, R>(&mut self, op: impl FnOnce(T, T) -> Result<R, Trap>) -> Result<(), Trap>
+    where
+        T: TryFrom<Value>,
+        R: Into<Value>,
+    {
+        let right = self.pop_as::<T>()?;
+        let left = self.pop_as::<T>()?;
+        self.push(op(left, right)?.into());
+        Ok(())
     }
 
-    pub fn call(&mut self, func: &Function, args: Vec<f64>) -> Option<f64> {
-        let mut locals = HashMap::new();
-        args.iter().enumerate().for_each(|(index, val)| {
-            locals.insert(index, val);
+    /// Index of the instance running the current frame.
+    fn current_instance(&self) -> usize {
+        self.frames[self.frames.len() - 1].instance
+    }
+
+    /// The instance running the current frame.
+    fn current(&self) -> &InstanceData {
+        &self.instances[self.current_instance()]
+    }
+
+    /// The address of the function at `index` in the current instance's
+    /// function index space.
+    fn func_addr(&self, index: usize) -> Result<usize, Trap> {
+        let funcs = &self.current().funcs;
+        funcs
+            .get(index)
+            .copied()
+            .ok_or(Trap::UndefinedFunction(index))
+    }
+
+    /// The address of the global at `index` in the current instance's global
+    /// index space.
+    fn global_addr(&self, index: usize) -> Result<usize, Trap> {
+        let globals = &self.current().globals;
+        globals
+            .get(index)
+            .copied()
+            .ok_or(Trap::UndefinedGlobal(index))
+    }
+
+    /// The table at `index` in the current instance's table index space.
+    fn table(&mut self, index: usize) -> Result<&mut Table, Trap> {
+        match self.current().tables.get(index) {
+            Some(&addr) => Ok(&mut self.tables[addr]),
+            None => Err(Trap::TableOutOfBounds),
+        }
+    }
+
+    /// Type of the function at `addr`, imported or defined.
+    fn type_of(&self, addr: usize) -> Result<&FuncType, Trap> {
+        match &self.funcs[addr] {
+            FuncInst::Host(host) => Ok(host.ty()),
+            FuncInst::Wasm { instance, index } => {
+                let module = &self.instances[*instance].module;
+                func_type(module, &module.functions[*index])
+            }
+        }
+    }
+
+    /// Calls the function at `addr` from `caller` with its arguments on the
+    /// operand stack. Host functions run to completion, leaving their
+    /// results on the stack, while a frame is pushed for a defined function,
+    /// in which case `true` is returned for the caller to run it.
+    fn call_func(&mut self, caller: usize, addr: usize, return_pc: usize) -> Result<bool, Trap> {
+        match &self.funcs[addr] {
+            FuncInst::Host(host) => {
+                let host = host.clone();
+                let params = host.ty().params.len();
+                let args = match self.stack.len().checked_sub(params) {
+                    Some(args) => self.stack.split_off(args),
+                    None => return Err(Trap::StackUnderflow),
+                };
+                let mut caller = Caller {
+                    memory: &mut self.memories[self.instances[caller].memory],
+                };
+                let results = host.call(&mut caller, &args)?;
+                self.stack.extend(results);
+                Ok(false)
+            }
+            &FuncInst::Wasm { instance, index } => {
+                self.enter(instance, index, return_pc)?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Opens a block of type `ty` over its parameters on the operand stack.
+    /// Branches to a loop carry its parameters, to other blocks its results.
+    fn push_label(&mut self, ty: BlockType, cont: usize, is_loop: bool) -> Result<(), Trap> {
+        let (params, results) = match ty {
+            BlockType::Empty => (0, 0),
+            BlockType::Value(_) => (0, 1),
+            BlockType::Type(index) => match self.current().module.types.get(index) {
+                Some(ty) => (ty.params.len(), ty.results.len()),
+                None => return Err(Trap::UndefinedType(index)),
+            },
+        };
+        let height = match self.stack.len().checked_sub(params) {
+            Some(height) => height,
+            None => return Err(Trap::StackUnderflow),
+        };
+        self.labels.push(Label {
+            arity: if is_loop { params } else { results },
+            height,
+            cont,
         });
+        Ok(())
+    }
+
+    /// Pops an index into `table` and returns the address of the function
+    /// there, checking that it has the type at index `ty`.
+    fn indirect_callee(&mut self, ty: usize, table: usize) -> Result<usize, Trap> {
+        let elem = self.pop_as::<i32>()? as u32 as usize;
+        let addr = match self.current().tables.get(table) {
+            Some(&table) => match self.tables[table].elems.get(elem) {
+                Some(Some(addr)) => *addr,
+                Some(None) => return Err(Trap::UninitializedElement),
+                None => return Err(Trap::UndefinedElement),
+            },
+            None => return Err(Trap::TableOutOfBounds),
+        };
+        if self.current().module.types.get(ty) != Some(self.type_of(addr)?) {
+            return Err(Trap::IndirectCallTypeMismatch);
+        }
+        Ok(addr)
+    }
 
-        self.execute(&func.code, &mut Some(locals));
+    /// Calls the function at `addr` from `instance` with `args` and returns
+    /// its results.
+    fn call(&mut self, instance: usize, addr: usize, args: Vec<Value>) -> Result<Vec<Value>, Trap> {
+        let ty = self.type_of(addr)?;
+        let arg_types = args.iter().map(Value::ty);
+        if !arg_types.eq(ty.params.iter().copied()) {
+            return Err(Trap::TypeMismatch);
+        }
 
-        if func.returns {
-            self.pop()
-        } else {
-            None
+        let depth = self.frames.len();
+        let height = self.stack.len();
+        let locals = self.locals.len();
+        let labels = self.labels.len();
+        self.stack.extend(args);
+        let result = match self.call_func(instance, addr, 0) {
+            Ok(true) => self.execute(depth),
+            Ok(false) => Ok(()),
+            Err(trap) => Err(trap),
+        };
+        if let Err(trap) = result {
+            self.frames.truncate(depth);
+            self.stack.truncate(height);
+            self.locals.truncate(locals);
+            self.labels.truncate(labels);
+            return Err(trap);
         }
+
+        Ok(self.stack.split_off(height))
     }
 
-    pub fn execute(
-        &mut self,
-        instructions: &Vec<Instruction>,
-        locals: &mut Option<HashMap<usize, &f64>>,
-    ) {
-        for instruction in instructions {
-            println!("Op: {:?}, Stack: {:?}", instruction, self.stack);
-            match instruction {
-                Instruction::Const(item) => self.push(*item),
-                Instruction::Add => {
-                    let right = self.pop().unwrap();
-                    let left = self.pop().unwrap();
-                    self.push(left + right);
-                }
-                Instruction::Mul => {
-                    let right = self.pop().unwrap();
-                    let left = self.pop().unwrap();
-                    self.push(left * right);
-                }
-                Instruction::Load => {
-                    let addr = self.pop().unwrap();
-                    let val = self.load(addr as usize);
+    /// Pushes a frame for the function at `index` in `instance`'s module,
+    /// moving its arguments off the operand stack into its locals.
+    fn enter(&mut self, instance: usize, index: usize, return_pc: usize) -> Result<(), Trap> {
+        if self.frames.len() >= self.max_call_depth {
+            return Err(Trap::CallStackExhausted);
+        }
+        let module = Rc::clone(&self.instances[instance].module);
+        let func = &module.functions[index];
+        let params = func_type(&module, func)?.params.len();
+        let args = match self.stack.len().checked_sub(params) {
+            Some(args) => args,
+            None => return Err(Trap::StackUnderflow),
+        };
+        let locals_base = self.locals.len();
+        self.locals.extend(self.stack.drain(args..));
+        self.locals
+            .extend(func.locals.iter().map(|ty| Value::default(*ty)));
+        self.frames.push(Frame {
+            instance,
+            func: index,
+            return_pc,
+            locals_base,
+            height: self.stack.len(),
+            labels_base: self.labels.len(),
+        });
+        Ok(())
+    }
+
+    /// Pops the current frame, leaving only its results on the operand stack,
+    /// and returns where to resume the caller unless that is the host which
+    /// entered at call depth `depth`.
+    fn leave(&mut self, depth: usize) -> Result<Option<usize>, Trap> {
+        let frame = self.frames.pop().unwrap();
+        let module = &self.instances[frame.instance].module;
+        let arity = func_type(module, &module.functions[frame.func])?
+            .results
+            .len();
+        if self.stack.len() < frame.height + arity {
+            return Err(Trap::StackUnderflow);
+        }
+        let results = self.stack.split_off(self.stack.len() - arity);
+        self.stack.truncate(frame.height);
+        self.stack.extend(results);
+        self.locals.truncate(frame.locals_base);
+        self.labels.truncate(frame.labels_base);
+        if self.frames.len() == depth {
+            return Ok(None);
+        }
+        Ok(Some(frame.return_pc))
+    }
+
+    /// Unwinds the stack to the label `depth` levels out in the current frame
+    /// and returns where to continue, or `None` if the branch leaves the
+    /// function.
+    fn branch(&mut self, depth: usize) -> Result<Option<usize>, Trap> {
+        let base = self.frames[self.frames.len() - 1].labels_base;
+        if depth >= self.labels.len() - base {
+            return Ok(None);
+        }
+        let label = self.labels.remove(self.labels.len() - 1 - depth);
+        self.labels.truncate(self.labels.len() - depth);
+        if self.stack.len() < label.height + label.arity {
+            return Err(Trap::StackUnderflow);
+        }
+        let results = self.stack.split_off(self.stack.len() - label.arity);
+        self.stack.truncate(label.height);
+        self.stack.extend(results);
+        Ok(Some(label.cont))
+    }
+
+    fn local(&mut self, index: usize) -> Result<&mut Value, Trap> {
+        let frame = &self.frames[self.frames.len() - 1];
+        let module = &self.instances[frame.instance].module;
+        let func = &module.functions[frame.func];
+        if index >= func_type(module, func)?.params.len() + func.locals.len() {
+            return Err(Trap::UndefinedLocal(index));
+        }
+        Ok(&mut self.locals[frame.locals_base + index])
+    }
+
+    /// The module and index of the function running in the current frame.
+    fn running(&self) -> (Rc<Module>, usize) {
+        let frame = &self.frames[self.frames.len() - 1];
+        (
+            Rc::clone(&self.instances[frame.instance].module),
+            frame.func,
+        )
+    }
+
+    /// Runs the function on top of the call stack, along with everything it
+    /// calls, until it returns to the host at call depth `depth`.
+    fn execute(&mut self, depth: usize) -> Result<(), Trap> {
+        let (mut module, mut func_index) = self.running();
+        let mut pc = 0;
+        loop {
+            let func = &module.functions[func_index];
+            if pc >= func.code.len() {
+                match self.leave(depth)? {
+                    Some(cont) => {
+                        (module, func_index) = self.running();
+                        pc = cont;
+                        continue;
+                    }
+                    None => return Ok(()),
+                }
+            }
+            let code = &func.code;
+            let targets = &func.targets;
+            match &code[pc] {
+                Instruction::Unreachable => return Err(Trap::Unreachable),
+                Instruction::Nop => {}
+                Instruction::Block(ty) => self.push_label(*ty, targets[pc] + 1, false)?,
+                Instruction::Loop(ty) => self.push_label(*ty, pc, true)?,
+                Instruction::If(ty) => {
+                    let cond = self.pop_as::<i32>()?;
+                    let target = targets[pc];
+                    let has_else = code[target] == Instruction::Else;
+                    let end = if has_else { targets[target] } else { target };
+                    self.push_label(*ty, end + 1, false)?;
+                    if cond == 0 {
+                        // Skip to the else branch, or to the `End` popping the label.
+                        pc = if has_else { target + 1 } else { end };
+                        continue;
+                    }
+                }
+                Instruction::Else => {
+                    // The then branch is done, skip the else branch.
+                    pc = targets[pc];
+                    continue;
+                }
+                Instruction::End => {
+                    self.labels.pop();
+                }
+                Instruction::Br(depth) => {
+                    // Leaving the function is handled at the top of the loop.
+                    pc = self.branch(*depth)?.unwrap_or(code.len());
+                    continue;
+                }
+                Instruction::BrIf(depth) => {
+                    if self.pop_as::<i32>()? != 0 {
+                        pc = self.branch(*depth)?.unwrap_or(code.len());
+                        continue;
+                    }
+                }
+                Instruction::BrTable(table, default) => {
+                    let index = self.pop_as::<i32>()? as u32 as usize;
+                    let depth = *table.get(index).unwrap_or(default);
+                    pc = self.branch(depth)?.unwrap_or(code.len());
+                    continue;
+                }
+                Instruction::Return => {
+                    pc = code.len();
+                    continue;
+                }
+                Instruction::Drop => {
+                    self.pop_value()?;
+                }
+                Instruction::Select | Instruction::SelectTyped(_) => {
+                    let cond = self.pop_as::<i32>()?;
+                    let right = self.pop_value()?;
+                    let left = self.pop_value()?;
+                    self.push(if cond != 0 { left } else { right });
+                }
+                Instruction::I32Const(item) => self.push(Value::I32(*item)),
+                Instruction::I64Const(item) => self.push(Value::I64(*item)),
+                Instruction::F32Const(item) => self.push(Value::F32(*item)),
+                Instruction::F64Const(item) => self.push(Value::F64(*item)),
+                Instruction::RefNull(ty) => self.push(Value::reference(*ty, None)),
+                Instruction::RefIsNull => {
+                    let addr = self.pop_ref()?;
+                    self.push(addr.is_none().into());
+                }
+                Instruction::RefFunc(index) => {
+                    let addr = self.func_addr(*index)?;
+                    self.push(Value::FuncRef(Some(addr)));
+                }
+                Instruction::I32Eqz => self.unop(|val: i32| val == 0)?,
+                Instruction::I32Eq => self.binop(|left: i32, right: i32| left == right)?,
+                Instruction::I32Ne => self.binop(|left: i32, right: i32| left != right)?,
+                Instruction::I32LtS => self.binop(|left: i32, right: i32| left < right)?,
+                Instruction::I32LtU => {
+                    self.binop(|left: i32, right: i32| (left as u32) < (right as u32))?
+                }
+                Instruction::I32GtS => self.binop(|left: i32, right: i32| left > right)?,
+                Instruction::I32GtU => {
+                    self.binop(|left: i32, right: i32| left as u32 > right as u32)?
+                }
+                Instruction::I32LeS => self.binop(|left: i32, right: i32| left <= right)?,
+                Instruction::I32LeU => {
+                    self.binop(|left: i32, right: i32| left as u32 <= right as u32)?
+                }
+                Instruction::I32GeS => self.binop(|left: i32, right: i32| left >= right)?,
+                Instruction::I32GeU => {
+                    self.binop(|left: i32, right: i32| left as u32 >= right as u32)?
+                }
+                Instruction::I64Eqz => self.unop(|val: i64| val == 0)?,
+                Instruction::I64Eq => self.binop(|left: i64, right: i64| left == right)?,
+                Instruction::I64Ne => self.binop(|left: i64, right: i64| left != right)?,
+                Instruction::I64LtS => self.binop(|left: i64, right: i64| left < right)?,
+                Instruction::I64LtU => {
+                    self.binop(|left: i64, right: i64| (left as u64) < (right as u64))?
+                }
+                Instruction::I64GtS => self.binop(|left: i64, right: i64| left > right)?,
+                Instruction::I64GtU => {
+                    self.binop(|left: i64, right: i64| left as u64 > right as u64)?
+                }
+                Instruction::I64LeS => self.binop(|left: i64, right: i64| left <= right)?,
+                Instruction::I64LeU => {
+                    self.binop(|left: i64, right: i64| left as u64 <= right as u64)?
+                }
+                Instruction::I64GeS => self.binop(|left: i64, right: i64| left >= right)?,
+                Instruction::I64GeU => {
+                    self.binop(|left: i64, right: i64| left as u64 >= right as u64)?
+                }
+                Instruction::F32Eq => self.binop(|left: f32, right: f32| left == right)?,
+                Instruction::F32Ne => self.binop(|left: f32, right: f32| left != right)?,
+                Instruction::F32Lt => self.binop(|left: f32, right: f32| left < right)?,
+                Instruction::F32Gt => self.binop(|left: f32, right: f32| left > right)?,
+                Instruction::F32Le => self.binop(|left: f32, right: f32| left <= right)?,
+                Instruction::F32Ge => self.binop(|left: f32, right: f32| left >= right)?,
+                Instruction::F64Eq => self.binop(|left: f64, right: f64| left == right)?,
+                Instruction::F64Ne => self.binop(|left: f64, right: f64| left != right)?,
+                Instruction::F64Lt => self.binop(|left: f64, right: f64| left < right)?,
+                Instruction::F64Gt => self.binop(|left: f64, right: f64| left > right)?,
+                Instruction::F64Le => self.binop(|left: f64, right: f64| left <= right)?,
+                Instruction::F64Ge => self.binop(|left: f64, right: f64| left >= right)?,
+                Instruction::I32Clz => self.unop(|val: i32| val.leading_zeros() as i32)?,
+                Instruction::I32Ctz => self.unop(|val: i32| val.trailing_zeros() as i32)?,
+                Instruction::I32Popcnt => self.unop(|val: i32| val.count_ones() as i32)?,
+                Instruction::I32Add => self.binop(i32::wrapping_add)?,
+                Instruction::I32Sub => self.binop(i32::wrapping_sub)?,
+                Instruction::I32Mul => self.binop(i32::wrapping_mul)?,
+                Instruction::I32DivS => self.try_binop(|left: i32, right: i32| {
+                    left.checked_div(nonzero(right)?)
+                        .ok_or(Trap::IntegerOverflow)
+                })?,
+                Instruction::I32DivU => self.try_binop(|left: i32, right: i32| {
+                    Ok((left as u32 / nonzero(right)? as u32) as i32)
+                })?,
+                Instruction::I32RemS => {
+                    self.try_binop(|left: i32, right: i32| Ok(left.wrapping_rem(nonzero(right)?)))?
+                }
+                Instruction::I32RemU => self.try_binop(|left: i32, right: i32| {
+                    Ok((left as u32 % nonzero(right)? as u32) as i32)
+                })?,
+                Instruction::I32And => self.binop(|left: i32, right: i32| left & right)?,
+                Instruction::I32Or => self.binop(|left: i32, right: i32| left | right)?,
+                Instruction::I32Xor => self.binop(|left: i32, right: i32| left ^ right)?,
+                Instruction::I32Shl => {
+                    self.binop(|left: i32, right: i32| left.wrapping_shl(right as u32))?
+                }
+                Instruction::I32ShrS => {
+                    self.binop(|left: i32, right: i32| left.wrapping_shr(right as u32))?
+                }
+                Instruction::I32ShrU => self.binop(|left: i32, right: i32| {
+                    (left as u32).wrapping_shr(right as u32) as i32
+                })?,
+                Instruction::I32Rotl => {
+                    self.binop(|left: i32, right: i32| left.rotate_left(right as u32))?
+                }
+                Instruction::I32Rotr => {
+                    self.binop(|left: i32, right: i32| left.rotate_right(right as u32))?
+                }
+                Instruction::I64Clz => self.unop(|val: i64| val.leading_zeros() as i64)?,
+                Instruction::I64Ctz => self.unop(|val: i64| val.trailing_zeros() as i64)?,
+                Instruction::I64Popcnt => self.unop(|val: i64| val.count_ones() as i64)?,
+                Instruction::I64Add => self.binop(i64::wrapping_add)?,
+                Instruction::I64Sub => self.binop(i64::wrapping_sub)?,
+                Instruction::I64Mul => self.binop(i64::wrapping_mul)?,
+                Instruction::I64DivS => self.try_binop(|left: i64, right: i64| {
+                    left.checked_div(nonzero(right)?)
+                        .ok_or(Trap::IntegerOverflow)
+                })?,
+                Instruction::I64DivU => self.try_binop(|left: i64, right: i64| {
+                    Ok((left as u64 / nonzero(right)? as u64) as i64)
+                })?,
+                Instruction::I64RemS => {
+                    self.try_binop(|left: i64, right: i64| Ok(left.wrapping_rem(nonzero(right)?)))?
+                }
+                Instruction::I64RemU => self.try_binop(|left: i64, right: i64| {
+                    Ok((left as u64 % nonzero(right)? as u64) as i64)
+                })?,
+                Instruction::I64And => self.binop(|left: i64, right: i64| left & right)?,
+                Instruction::I64Or => self.binop(|left: i64, right: i64| left | right)?,
+                Instruction::I64Xor => self.binop(|left: i64, right: i64| left ^ right)?,
+                Instruction::I64Shl => {
+                    self.binop(|left: i64, right: i64| left.wrapping_shl(right as u32))?
+                }
+                Instruction::I64ShrS => {
+                    self.binop(|left: i64, right: i64| left.wrapping_shr(right as u32))?
+                }
+                Instruction::I64ShrU => self.binop(|left: i64, right: i64| {
+                    (left as u64).wrapping_shr(right as u32) as i64
+                })?,
+                Instruction::I64Rotl => {
+                    self.binop(|left: i64, right: i64| left.rotate_left(right as u32))?
+                }
+                Instruction::I64Rotr => {
+                    self.binop(|left: i64, right: i64| left.rotate_right(right as u32))?
+                }
+                Instruction::F32Abs => self.unop(f32::abs)?,
+                Instruction::F32Neg => self.unop(|val: f32| -val)?,
+                Instruction::F32Ceil => self.unop(f32::ceil)?,
+                Instruction::F32Floor => self.unop(f32::floor)?,
+                Instruction::F32Trunc => self.unop(f32::trunc)?,
+                Instruction::F32Nearest => self.unop(f32::round_ties_even)?,
+                Instruction::F32Sqrt => self.unop(f32::sqrt)?,
+                Instruction::F32Add => self.binop(|left: f32, right: f32| left + right)?,
+                Instruction::F32Sub => self.binop(|left: f32, right: f32| left - right)?,
+                Instruction::F32Mul => self.binop(|left: f32, right: f32| left * right)?,
+                Instruction::F32Div => self.binop(|left: f32, right: f32| left / right)?,
+                Instruction::F32Min => self.binop(f32::wasm_min)?,
+                Instruction::F32Max => self.binop(f32::wasm_max)?,
+                Instruction::F32Copysign => self.binop(f32::copysign)?,
+                Instruction::F64Abs => self.unop(f64::abs)?,
+                Instruction::F64Neg => self.unop(|val: f64| -val)?,
+                Instruction::F64Ceil => self.unop(f64::ceil)?,
+                Instruction::F64Floor => self.unop(f64::floor)?,
+                Instruction::F64Trunc => self.unop(f64::trunc)?,
+                Instruction::F64Nearest => self.unop(f64::round_ties_even)?,
+                Instruction::F64Sqrt => self.unop(f64::sqrt)?,
+                Instruction::F64Add => self.binop(|left: f64, right: f64| left + right)?,
+                Instruction::F64Sub => self.binop(|left: f64, right: f64| left - right)?,
+                Instruction::F64Mul => self.binop(|left: f64, right: f64| left * right)?,
+                Instruction::F64Div => self.binop(|left: f64, right: f64| left / right)?,
+                Instruction::F64Min => self.binop(f64::wasm_min)?,
+                Instruction::F64Max => self.binop(f64::wasm_max)?,
+                Instruction::F64Copysign => self.binop(f64::copysign)?,
+                Instruction::I32WrapI64 => self.unop(|val: i64| val as i32)?,
+                Instruction::I32TruncF32S => {
+                    self.try_unop(|val: f32| numeric::trunc_i32(val.into()))?
+                }
+                Instruction::I32TruncF32U => {
+                    self.try_unop(|val: f32| Ok(numeric::trunc_u32(val.into())? as i32))?
+                }
+                Instruction::I32TruncF64S => self.try_unop(numeric::trunc_i32)?,
+                Instruction::I32TruncF64U => {
+                    self.try_unop(|val: f64| Ok(numeric::trunc_u32(val)? as i32))?
+                }
+                Instruction::I64ExtendI32S => self.unop(|val: i32| val as i64)?,
+                Instruction::I64ExtendI32U => self.unop(|val: i32| val as u32 as i64)?,
+                Instruction::I64TruncF32S => {
+                    self.try_unop(|val: f32| numeric::trunc_i64(val.into()))?
+                }
+                Instruction::I64TruncF32U => {
+                    self.try_unop(|val: f32| Ok(numeric::trunc_u64(val.into())? as i64))?
+                }
+                Instruction::I64TruncF64S => self.try_unop(numeric::trunc_i64)?,
+                Instruction::I64TruncF64U => {
+                    self.try_unop(|val: f64| Ok(numeric::trunc_u64(val)? as i64))?
+                }
+                Instruction::F32ConvertI32S => self.unop(|val: i32| val as f32)?,
+                Instruction::F32ConvertI32U => self.unop(|val: i32| val as u32 as f32)?,
+                Instruction::F32ConvertI64S => self.unop(|val: i64| val as f32)?,
+                Instruction::F32ConvertI64U => self.unop(|val: i64| val as u64 as f32)?,
+                Instruction::F32DemoteF64 => self.unop(|val: f64| val as f32)?,
+                Instruction::F64ConvertI32S => self.unop(|val: i32| val as f64)?,
+                Instruction::F64ConvertI32U => self.unop(|val: i32| val as u32 as f64)?,
+                Instruction::F64ConvertI64S => self.unop(|val: i64| val as f64)?,
+                Instruction::F64ConvertI64U => self.unop(|val: i64| val as u64 as f64)?,
+                Instruction::F64PromoteF32 => self.unop(|val: f32| val as f64)?,
+                Instruction::I32ReinterpretF32 => self.unop(|val: f32| val.to_bits() as i32)?,
+                Instruction::I64ReinterpretF64 => self.unop(|val: f64| val.to_bits() as i64)?,
+                Instruction::F32ReinterpretI32 => {
+                    self.unop(|val: i32| f32::from_bits(val as u32))?
+                }
+                Instruction::F64ReinterpretI64 => {
+                    self.unop(|val: i64| f64::from_bits(val as u64))?
+                }
+                Instruction::I32Extend8S => self.unop(|val: i32| val as i8 as i32)?,
+                Instruction::I32Extend16S => self.unop(|val: i32| val as i16 as i32)?,
+                Instruction::I64Extend8S => self.unop(|val: i64| val as i8 as i64)?,
+                Instruction::I64Extend16S => self.unop(|val: i64| val as i16 as i64)?,
+                Instruction::I64Extend32S => self.unop(|val: i64| val as i32 as i64)?,
+                // Rust's float to integer casts saturate and map NaN to 0.
+                Instruction::I32TruncSatF32S => self.unop(|val: f32| val as i32)?,
+                Instruction::I32TruncSatF32U => self.unop(|val: f32| val as u32 as i32)?,
+                Instruction::I32TruncSatF64S => self.unop(|val: f64| val as i32)?,
+                Instruction::I32TruncSatF64U => self.unop(|val: f64| val as u32 as i32)?,
+                Instruction::I64TruncSatF32S => self.unop(|val: f32| val as i64)?,
+                Instruction::I64TruncSatF32U => self.unop(|val: f32| val as u64 as i64)?,
+                Instruction::I64TruncSatF64S => self.unop(|val: f64| val as i64)?,
+                Instruction::I64TruncSatF64U => self.unop(|val: f64| val as u64 as i64)?,
+                Instruction::I32Load(memarg) => self.load_op(memarg, |val: i32| val)?,
+                Instruction::I64Load(memarg) => self.load_op(memarg, |val: i64| val)?,
+                Instruction::F32Load(memarg) => self.load_op(memarg, |val: f32| val)?,
+                Instruction::F64Load(memarg) => self.load_op(memarg, |val: f64| val)?,
+                Instruction::I32Load8S(memarg) => self.load_op(memarg, |val: i8| val as i32)?,
+                Instruction::I32Load8U(memarg) => self.load_op(memarg, |val: u8| val as i32)?,
+                Instruction::I32Load16S(memarg) => self.load_op(memarg, |val: i16| val as i32)?,
+                Instruction::I32Load16U(memarg) => self.load_op(memarg, |val: u16| val as i32)?,
+                Instruction::I64Load8S(memarg) => self.load_op(memarg, |val: i8| val as i64)?,
+                Instruction::I64Load8U(memarg) => self.load_op(memarg, |val: u8| val as i64)?,
+                Instruction::I64Load16S(memarg) => self.load_op(memarg, |val: i16| val as i64)?,
+                Instruction::I64Load16U(memarg) => self.load_op(memarg, |val: u16| val as i64)?,
+                Instruction::I64Load32S(memarg) => self.load_op(memarg, |val: i32| val as i64)?,
+                Instruction::I64Load32U(memarg) => self.load_op(memarg, |val: u32| val as i64)?,
+                Instruction::I32Store(memarg) => self.store_op(memarg, |val: i32| val)?,
+                Instruction::I64Store(memarg) => self.store_op(memarg, |val: i64| val)?,
+                Instruction::F32Store(memarg) => self.store_op(memarg, |val: f32| val)?,
+                Instruction::F64Store(memarg) => self.store_op(memarg, |val: f64| val)?,
+                Instruction::I32Store8(memarg) => self.store_op(memarg, |val: i32| val as u8)?,
+                Instruction::I32Store16(memarg) => self.store_op(memarg, |val: i32| val as u16)?,
+                Instruction::I64Store8(memarg) => self.store_op(memarg, |val: i64| val as u8)?,
+                Instruction::I64Store16(memarg) => self.store_op(memarg, |val: i64| val as u16)?,
+                Instruction::I64Store32(memarg) => self.store_op(memarg, |val: i64| val as u32)?,
+                Instruction::MemorySize => {
+                    let size = self.memory().size();
+                    self.push(Value::I32(size as i32));
+                }
+                Instruction::MemoryGrow => {
+                    let delta = self.pop_as::<i32>()? as u32;
+                    let size = self.memory().grow(delta).map_or(-1, |size| size as i32);
+                    self.push(Value::I32(size));
+                }
+                Instruction::MemoryInit(data) => {
+                    let (d, s, n) = self.pop_bulk_args()?;
+                    self.memory_init(self.current_instance(), *data, d, s, n)?;
+                }
+                Instruction::DataDrop(data) => {
+                    let instance = self.current_instance();
+                    if let Some(dropped) = self.instances[instance].dropped_data.get_mut(*data) {
+                        *dropped = true;
+                    }
+                }
+                Instruction::MemoryCopy => {
+                    let (d, s, n) = self.pop_bulk_args()?;
+                    let memory = self.memory().data_mut();
+                    match (range(s, n, memory.len()), range(d, n, memory.len())) {
+                        (Some(src), Some(dst)) => memory.copy_within(src, dst.start),
+                        _ => return Err(Trap::MemoryOutOfBounds),
+                    }
+                }
+                Instruction::MemoryFill => {
+                    let (d, val, n) = self.pop_bulk_args()?;
+                    let memory = self.memory().data_mut();
+                    match range(d, n, memory.len()) {
+                        Some(dst) => memory[dst].fill(val as u8),
+                        None => return Err(Trap::MemoryOutOfBounds),
+                    }
+                }
+                Instruction::TableInit(elem, table) => {
+                    let (d, s, n) = self.pop_bulk_args()?;
+                    self.table_init(self.current_instance(), *elem, *table, d, s, n)?;
+                }
+                Instruction::ElemDrop(elem) => {
+                    let instance = self.current_instance();
+                    if let Some(funcs) = self.instances[instance].elems.get_mut(*elem) {
+                        funcs.clear();
+                    }
+                }
+                Instruction::TableCopy(dst, src) => {
+                    let (d, s, n) = self.pop_bulk_args()?;
+                    self.table_copy(*dst, *src, d, s, n)?;
+                }
+                Instruction::TableGet(table) => {
+                    let index = self.pop_as::<i32>()? as u32 as usize;
+                    let table = self.table(*table)?;
+                    let addr = *table.elems.get(index).ok_or(Trap::TableOutOfBounds)?;
+                    let val = Value::reference(table.ty, addr);
                     self.push(val);
                 }
-                Instruction::Store => {
-                    let val = self.pop().unwrap();
-                    let addr = self.pop().unwrap();
-                    self.store(addr as usize, val)
+                Instruction::TableSet(table) => {
+                    let addr = self.pop_ref()?;
+                    let index = self.pop_as::<i32>()? as u32 as usize;
+                    let elem = self.table(*table)?.elems.get_mut(index);
+                    *elem.ok_or(Trap::TableOutOfBounds)? = addr;
                 }
-                Instruction::LocalGet(index) => {
-                    if let Some(locals) = locals {
-                        self.push(*locals[&index]);
-                    } else {
-                        println!("No locals supplied");
+                Instruction::TableSize(table) => {
+                    let size = self.table(*table)?.elems.len();
+                    self.push(Value::I32(size as i32));
+                }
+                Instruction::TableGrow(table) => {
+                    let delta = self.pop_as::<i32>()? as u32;
+                    let init = self.pop_ref()?;
+                    let size = self.table(*table)?.grow(delta, init);
+                    self.push(Value::I32(size.map_or(-1, |size| size as i32)));
+                }
+                Instruction::TableFill(table) => {
+                    let n = self.pop_as::<i32>()? as u32;
+                    let addr = self.pop_ref()?;
+                    let d = self.pop_as::<i32>()? as u32;
+                    let elems = &mut self.table(*table)?.elems;
+                    match range(d, n, elems.len()) {
+                        Some(dst) => elems[dst].fill(addr),
+                        None => return Err(Trap::TableOutOfBounds),
                     }
                 }
-                Instruction::LocalSet(_index) => {
-                    // if let Some(local_vars) = &mut locals {
-                    // local_vars[&index] = &self.pop().unwrap();
-                    // } else {
-                    // println!("No locals supplied");
-                    // }
+                Instruction::LocalGet(index) => {
+                    let val = *self.local(*index)?;
+                    self.push(val);
+                }
+                Instruction::LocalSet(index) => {
+                    let val = self.pop_value()?;
+                    *self.local(*index)? = val;
+                }
+                Instruction::LocalTee(index) => {
+                    let val = self.pop_value()?;
+                    *self.local(*index)? = val;
+                    self.push(val);
+                }
+                Instruction::GlobalGet(index) => {
+                    let addr = self.global_addr(*index)?;
+                    self.push(self.globals[addr].val);
+                }
+                Instruction::GlobalSet(index) => {
+                    let val = self.pop_value()?;
+                    let addr = self.global_addr(*index)?;
+                    self.globals[addr].val = val;
                 }
                 Instruction::CallFunc(index) => {
-                    let fargs = (0..self.functions[*index].nparams)
-                        .map(|_| self.pop().unwrap())
-                        .rev()
-                        .collect();
-
-                    let result = self.call(&self.functions[*index], fargs);
-                    if self.functions[*index].returns {
-                        self.push(result.unwrap());
+                    let caller = self.current_instance();
+                    let addr = self.func_addr(*index)?;
+                    if self.call_func(caller, addr, pc + 1)? {
+                        (module, func_index) = self.running();
+                        pc = 0;
+                        continue;
+                    }
+                }
+                Instruction::CallIndirect(ty, table) => {
+                    let caller = self.current_instance();
+                    let addr = self.indirect_callee(*ty, *table)?;
+                    if self.call_func(caller, addr, pc + 1)? {
+                        (module, func_index) = self.running();
+                        pc = 0;
+                        continue;
                     }
                 }
             }
+            pc += 1;
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    #[test]
-    fn example() {
-        let code = vec![
-            Instruction::Const(2.0),
-            Instruction::Const(3.0),
-            Instruction::Const(0.1),
-            Instruction::Mul,
-            Instruction::Add,
-        ];
+/// An instantiated module, whose definitions live in a store it may share
+/// with instances it imports from or exports to.
+pub struct Instance {
+    store: Rc<RefCell<Store>>,
+    index: usize,
+}
 
-        let mut m = Machine::new(vec![], 100);
-        m.execute(&code, &mut None);
-        println!("Result: {}", m.pop().unwrap());
+impl Instance {
+    /// Creates an instance for a module without imports.
+    pub fn new(module: impl Into<Rc<Module>>) -> Result<Self, Error> {
+        Instance::with_imports(module, &Imports::default())
     }
-    #[test]
-    fn example_variables() {
-        let x_addr = 22.0;
-        let v_addr = 42.0;
 
-        let code = vec![
-            Instruction::Const(x_addr),
-            Instruction::Const(x_addr),
-            Instruction::Load,
-            Instruction::Const(v_addr),
-            Instruction::Load,
-            Instruction::Const(0.1),
-            Instruction::Mul,
-            Instruction::Add,
-            Instruction::Store,
-        ];
+    /// Creates an instance running the module's functions, in a store of its
+    /// own, with its imports resolved from `imports`.
+    pub fn with_imports(module: impl Into<Rc<Module>>, imports: &Imports) -> Result<Self, Error> {
+        let module = module.into();
+        let mut store = Store::default();
+        let mut externs = Vec::with_capacity(module.imports.len());
+        for import in &module.imports {
+            match imports.get_func(&import.module, &import.name) {
+                Some(func) => externs.push(Extern::Func(store.alloc_host(func.clone()))),
+                None => {
+                    return Err(Error::UnknownImport {
+                        module: import.module.clone(),
+                        name: import.name.clone(),
+                    })
+                }
+            }
+        }
+        let index = store.instantiate(module, &externs)?;
+        Ok(Instance {
+            store: Rc::new(RefCell::new(store)),
+            index,
+        })
+    }
 
-        let mut m = Machine::new(vec![], 65536);
-        m.store(x_addr as usize, 2.0);
-        m.store(v_addr as usize, 3.0);
-        m.execute(&code, &mut None);
-        println!("Result: {}", m.load(x_addr as usize));
+    pub(crate) fn from_store(store: Rc<RefCell<Store>>, index: usize) -> Self {
+        Instance { store, index }
     }
-    #[test]
+
+    /// Whether the instance lives in `store`.
+    pub(crate) fn in_store(&self, store: &Rc<RefCell<Store>>) -> bool {
+        Rc::ptr_eq(&self.store, store)
+    }
+
+    /// The store address of the definition exported as `name`.
+    pub(crate) fn export_extern(&self, name: &str) -> Result<Option<Extern>, Trap> {
+        Ok(match self.export(name)? {
+            Some(desc) => self.store_ref()?.export_extern(self.index, desc),
+            None => None,
+        })
+    }
+
+    /// Names of everything the module exports.
+    pub(crate) fn export_names(&self) -> Result<Vec<String>, Trap> {
+        let store = self.store_ref()?;
+        Ok(store.instances[self.index]
+            .exports
+            .keys()
+            .cloned()
+            .collect())
+    }
+
+    /// Limits how many function calls may be active at once, in this and
+    /// every instance sharing its store; a call beyond that traps with
+    /// `Trap::CallStackExhausted`.
+    pub fn set_max_call_depth(&mut self, depth: usize) -> Result<(), Trap> {
+        self.store_mut()?.max_call_depth = depth;
+        Ok(())
+    }
+
+    /// Current value of the global at `index`.
+    pub fn global(&self, index: usize) -> Result<Option<Value>, Trap> {
+        let store = self.store_ref()?;
+        let globals = &store.instances[self.index].globals;
+        Ok(globals.get(index).map(|&addr| store.globals[addr].val))
+    }
+
+    pub fn memory(&self) -> Result<Ref<'_, Memory>, Trap> {
+        Ok(Ref::map(self.store_ref()?, |store| {
+            &store.memories[store.instances[self.index].memory]
+        }))
+    }
+
+    pub fn memory_mut(&mut self) -> Result<RefMut<'_, Memory>, Trap> {
+        let index = self.index;
+        Ok(RefMut::map(self.store_mut()?, |store| {
+            &mut store.memories[store.instances[index].memory]
+        }))
+    }
+
+    /// What the module exports under `name`.
+    pub fn export(&self, name: &str) -> Result<Option<ExportDesc>, Trap> {
+        let store = self.store_ref()?;
+        Ok(store.instances[self.index].exports.get(name).copied())
+    }
+
+    /// Current value of the global exported as `name`.
+    pub fn exported_global(&self, name: &str) -> Result<Option<Value>, Trap> {
+        match self.export(name)? {
+            Some(ExportDesc::Global(index)) => self.global(index),
+            _ => Ok(None),
+        }
+    }
+
+    /// The memory exported as `name`.
+    pub fn exported_memory(&self, name: &str) -> Result<Option<Ref<'_, Memory>>, Trap> {
+        match self.export(name)? {
+            Some(ExportDesc::Memory(0)) => self.memory().map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Number of elements in the table exported as `name`.
+    pub fn exported_table_size(&self, name: &str) -> Result<Option<usize>, Trap> {
+        match self.export_extern(name)? {
+            Some(Extern::Table(addr)) => Ok(Some(self.store_ref()?.tables[addr].elems.len())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Calls the function exported as `name`, checking the arguments against
+    /// its parameters.
+    pub fn invoke(&mut self, name: &str, args: &[Value]) -> Result<Vec<Value>, Error> {
+        let index = match self.export(name)? {
+            Some(ExportDesc::Func(index)) => index,
+            Some(_) => return Err(Error::NotAFunction(name.to_string())),
+            None => return Err(Error::UnknownExport(name.to_string())),
+        };
+        let params = self.func_type(index)?.params;
+        if !args.iter().map(Value::ty).eq(params.iter().copied()) {
+            return Err(Error::ArgumentTypeMismatch {
+                expected: params,
+                found: args.iter().map(Value::ty).collect(),
+            });
+        }
+        Ok(self.call(index, args.to_vec())?)
+    }
+
+    /// Type of the function at `index` in the module's function index space.
+    fn func_type(&self, index: usize) -> Result<FuncType, Trap> {
+        let store = self.store_mut()?;
+        let funcs = &store.instances[self.index].funcs;
+        let addr = funcs.get(index).ok_or(Trap::UndefinedFunction(index))?;
+        Ok(store.type_of(*addr)?.clone())
+    }
+
+    /// Calls the function at `index` with `args` and returns its results.
+    pub fn call(&mut self, index: usize, args: Vec<Value>) -> Result<Vec<Value>, Trap> {
+        let mut store = self.store_mut()?;
+        let funcs = &store.instances[self.index].funcs;
+        let addr = *funcs.get(index).ok_or(Trap::UndefinedFunction(index))?;
+        store.call(self.index, addr, args)
+    }
+
+    /// Borrows the store, which fails while a host function called from it
+    /// is running, as the running code has it borrowed mutably.
+    fn store_ref(&self) -> Result<Ref<'_, Store>, Trap> {
+        self.store.try_borrow().map_err(|_| Trap::ReentrantCall)
+    }
+
+    /// Borrows the store to run code in it or change it, which fails while
+    /// a host function called from the same store is running.
+    fn store_mut(&self) -> Result<RefMut<'_, Store>, Trap> {
+        self.store.try_borrow_mut().map_err(|_| Trap::ReentrantCall)
+    }
+
+    pub fn load<T: LittleEndian>(&self, addr: usize) -> Result<T, Trap> {
+        self.memory()?.load(addr)
+    }
+
+    pub fn store<T: LittleEndian>(&mut self, addr: usize, val: T) -> Result<(), Trap> {
+        self.memory_mut()?.store(addr, val)
+    }
+
+    pub fn push(&mut self, item: Value) -> Result<(), Trap> {
+        self.store_mut()?.stack.push(item);
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Result<Option<Value>, Trap> {
+        Ok(self.store_mut()?.stack.pop())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONE_PAGE: Option<Limits> = Some(Limits { min: 1, max: None });
+
+    const F64_ALIGNED: MemArg = MemArg {
+        align: 3,
+        offset: 0,
+    };
+
+    /// A function given by its signature rather than a type index.
+    struct Def {
+        ty: FuncType,
+        locals: Vec<ValType>,
+        code: Vec<Instruction>,
+    }
+
+    fn func(
+        params: Vec<ValType>,
+        result: Option<ValType>,
+        locals: Vec<ValType>,
+        code: Vec<Instruction>,
+    ) -> Def {
+        let results = result.into_iter().collect();
+        Def {
+            ty: FuncType { params, results },
+            locals,
+            code,
+        }
+    }
+
+    /// A module of the given functions, each with a type of its own.
+    fn module(defs: Vec<Def>) -> Module {
+        let mut module = Module::default();
+        for (index, def) in defs.into_iter().enumerate() {
+            module.types.push(def.ty);
+            module
+                .functions
+                .push(Function::new(index, def.locals, def.code));
+        }
+        module
+    }
+
+    fn instance(defs: Vec<Def>, memory: Option<Limits>) -> Instance {
+        let module = Module {
+            memory,
+            ..module(defs)
+        };
+        Instance::new(module).unwrap()
+    }
+
+    /// Wraps `code` in a function without parameters.
+    fn main(result: Option<ValType>, code: Vec<Instruction>) -> Def {
+        func(vec![], result, vec![], code)
+    }
+
+    #[test]
+    fn example() {
+        let code = vec![
+            Instruction::F64Const(2.0),
+            Instruction::F64Const(3.0),
+            Instruction::F64Const(0.1),
+            Instruction::F64Mul,
+            Instruction::F64Add,
+        ];
+
+        let mut m = instance(vec![main(Some(ValType::F64), code)], ONE_PAGE);
+        assert_eq!(m.call(0, vec![]), Ok(vec![Value::F64(2.3)]));
+    }
+    #[test]
+    fn example_variables() {
+        let x_addr = 22;
+        let v_addr = 42;
+
+        let code = vec![
+            Instruction::I32Const(x_addr),
+            Instruction::I32Const(x_addr),
+            Instruction::F64Load(F64_ALIGNED),
+            Instruction::I32Const(v_addr),
+            Instruction::F64Load(F64_ALIGNED),
+            Instruction::F64Const(0.1),
+            Instruction::F64Mul,
+            Instruction::F64Add,
+            Instruction::F64Store(F64_ALIGNED),
+        ];
+
+        let mut m = instance(vec![main(None, code)], ONE_PAGE);
+        m.store(x_addr as usize, 2.0).unwrap();
+        m.store(v_addr as usize, 3.0).unwrap();
+        m.call(0, vec![]).unwrap();
+        assert_eq!(m.load::<f64>(x_addr as usize), Ok(2.3));
+    }
+    #[test]
     fn example_functions() {
-        let update_position = Function::new(
-            3,
-            true,
+        let update_position = func(
+            vec![ValType::F64, ValType::F64, ValType::F64],
+            Some(ValType::F64),
+            vec![],
             vec![
                 Instruction::LocalGet(0), // x
                 Instruction::LocalGet(1), // v
                 Instruction::LocalGet(2), // dt
-                Instruction::Mul,
-                Instruction::Add,
+                Instruction::F64Mul,
+                Instruction::F64Add,
             ],
         );
 
-        let functions = vec![update_position];
-
-        let x_addr = 22.0;
-        let v_addr = 42.0;
+        let x_addr = 22;
+        let v_addr = 42;
 
         let code = vec![
-            Instruction::Const(x_addr),
-            Instruction::Const(x_addr),
-            Instruction::Load,
-            Instruction::Const(v_addr),
-            Instruction::Load,
-            Instruction::Const(0.1),
+            Instruction::I32Const(x_addr),
+            Instruction::I32Const(x_addr),
+            Instruction::F64Load(F64_ALIGNED),
+            Instruction::I32Const(v_addr),
+            Instruction::F64Load(F64_ALIGNED),
+            Instruction::F64Const(0.1),
             Instruction::CallFunc(0), // update_position
-            Instruction::Store,
+            Instruction::F64Store(F64_ALIGNED),
+        ];
+
+        let functions = vec![update_position, main(None, code)];
+
+        let mut m = instance(functions, ONE_PAGE);
+        m.store(x_addr as usize, 2.0).unwrap();
+        m.store(v_addr as usize, 3.0).unwrap();
+        m.call(1, vec![]).unwrap();
+        assert_eq!(m.load::<f64>(x_addr as usize), Ok(2.3));
+    }
+    #[test]
+    fn integer_arithmetic_wraps() {
+        let i32_code = vec![
+            Instruction::I32Const(i32::MAX),
+            Instruction::I32Const(1),
+            Instruction::I32Add,
+        ];
+        let i64_code = vec![
+            Instruction::I64Const(i64::MAX),
+            Instruction::I64Const(2),
+            Instruction::I64Mul,
+        ];
+        let functions = vec![
+            main(Some(ValType::I32), i32_code),
+            main(Some(ValType::I64), i64_code),
+        ];
+
+        let mut m = instance(functions, None);
+        assert_eq!(m.call(0, vec![]), Ok(vec![Value::I32(i32::MIN)]));
+        assert_eq!(m.call(1, vec![]), Ok(vec![Value::I64(-2)]));
+    }
+    #[test]
+    fn integer_instructions() {
+        fn i32_binop(op: Instruction, left: i32, right: i32) -> Result<Vec<Value>, Trap> {
+            let code = vec![
+                Instruction::I32Const(left),
+                Instruction::I32Const(right),
+                op,
+            ];
+            instance(vec![main(Some(ValType::I32), code)], None).call(0, vec![])
+        }
+        fn i64_binop(op: Instruction, left: i64, right: i64) -> Result<Vec<Value>, Trap> {
+            let ty = match op {
+                Instruction::I64Eq | Instruction::I64LtU | Instruction::I64GeS => ValType::I32,
+                _ => ValType::I64,
+            };
+            let code = vec![
+                Instruction::I64Const(left),
+                Instruction::I64Const(right),
+                op,
+            ];
+            instance(vec![main(Some(ty), code)], None).call(0, vec![])
+        }
+        let i32 = |val| Ok(vec![Value::I32(val)]);
+        let i64 = |val| Ok(vec![Value::I64(val)]);
+
+        assert_eq!(i32_binop(Instruction::I32Sub, i32::MIN, 1), i32(i32::MAX));
+        assert_eq!(i32_binop(Instruction::I32DivS, -7, 2), i32(-3));
+        assert_eq!(i32_binop(Instruction::I32DivU, -1, 2), i32(i32::MAX));
+        assert_eq!(i32_binop(Instruction::I32RemS, -7, 2), i32(-1));
+        assert_eq!(i32_binop(Instruction::I32RemS, i32::MIN, -1), i32(0));
+        assert_eq!(i32_binop(Instruction::I32RemU, -1, 10), i32(5));
+        assert_eq!(
+            i32_binop(Instruction::I32DivS, i32::MIN, -1),
+            Err(Trap::IntegerOverflow)
+        );
+        assert_eq!(
+            i32_binop(Instruction::I32DivU, 1, 0),
+            Err(Trap::IntegerDivideByZero)
+        );
+        assert_eq!(
+            i32_binop(Instruction::I32RemS, 1, 0),
+            Err(Trap::IntegerDivideByZero)
+        );
+        assert_eq!(i32_binop(Instruction::I32Shl, 1, 33), i32(2));
+        assert_eq!(i32_binop(Instruction::I32ShrS, -8, 1), i32(-4));
+        assert_eq!(i32_binop(Instruction::I32ShrU, -8, 29), i32(7));
+        assert_eq!(
+            i32_binop(Instruction::I32Rotl, 0x8000_0001u32 as i32, 33),
+            i32(3)
+        );
+        assert_eq!(i32_binop(Instruction::I32Rotr, 3, -1), i32(6));
+        assert_eq!(i32_binop(Instruction::I32Xor, 0b1100, 0b1010), i32(0b0110));
+        assert_eq!(i32_binop(Instruction::I32LtS, -1, 0), i32(1));
+        assert_eq!(i32_binop(Instruction::I32LtU, -1, 0), i32(0));
+        assert_eq!(i32_binop(Instruction::I32GeU, -1, 0), i32(1));
+        assert_eq!(i32_binop(Instruction::I32Ne, 3, 3), i32(0));
+
+        assert_eq!(i64_binop(Instruction::I64Mul, i64::MAX, 2), i64(-2));
+        assert_eq!(
+            i64_binop(Instruction::I64DivS, i64::MIN, -1),
+            Err(Trap::IntegerOverflow)
+        );
+        assert_eq!(
+            i64_binop(Instruction::I64RemU, 1, 0),
+            Err(Trap::IntegerDivideByZero)
+        );
+        assert_eq!(i64_binop(Instruction::I64ShrU, -1, 127), i64(1));
+        assert_eq!(i64_binop(Instruction::I64Rotr, 1, 65), i64(i64::MIN));
+        assert_eq!(i64_binop(Instruction::I64LtU, 1, -1), i32(1));
+        assert_eq!(i64_binop(Instruction::I64GeS, 1, -1), i32(1));
+        assert_eq!(i64_binop(Instruction::I64Eq, 2, 2), i32(1));
+
+        let code = vec![
+            Instruction::I32Const(0x00f0),
+            Instruction::I32Clz,
+            Instruction::I32Const(0x00f0),
+            Instruction::I32Ctz,
+            Instruction::I32Add,
+            Instruction::I64Const(-1),
+            Instruction::I64Popcnt,
+            Instruction::I64Eqz,
+            Instruction::I32Add,
+        ];
+        let mut m = instance(vec![main(Some(ValType::I32), code)], None);
+        assert_eq!(m.call(0, vec![]), i32(24 + 4));
+    }
+    #[test]
+    fn float_instructions() {
+        fn eval(result: ValType, code: Vec<Instruction>) -> Value {
+            let mut m = instance(vec![main(Some(result), code)], None);
+            m.call(0, vec![]).unwrap()[0]
+        }
+        fn f32_bits(op: Instruction, args: &[f32]) -> u32 {
+            let mut code: Vec<_> = args.iter().map(|arg| Instruction::F32Const(*arg)).collect();
+            code.push(op);
+            match eval(ValType::F32, code) {
+                Value::F32(val) => val.to_bits(),
+                other => panic!("unexpected result {}", other),
+            }
+        }
+        fn f64_bits(op: Instruction, args: &[f64]) -> u64 {
+            let mut code: Vec<_> = args.iter().map(|arg| Instruction::F64Const(*arg)).collect();
+            code.push(op);
+            match eval(ValType::F64, code) {
+                Value::F64(val) => val.to_bits(),
+                other => panic!("unexpected result {}", other),
+            }
+        }
+        let nan = f32::from_bits(0x7fa0_0001);
+
+        assert_eq!(
+            f32_bits(Instruction::F32Min, &[0.0, -0.0]),
+            (-0.0f32).to_bits()
+        );
+        assert_eq!(f32_bits(Instruction::F32Max, &[-0.0, 0.0]), 0);
+        assert!(f32::from_bits(f32_bits(Instruction::F32Min, &[1.0, nan])).is_nan());
+        assert!(f32::from_bits(f32_bits(Instruction::F32Max, &[nan, 1.0])).is_nan());
+        assert_eq!(f32_bits(Instruction::F32Neg, &[nan]), 0xffa0_0001);
+        assert_eq!(f32_bits(Instruction::F32Abs, &[-nan]), 0x7fa0_0001);
+        assert_eq!(
+            f32_bits(Instruction::F32Copysign, &[nan, -1.0]),
+            0xffa0_0001
+        );
+        assert_eq!(f32_bits(Instruction::F32Nearest, &[2.5]), 2.0f32.to_bits());
+        assert_eq!(
+            f32_bits(Instruction::F32Nearest, &[-0.5]),
+            (-0.0f32).to_bits()
+        );
+        assert_eq!(
+            f32_bits(Instruction::F32Trunc, &[-1.5]),
+            (-1.0f32).to_bits()
+        );
+        assert_eq!(
+            f32_bits(Instruction::F32Div, &[1.0, -0.0]),
+            f32::NEG_INFINITY.to_bits()
+        );
+
+        assert_eq!(
+            f64_bits(Instruction::F64Min, &[-0.0, 0.0]),
+            (-0.0f64).to_bits()
+        );
+        assert_eq!(f64_bits(Instruction::F64Max, &[0.0, -0.0]), 0);
+        assert_eq!(
+            f64_bits(Instruction::F64Max, &[-1.0, -2.0]),
+            (-1.0f64).to_bits()
+        );
+        assert_eq!(f64_bits(Instruction::F64Nearest, &[3.5]), 4.0f64.to_bits());
+        assert_eq!(f64_bits(Instruction::F64Ceil, &[-0.5]), (-0.0f64).to_bits());
+        assert_eq!(
+            f64_bits(Instruction::F64Floor, &[-0.5]),
+            (-1.0f64).to_bits()
+        );
+        assert_eq!(f64_bits(Instruction::F64Sqrt, &[16.0]), 4.0f64.to_bits());
+        assert_eq!(f64_bits(Instruction::F64Sub, &[1.0, 1.0]), 0);
+        assert!(f64::from_bits(f64_bits(Instruction::F64Sqrt, &[-1.0])).is_nan());
+
+        let code = vec![
+            Instruction::F32Const(nan),
+            Instruction::F32Const(nan),
+            Instruction::F32Ne,
+            Instruction::F64Const(-0.0),
+            Instruction::F64Const(0.0),
+            Instruction::F64Eq,
+            Instruction::I32Add,
+            Instruction::F64Const(f64::NAN),
+            Instruction::F64Const(1.0),
+            Instruction::F64Lt,
+            Instruction::I32Add,
+        ];
+        assert_eq!(eval(ValType::I32, code), Value::I32(2));
+    }
+    #[test]
+    fn conversion_instructions() {
+        fn convert(arg: Instruction, op: Instruction, result: ValType) -> Result<Vec<Value>, Trap> {
+            let code = vec![arg, op];
+            instance(vec![main(Some(result), code)], None).call(0, vec![])
+        }
+        use Instruction::*;
+        let i32 = |val| Ok(vec![Value::I32(val)]);
+        let i64 = |val| Ok(vec![Value::I64(val)]);
+
+        assert_eq!(
+            convert(I64Const(0x1_0000_0005), I32WrapI64, ValType::I32),
+            i32(5)
+        );
+        assert_eq!(convert(I32Const(-1), I64ExtendI32S, ValType::I64), i64(-1));
+        assert_eq!(
+            convert(I32Const(-1), I64ExtendI32U, ValType::I64),
+            i64(0xffff_ffff)
+        );
+        assert_eq!(
+            convert(I32Const(0x80), I32Extend8S, ValType::I32),
+            i32(-128)
+        );
+        assert_eq!(
+            convert(I64Const(0xffff_8000), I64Extend16S, ValType::I64),
+            i64(-32768)
+        );
+        assert_eq!(
+            convert(I64Const(0x8000_0000), I64Extend32S, ValType::I64),
+            i64(i32::MIN as i64)
+        );
+
+        assert_eq!(convert(F32Const(-1.9), I32TruncF32S, ValType::I32), i32(-1));
+        assert_eq!(convert(F64Const(-0.9), I32TruncF64U, ValType::I32), i32(0));
+        assert_eq!(
+            convert(F64Const(4294967295.9), I32TruncF64U, ValType::I32),
+            i32(-1)
+        );
+        assert_eq!(
+            convert(F64Const(-2147483648.9), I32TruncF64S, ValType::I32),
+            i32(i32::MIN)
+        );
+        assert_eq!(
+            convert(F64Const(2147483648.0), I32TruncF64S, ValType::I32),
+            Err(Trap::IntegerOverflow)
+        );
+        assert_eq!(
+            convert(F32Const(-1.0), I64TruncF32U, ValType::I64),
+            Err(Trap::IntegerOverflow)
+        );
+        assert_eq!(
+            convert(F64Const(9223372036854775808.0), I64TruncF64S, ValType::I64),
+            Err(Trap::IntegerOverflow)
+        );
+        assert_eq!(
+            convert(F64Const(-9223372036854775808.0), I64TruncF64S, ValType::I64),
+            i64(i64::MIN)
+        );
+        assert_eq!(
+            convert(F32Const(f32::NAN), I64TruncF32S, ValType::I64),
+            Err(Trap::InvalidConversionToInteger)
+        );
+
+        assert_eq!(
+            convert(F32Const(f32::NAN), I32TruncSatF32S, ValType::I32),
+            i32(0)
+        );
+        assert_eq!(
+            convert(F64Const(1e10), I32TruncSatF64S, ValType::I32),
+            i32(i32::MAX)
+        );
+        assert_eq!(
+            convert(F64Const(-1e10), I32TruncSatF64U, ValType::I32),
+            i32(0)
+        );
+        assert_eq!(
+            convert(F32Const(f32::INFINITY), I64TruncSatF32U, ValType::I64),
+            i64(-1)
+        );
+        assert_eq!(
+            convert(F64Const(-1e30), I64TruncSatF64S, ValType::I64),
+            i64(i64::MIN)
+        );
+
+        assert_eq!(
+            convert(I64Const(-1), F32ConvertI64U, ValType::F32),
+            Ok(vec![Value::F32(18446744073709551616.0)])
+        );
+        assert_eq!(
+            convert(I32Const(16777217), F32ConvertI32S, ValType::F32),
+            Ok(vec![Value::F32(16777216.0)])
+        );
+        assert_eq!(
+            convert(I32Const(-1), F64ConvertI32U, ValType::F64),
+            Ok(vec![Value::F64(4294967295.0)])
+        );
+        assert_eq!(
+            convert(F64Const(1e300), F32DemoteF64, ValType::F32),
+            Ok(vec![Value::F32(f32::INFINITY)])
+        );
+        assert_eq!(
+            convert(F32Const(1.5), F64PromoteF32, ValType::F64),
+            Ok(vec![Value::F64(1.5)])
+        );
+        assert_eq!(
+            convert(F32Const(-0.0), I32ReinterpretF32, ValType::I32),
+            i32(i32::MIN)
+        );
+        match convert(
+            I64Const(0x7ff0_0000_0000_0001),
+            F64ReinterpretI64,
+            ValType::F64,
+        )
+        .as_deref()
+        {
+            Ok([Value::F64(val)]) => assert_eq!(val.to_bits(), 0x7ff0_0000_0000_0001),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+    #[test]
+    fn memory_access_widths() {
+        let at = |offset| MemArg { align: 0, offset };
+        let code = vec![
+            Instruction::I32Const(0),
+            Instruction::I64Const(0x1122_3344_8899_aabb),
+            Instruction::I64Store(at(0)),
+            Instruction::I32Const(8),
+            Instruction::I32Const(0x1ff),
+            Instruction::I32Store8(at(4)),
+            // Sign and zero extension of the same bytes.
+            Instruction::I32Const(0),
+            Instruction::I32Load16S(at(0)),
+            Instruction::I32Const(0xaabb - 0x10000),
+            Instruction::I32Eq,
+            Instruction::I32Const(0),
+            Instruction::I64Load32U(at(0)),
+            Instruction::I64Const(0x8899_aabb),
+            Instruction::I64Eq,
+            Instruction::I32Add,
+            Instruction::I32Const(4),
+            Instruction::I64Load8S(at(8)),
+            Instruction::I64Const(-1),
+            Instruction::I64Eq,
+            Instruction::I32Add,
+            Instruction::I32Const(2),
+            Instruction::I32Load(at(2)),
+            Instruction::I32Const(0x1122_3344),
+            Instruction::I32Eq,
+            Instruction::I32Add,
+        ];
+        let mut m = instance(vec![main(Some(ValType::I32), code)], ONE_PAGE);
+        assert_eq!(m.call(0, vec![]), Ok(vec![Value::I32(4)]));
+        assert_eq!(m.load::<u8>(12), Ok(0xff));
+        assert_eq!(m.load::<u16>(6), Ok(0x1122));
+
+        // The effective address is computed without wrapping around.
+        let code = vec![Instruction::I32Const(-1), Instruction::I32Load8U(at(1))];
+        let mut m = instance(vec![main(Some(ValType::I32), code)], ONE_PAGE);
+        assert_eq!(m.call(0, vec![]), Err(Trap::MemoryOutOfBounds));
+
+        let code = vec![
+            Instruction::I32Const(PAGE_SIZE as i32 - 4),
+            Instruction::F32Const(1.0),
+            Instruction::F32Store(at(1)),
+        ];
+        let mut m = instance(vec![main(None, code)], ONE_PAGE);
+        assert_eq!(m.call(0, vec![]), Err(Trap::MemoryOutOfBounds));
+        assert_eq!(m.load::<u32>(PAGE_SIZE - 4), Ok(0));
+    }
+    #[test]
+    fn memory_grows_in_pages() {
+        // grow(delta) stores a marker at the start of the new pages, if any.
+        let grow = func(
+            vec![ValType::I32],
+            Some(ValType::I32),
+            vec![ValType::I32],
+            vec![
+                Instruction::LocalGet(0),
+                Instruction::MemoryGrow,
+                Instruction::LocalTee(1),
+                Instruction::I32Const(-1),
+                Instruction::I32Ne,
+                Instruction::If(BlockType::Empty),
+                Instruction::LocalGet(1),
+                Instruction::I32Const(PAGE_SIZE as i32),
+                Instruction::I32Mul,
+                Instruction::I32Const(7),
+                Instruction::I32Store8(MemArg {
+                    align: 0,
+                    offset: 0,
+                }),
+                Instruction::End,
+                Instruction::LocalGet(1),
+            ],
+        );
+        let size = main(Some(ValType::I32), vec![Instruction::MemorySize]);
+        let limits = Limits {
+            min: 1,
+            max: Some(3),
+        };
+        let mut m = instance(vec![grow, size], Some(limits));
+        assert_eq!(m.call(1, vec![]), Ok(vec![Value::I32(1)]));
+        assert_eq!(m.call(0, vec![Value::I32(2)]), Ok(vec![Value::I32(1)]));
+        assert_eq!(m.call(0, vec![Value::I32(1)]), Ok(vec![Value::I32(-1)]));
+        assert_eq!(m.call(0, vec![Value::I32(-1)]), Ok(vec![Value::I32(-1)]));
+        assert_eq!(m.call(1, vec![]), Ok(vec![Value::I32(3)]));
+        assert_eq!(m.memory().unwrap().data().len(), 3 * PAGE_SIZE);
+        assert_eq!(m.load::<u8>(PAGE_SIZE), Ok(7));
+        assert_eq!(m.load::<u8>(2 * PAGE_SIZE), Ok(0));
+
+        // Without a maximum, memory can grow up to 4GiB.
+        let mut memory = Memory::new(Limits { min: 0, max: None }).unwrap();
+        assert_eq!(memory.grow(0), Some(0));
+        assert_eq!(memory.grow(MAX_PAGES + 1), None);
+        assert_eq!(memory.grow(2), Some(0));
+        assert_eq!(memory.size(), 2);
+        let too_large = Limits {
+            min: MAX_PAGES + 1,
+            max: None,
+        };
+        assert!(Memory::new(too_large).is_none());
+    }
+    #[test]
+    fn traps_instead_of_panicking() {
+        let functions = vec![
+            main(
+                None,
+                vec![
+                    Instruction::I32Const(PAGE_SIZE as i32 - 4),
+                    Instruction::F64Load(F64_ALIGNED),
+                ],
+            ),
+            main(None, vec![Instruction::I32Add]),
+            main(None, vec![Instruction::Unreachable]),
+            main(None, vec![Instruction::LocalGet(0)]),
+            main(None, vec![Instruction::CallFunc(9)]),
+            main(
+                None,
+                vec![
+                    Instruction::F32Const(1.0),
+                    Instruction::I32Const(1),
+                    Instruction::I32Add,
+                ],
+            ),
+            main(Some(ValType::I32), vec![Instruction::I32Const(7)]),
+        ];
+        let mut m = instance(functions, ONE_PAGE);
+        assert_eq!(m.load::<f64>(PAGE_SIZE - 7), Err(Trap::MemoryOutOfBounds));
+        assert_eq!(m.store(usize::MAX, 1.0), Err(Trap::MemoryOutOfBounds));
+
+        assert_eq!(m.call(0, vec![]), Err(Trap::MemoryOutOfBounds));
+        assert_eq!(m.call(1, vec![]), Err(Trap::StackUnderflow));
+        assert_eq!(m.call(2, vec![]), Err(Trap::Unreachable));
+        assert_eq!(m.call(3, vec![]), Err(Trap::UndefinedLocal(0)));
+        assert_eq!(m.call(4, vec![]), Err(Trap::UndefinedFunction(9)));
+        assert_eq!(m.call(5, vec![]), Err(Trap::TypeMismatch));
+        assert_eq!(m.call(10, vec![]), Err(Trap::UndefinedFunction(10)));
+
+        // The instance is still usable after a trap.
+        assert_eq!(m.call(6, vec![]), Ok(vec![Value::I32(7)]));
+        assert_eq!(m.pop(), Ok(None));
+
+        let untyped = Module {
+            functions: vec![Function::new(0, vec![], vec![])],
+            ..Module::default()
+        };
+        let mut m = Instance::new(untyped).unwrap();
+        assert_eq!(m.call(0, vec![]), Err(Trap::UndefinedType(0)));
+    }
+    #[test]
+    fn rejects_invalid_modules_before_running_them() {
+        let sources = [
+            "(func i32.add)",
+            "(func (local.get 0) drop)",
+            "(func (call 9))",
+            "(func (i32.add (f32.const 1) (i32.const 1)) drop)",
+        ];
+        for src in sources.iter() {
+            match Module::from_text(src) {
+                Err(Error::Invalid(_)) => {}
+                other => panic!("{} was not rejected: {:?}", src, other),
+            }
+        }
+
+        // A body of just `else`, which has no `if` to jump past.
+        let lone_else = b"\0asm\x01\0\0\0\
+            \x01\x04\x01\x60\0\0\
+            \x03\x02\x01\0\
+            \x0a\x05\x01\x03\0\x05\x0b";
+        match Module::from_binary(lone_else) {
+            Err(Error::Invalid(err)) => assert_eq!(err.func, Some(0)),
+            other => panic!("lone else was not rejected: {:?}", other),
+        }
+    }
+    #[test]
+    fn needs_an_extern_for_every_import() {
+        let module = Module::from_text(r#"(import "env" "f" (func))"#).unwrap();
+        let mut store = Store::default();
+        assert_eq!(
+            store.instantiate(Rc::new(module), &[]),
+            Err(Error::ImportCountMismatch {
+                expected: 1,
+                found: 0
+            })
+        );
+    }
+    #[test]
+    fn rejects_tables_too_large_to_allocate() {
+        for src in ["(table 0xffffffff funcref)", "(table 10000001 externref)"].iter() {
+            let module = Module::from_text(src).unwrap();
+            assert_eq!(
+                Instance::new(module).err(),
+                Some(Error::AllocationFailed("table"))
+            );
+        }
+        let module = Module::from_text("(table 0 0xffffffff funcref)").unwrap();
+        assert!(Instance::new(module).is_ok());
+    }
+    #[test]
+    fn blocks_and_branches() {
+        let code = vec![
+            Instruction::Block(BlockType::Value(ValType::I32)),
+            Instruction::I32Const(1),
+            Instruction::I32Const(2),
+            Instruction::Br(0),
+            Instruction::I32Const(3),
+            Instruction::End,
+            Instruction::Loop(BlockType::Empty),
+            Instruction::I32Const(0),
+            Instruction::BrIf(0),
+            Instruction::End,
+            Instruction::I32Const(40),
+            Instruction::I32Const(50),
+            Instruction::I32Const(0),
+            Instruction::Select,
+            Instruction::I32Const(6),
+            Instruction::Drop,
+            Instruction::I32Add,
+        ];
+        let mut m = instance(vec![main(Some(ValType::I32), code)], None);
+        assert_eq!(m.call(0, vec![]), Ok(vec![Value::I32(52)]));
+        assert_eq!(m.pop(), Ok(None));
+    }
+    #[test]
+    fn indirect_calls() {
+        let double = || {
+            func(
+                vec![ValType::I32],
+                Some(ValType::I32),
+                vec![],
+                vec![
+                    Instruction::LocalGet(0),
+                    Instruction::LocalGet(0),
+                    Instruction::I32Add,
+                ],
+            )
+        };
+        let dispatch = || {
+            func(
+                vec![ValType::I32],
+                Some(ValType::I32),
+                vec![],
+                vec![
+                    Instruction::I32Const(21),
+                    Instruction::LocalGet(0),
+                    Instruction::CallIndirect(0, 0),
+                ],
+            )
+        };
+        // The table holds `double` and `main`, and type 0 is `double`'s.
+        let module = |offset| Module {
+            tables: vec![TableType {
+                elem: RefType::Func,
+                limits: Limits { min: 4, max: None },
+            }],
+            elems: vec![Elem {
+                mode: ElemMode::Active {
+                    table: 0,
+                    offset: ConstExpr::Const(Value::I32(offset)),
+                },
+                ty: RefType::Func,
+                init: vec![ConstExpr::RefFunc(0), ConstExpr::RefFunc(2)],
+            }],
+            ..module(vec![double(), dispatch(), main(None, vec![])])
+        };
+        let mut m = Instance::new(module(1)).unwrap();
+        assert_eq!(m.call(1, vec![Value::I32(1)]), Ok(vec![Value::I32(42)]));
+        assert_eq!(
+            m.call(1, vec![Value::I32(0)]),
+            Err(Trap::UninitializedElement)
+        );
+        assert_eq!(
+            m.call(1, vec![Value::I32(2)]),
+            Err(Trap::IndirectCallTypeMismatch)
+        );
+        assert_eq!(m.call(1, vec![Value::I32(4)]), Err(Trap::UndefinedElement));
+        assert_eq!(m.call(1, vec![Value::I32(-1)]), Err(Trap::UndefinedElement));
+
+        assert!(matches!(
+            Instance::new(module(3)),
+            Err(Error::Trap(Trap::TableOutOfBounds))
+        ));
+    }
+
+    #[test]
+    fn call_indirect_compares_signatures_not_type_indices() {
+        let unary = |param| FuncType {
+            params: vec![param],
+            results: vec![ValType::I32],
+        };
+        // Types 0 and 1 are the same signature; type 2 takes an i64.
+        let module = |ty, arg| Module {
+            types: vec![
+                unary(ValType::I32),
+                unary(ValType::I32),
+                unary(ValType::I64),
+            ],
+            functions: vec![
+                Function::new(0, vec![], vec![Instruction::LocalGet(0)]),
+                Function::new(
+                    0,
+                    vec![],
+                    vec![
+                        arg,
+                        Instruction::I32Const(0),
+                        Instruction::CallIndirect(ty, 0),
+                    ],
+                ),
+            ],
+            tables: vec![TableType {
+                elem: RefType::Func,
+                limits: Limits { min: 1, max: None },
+            }],
+            elems: vec![Elem {
+                mode: ElemMode::Active {
+                    table: 0,
+                    offset: ConstExpr::Const(Value::I32(0)),
+                },
+                ty: RefType::Func,
+                init: vec![ConstExpr::RefFunc(0)],
+            }],
+            ..Module::default()
+        };
+        let mut m = Instance::new(module(1, Instruction::I32Const(7))).unwrap();
+        assert_eq!(m.call(1, vec![Value::I32(0)]), Ok(vec![Value::I32(7)]));
+
+        let mut m = Instance::new(module(2, Instruction::I64Const(7))).unwrap();
+        assert_eq!(
+            m.call(1, vec![Value::I32(0)]),
+            Err(Trap::IndirectCallTypeMismatch)
+        );
+
+        let err = module(3, Instruction::I32Const(7)).validate().unwrap_err();
+        assert_eq!(
+            (err.func, err.message.as_str()),
+            (Some(1), "unknown type 3")
+        );
+    }
+
+    #[test]
+    fn multiple_results() {
+        let i32s = |n| vec![ValType::I32; n];
+        let ty = |params, results| FuncType {
+            params: i32s(params),
+            results: i32s(results),
+        };
+        // Returns (a, b, a + b) from a block taking a and b as parameters.
+        let sum_pair = vec![
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::Block(BlockType::Type(0)),
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32Add,
+            Instruction::End,
+        ];
+        // Counts n down to zero in a loop carrying (n, steps).
+        let countdown = vec![
+            Instruction::LocalGet(0),
+            Instruction::I32Const(0),
+            Instruction::Loop(BlockType::Type(3)),
+            Instruction::LocalSet(1),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+            Instruction::LocalTee(0),
+            Instruction::LocalGet(1),
+            Instruction::I32Const(1),
+            Instruction::I32Add,
+            Instruction::LocalGet(0),
+            Instruction::BrIf(0),
+            Instruction::End,
+        ];
+        let caller = vec![
+            Instruction::I32Const(3),
+            Instruction::I32Const(4),
+            Instruction::CallFunc(0),
+            Instruction::I32Add,
+        ];
+        let module = Module {
+            types: vec![ty(2, 3), ty(1, 2), ty(0, 2), ty(2, 2)],
+            functions: vec![
+                Function::new(0, vec![], sum_pair),
+                Function::new(1, i32s(1), countdown),
+                Function::new(2, vec![], caller),
+            ],
+            ..Module::default()
+        };
+        module.validate().unwrap();
+
+        let mut m = Instance::new(module).unwrap();
+        let results = m.call(0, vec![Value::I32(3), Value::I32(4)]);
+        assert_eq!(
+            results,
+            Ok(vec![Value::I32(3), Value::I32(4), Value::I32(7)])
+        );
+        assert_eq!(
+            m.call(1, vec![Value::I32(5)]),
+            Ok(vec![Value::I32(0), Value::I32(5)])
+        );
+        assert_eq!(m.call(2, vec![]), Ok(vec![Value::I32(3), Value::I32(11)]));
+        assert_eq!(m.pop(), Ok(None));
+    }
+
+    #[test]
+    fn host_functions() {
+        use std::cell::RefCell;
+
+        let logged = Rc::new(RefCell::new(Vec::new()));
+        let mut imports = Imports::new();
+        let log = Rc::clone(&logged);
+        let log_ty = FuncType {
+            params: vec![ValType::I32],
+            results: vec![],
+        };
+        imports.func("env", "log", log_ty.clone(), move |caller, args| {
+            // Logs the byte at the address passed in.
+            let addr = i32::try_from(args[0]).unwrap() as usize;
+            log.borrow_mut().push(caller.memory().data()[addr]);
+            caller.memory_mut().data_mut()[addr] += 1;
+            Ok(vec![])
+        });
+        let answer_ty = FuncType {
+            params: vec![],
+            results: vec![ValType::I64],
+        };
+        imports.func("env", "answer", answer_ty.clone(), |_, _| {
+            Ok(vec![Value::I64(42)])
+        });
+        imports.func("env", "fail", FuncType::default(), |_, _| {
+            Err(Trap::Unreachable)
+        });
+        imports.func("env", "lie", answer_ty.clone(), |_, _| {
+            Ok(vec![Value::I32(42)])
+        });
+
+        let import = |name: &str, ty| Import {
+            module: "env".to_string(),
+            name: name.to_string(),
+            desc: ImportDesc::Func(ty),
+        };
+        let code = vec![
+            Instruction::I32Const(7),
+            Instruction::CallFunc(0),
+            Instruction::I32Const(7),
+            Instruction::I32Const(0),
+            Instruction::CallIndirect(0, 0),
+            Instruction::CallFunc(1),
         ];
+        let module = || Module {
+            types: vec![log_ty.clone(), answer_ty.clone(), FuncType::default()],
+            imports: vec![
+                import("log", 0),
+                import("answer", 1),
+                import("fail", 2),
+                import("lie", 1),
+            ],
+            functions: vec![Function::new(1, vec![], code.clone())],
+            tables: vec![TableType {
+                elem: RefType::Func,
+                limits: Limits { min: 1, max: None },
+            }],
+            memory: ONE_PAGE,
+            elems: vec![Elem {
+                mode: ElemMode::Active {
+                    table: 0,
+                    offset: ConstExpr::Const(Value::I32(0)),
+                },
+                ty: RefType::Func,
+                init: vec![ConstExpr::RefFunc(0)],
+            }],
+            ..Module::default()
+        };
+        module().validate().unwrap();
+
+        let mut m = Instance::with_imports(module(), &imports).unwrap();
+        assert_eq!(m.call(4, vec![]), Ok(vec![Value::I64(42)]));
+        assert_eq!(*logged.borrow(), vec![0, 1]);
+        assert_eq!(m.memory().unwrap().data()[7], 2);
+        assert_eq!(m.call(1, vec![]), Ok(vec![Value::I64(42)]));
+        assert_eq!(m.call(2, vec![]), Err(Trap::Unreachable));
+        assert_eq!(m.call(3, vec![]), Err(Trap::TypeMismatch));
+        assert_eq!(m.call(0, vec![]), Err(Trap::TypeMismatch));
+
+        let mut missing = module();
+        missing.imports[1].name = "question".to_string();
+        assert_eq!(
+            Instance::with_imports(missing, &imports).err(),
+            Some(Error::UnknownImport {
+                module: "env".to_string(),
+                name: "question".to_string()
+            })
+        );
+        let mut mistyped = module();
+        mistyped.imports[0].desc = ImportDesc::Func(2);
+        assert_eq!(
+            Instance::with_imports(mistyped, &imports).err(),
+            Some(Error::IncompatibleImportType {
+                module: "env".to_string(),
+                name: "log".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn invoke_by_name() {
+        let module = text::parse(
+            r#"(memory (export "mem") 2)
+               (table (export "table") 3 funcref)
+               (global $count (export "count") (mut i64) (i64.const 0))
+               (func (export "add") (param i64) (result i64)
+                 (global.set $count (i64.add (global.get $count) (local.get 0)))
+                 (global.get $count))"#,
+        )
+        .unwrap();
+        let mut m = Instance::new(module).unwrap();
+        assert_eq!(m.invoke("add", &[Value::I64(5)]), Ok(vec![Value::I64(5)]));
+        assert_eq!(m.invoke("add", &[Value::I64(2)]), Ok(vec![Value::I64(7)]));
+        assert_eq!(m.exported_global("count"), Ok(Some(Value::I64(7))));
+        assert_eq!(
+            m.exported_memory("mem").unwrap().map(|mem| mem.size()),
+            Some(2)
+        );
+        assert_eq!(m.exported_table_size("table"), Ok(Some(3)));
+        assert_eq!(m.export("add"), Ok(Some(ExportDesc::Func(0))));
+        assert_eq!(m.exported_global("mem"), Ok(None));
+
+        let err = m.invoke("add", &[Value::I32(5)]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "argument type mismatch: expected [i64], found [i32]"
+        );
+        assert_eq!(
+            m.invoke("sub", &[]),
+            Err(Error::UnknownExport("sub".to_string()))
+        );
+        assert_eq!(
+            m.invoke("count", &[]),
+            Err(Error::NotAFunction("count".to_string()))
+        );
+    }
+
+    #[test]
+    fn instances_share_a_module() {
+        let module = Rc::new(
+            Module::from_text(
+                r#"(memory (export "mem") 1)
+                   (global $count (mut i32) (i32.const 0))
+                   (func (export "bump") (result i32)
+                     (global.set $count (i32.add (global.get $count) (i32.const 1)))
+                     (i32.store (i32.const 0) (global.get $count))
+                     (global.get $count))"#,
+            )
+            .unwrap(),
+        );
+        let mut a = Instance::new(Rc::clone(&module)).unwrap();
+        let mut b = Instance::new(Rc::clone(&module)).unwrap();
+        assert_eq!(a.invoke("bump", &[]), Ok(vec![Value::I32(1)]));
+        assert_eq!(a.invoke("bump", &[]), Ok(vec![Value::I32(2)]));
+        assert_eq!(b.invoke("bump", &[]), Ok(vec![Value::I32(1)]));
+        assert_eq!(a.load::<i32>(0), Ok(2));
+        assert_eq!(b.load::<i32>(0), Ok(1));
+
+        assert!(matches!(
+            Module::from_text("(func (result i32))"),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(Module::from_text("(func"), Err(Error::Parse(_))));
+        assert!(matches!(
+            Module::from_binary(b"\0asm"),
+            Err(Error::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn initializes_memory_and_runs_start() {
+        let module = Module::from_text(
+            r#"(memory 1)
+               (global $sum (mut i32) (i32.const 0))
+               (data (i32.const 4) "\01\02" "\03")
+               (data $passive "\ff")
+               (func $start
+                 (global.set $sum (i32.add (i32.load8_u (i32.const 4)) (i32.load8_u (i32.const 6)))))
+               (start $start)
+               (func (export "sum") (result i32) (global.get $sum))"#,
+        )
+        .unwrap();
+        assert_eq!(module.data[1].offset, None);
+        let mut m = Instance::new(module).unwrap();
+        assert_eq!(m.invoke("sum", &[]), Ok(vec![Value::I32(4)]));
+        assert_eq!(m.load::<u32>(4), Ok(0x030201));
+        assert_eq!(m.load::<u8>(0), Ok(0));
+
+        let instantiate = |src: &str| Instance::new(Module::from_text(src).unwrap()).err();
+        assert_eq!(
+            instantiate(r#"(memory 1) (data (i32.const 65535) "ab")"#),
+            Some(Error::Trap(Trap::MemoryOutOfBounds))
+        );
+        assert_eq!(
+            instantiate("(func unreachable) (start 0)"),
+            Some(Error::Trap(Trap::Unreachable))
+        );
+        assert_eq!(instantiate(r#"(data "no memory needed")"#), None);
+    }
+
+    #[test]
+    fn bulk_memory() {
+        let module = Module::from_text(
+            r#"(memory 1)
+               (data $hello "hello")
+               (func (export "init") (param i32 i32 i32)
+                 (memory.init $hello (local.get 0) (local.get 1) (local.get 2)))
+               (func (export "drop") (param i32 i32 i32) (data.drop $hello))
+               (func (export "copy") (param i32 i32 i32)
+                 (memory.copy (local.get 0) (local.get 1) (local.get 2)))
+               (func (export "fill") (param i32 i32 i32)
+                 (memory.fill (local.get 0) (local.get 1) (local.get 2)))"#,
+        )
+        .unwrap();
+        let mut m = Instance::new(module).unwrap();
+        let mut run = |name: &str, d: i32, s: i32, n: i32| {
+            let args = [Value::I32(d), Value::I32(s), Value::I32(n)];
+            m.invoke(name, &args).map(|_| ())
+        };
+        let trap = Err(Error::Trap(Trap::MemoryOutOfBounds));
+        assert_eq!(run("init", 0, 0, 5), Ok(()));
+        assert_eq!(run("init", 10, 1, 4), Ok(()));
+        // Overlapping copies behave as if through a temporary buffer.
+        assert_eq!(run("copy", 1, 0, 4), Ok(()));
+        assert_eq!(run("copy", 11, 10, 4), Ok(()));
+        assert_eq!(run("copy", 10, 11, 4), Ok(()));
+        assert_eq!(run("fill", 20, 0x21, 3), Ok(()));
+        // Nothing is written if any part is out of bounds.
+        assert_eq!(run("init", 65534, 0, 3), trap);
+        assert_eq!(run("init", 30, 3, 3), trap);
+        assert_eq!(run("copy", 30, 65535, 2), trap);
+        assert_eq!(run("fill", 65535, 0x21, 2), trap);
+        assert_eq!(run("init", 65536, 5, 0), Ok(()));
+        assert_eq!(run("fill", 65537, 0, 0), trap);
+        assert_eq!(run("drop", 0, 0, 0), Ok(()));
+        assert_eq!(run("init", 30, 0, 1), trap);
+        assert_eq!(run("init", 30, 0, 0), Ok(()));
+
+        let memory = m.memory().unwrap();
+        assert_eq!(&memory.data()[..5], b"hhell");
+        assert_eq!(&memory.data()[10..15], b"elloo");
+        assert_eq!(&memory.data()[20..24], b"!!!\0");
+        assert!(memory.data()[30..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn bulk_tables() {
+        let module = Module::from_text(
+            r#"(table $t 4 funcref)
+               (table $u funcref (elem $two))
+               (elem $funcs func $one $two)
+               (elem $active (table $t) (i32.const 0) func $two)
+               (func $one (result i32) (i32.const 1))
+               (func $two (result i32) (i32.const 2))
+               (func (export "init") (param i32 i32 i32)
+                 (table.init $t $funcs (local.get 0) (local.get 1) (local.get 2)))
+               (func (export "init_active") (param i32 i32 i32)
+                 (table.init $active (local.get 0) (local.get 1) (local.get 2)))
+               (func (export "drop") (param i32 i32 i32) (elem.drop $funcs))
+               (func (export "copy") (param i32 i32 i32)
+                 (table.copy (local.get 0) (local.get 1) (local.get 2)))
+               (func (export "copy_u") (param i32 i32 i32)
+                 (table.copy $t $u (local.get 0) (local.get 1) (local.get 2)))
+               (func (export "call") (param i32) (result i32)
+                 (call_indirect $t (result i32) (local.get 0)))"#,
+        )
+        .unwrap();
+        let mut m = Instance::new(module).unwrap();
+        let mut run = |name: &str, d: i32, s: i32, n: i32| {
+            let args = [Value::I32(d), Value::I32(s), Value::I32(n)];
+            m.invoke(name, &args).map(|_| ())
+        };
+        let trap = Err(Error::Trap(Trap::TableOutOfBounds));
+        assert_eq!(run("init", 1, 0, 2), Ok(()));
+        assert_eq!(run("copy", 2, 1, 2), Ok(()));
+        assert_eq!(run("copy_u", 1, 0, 1), Ok(()));
+        assert_eq!(run("init", 3, 0, 2), trap);
+        assert_eq!(run("copy", 3, 0, 2), trap);
+        // Active segments are dropped once they have been copied.
+        assert_eq!(run("init_active", 0, 0, 1), trap);
+        assert_eq!(run("drop", 0, 0, 0), Ok(()));
+        assert_eq!(run("init", 0, 0, 1), trap);
+        assert_eq!(run("init", 4, 0, 0), Ok(()));
+
+        let results: Vec<_> = (0..4).map(|i| m.invoke("call", &[Value::I32(i)])).collect();
+        let expected = [2, 2, 1, 2].map(|result| Ok(vec![Value::I32(result)]));
+        assert_eq!(results, expected);
+    }
 
-        let mut m = Machine::new(functions, 65536);
-        m.store(x_addr as usize, 2.0);
-        m.store(v_addr as usize, 3.0);
-        m.execute(&code, &mut None);
-        println!("Result: {}", m.load(x_addr as usize));
+    #[test]
+    fn reference_types() {
+        let module = Module::from_text(
+            r#"(table $handles 2 externref)
+               (table $funcs 1 2 funcref)
+               (global $callback (mut funcref) (ref.null func))
+               (elem declare func $answer)
+               (func $answer (result i32) (i32.const 42))
+               (func (export "store") (param i32 externref)
+                 (table.set $handles (local.get 0) (local.get 1)))
+               (func (export "load") (param i32) (result externref)
+                 (table.get $handles (local.get 0)))
+               (func (export "grow") (param externref i32) (result i32)
+                 (table.grow $handles (local.get 0) (local.get 1)))
+               (func (export "size") (result i32) (table.size $handles))
+               (func (export "fill") (param i32 externref i32)
+                 (table.fill $handles (local.get 0) (local.get 1) (local.get 2)))
+               (func (export "is_null") (param externref) (result i32)
+                 (ref.is_null (local.get 0)))
+               (func (export "pick") (param externref externref i32) (result externref)
+                 (select (result externref) (local.get 0) (local.get 1) (local.get 2)))
+               (func (export "grow_funcs") (param i32) (result i32)
+                 (table.grow $funcs (ref.null func) (local.get 0)))
+               (func (export "set_callback") (global.set $callback (ref.func $answer)))
+               (func (export "call_callback") (result i32)
+                 (table.set $funcs (i32.const 0) (global.get $callback))
+                 (call_indirect $funcs (result i32) (i32.const 0)))"#,
+        )
+        .unwrap();
+        let mut m = Instance::new(module).unwrap();
+        let handle = |handle| Value::ExternRef(Some(handle));
+        let null = Value::ExternRef(None);
+        let oob = Err(Error::Trap(Trap::TableOutOfBounds));
+
+        assert_eq!(m.invoke("store", &[Value::I32(0), handle(7)]), Ok(vec![]));
+        assert_eq!(m.invoke("load", &[Value::I32(0)]), Ok(vec![handle(7)]));
+        assert_eq!(m.invoke("load", &[Value::I32(1)]), Ok(vec![null]));
+        assert_eq!(m.invoke("load", &[Value::I32(2)]), oob);
+        assert_eq!(m.invoke("store", &[Value::I32(2), handle(7)]), oob);
+
+        assert_eq!(
+            m.invoke("grow", &[handle(3), Value::I32(2)]),
+            Ok(vec![Value::I32(2)])
+        );
+        assert_eq!(m.invoke("size", &[]), Ok(vec![Value::I32(4)]));
+        assert_eq!(m.invoke("load", &[Value::I32(3)]), Ok(vec![handle(3)]));
+        assert_eq!(
+            m.invoke("grow", &[null, Value::I32(-1)]),
+            Ok(vec![Value::I32(-1)])
+        );
+        assert_eq!(
+            m.invoke("grow_funcs", &[Value::I32(2)]),
+            Ok(vec![Value::I32(-1)])
+        );
+        assert_eq!(
+            m.invoke("grow_funcs", &[Value::I32(1)]),
+            Ok(vec![Value::I32(1)])
+        );
+
+        let fill = [Value::I32(1), handle(5), Value::I32(3)];
+        assert_eq!(m.invoke("fill", &fill), Ok(vec![]));
+        let loaded: Vec<_> = (0..4)
+            .map(|i| m.invoke("load", &[Value::I32(i)]).unwrap()[0])
+            .collect();
+        assert_eq!(loaded, vec![handle(7), handle(5), handle(5), handle(5)]);
+        assert_eq!(m.invoke("fill", &[Value::I32(3), null, Value::I32(2)]), oob);
+        assert_eq!(m.invoke("load", &[Value::I32(3)]), Ok(vec![handle(5)]));
+
+        assert_eq!(m.invoke("is_null", &[null]), Ok(vec![Value::I32(1)]));
+        assert_eq!(m.invoke("is_null", &[handle(0)]), Ok(vec![Value::I32(0)]));
+        let args = [handle(1), handle(2), Value::I32(0)];
+        assert_eq!(m.invoke("pick", &args), Ok(vec![handle(2)]));
+
+        assert_eq!(
+            m.invoke("call_callback", &[]),
+            Err(Error::Trap(Trap::UninitializedElement))
+        );
+        m.invoke("set_callback", &[]).unwrap();
+        assert_eq!(m.invoke("call_callback", &[]), Ok(vec![Value::I32(42)]));
+    }
+
+    #[test]
+    fn recursion_with_if_else() {
+        // sum(n) = if n { n + sum(n - 1) } else { 0 }
+        let sum = func(
+            vec![ValType::I32],
+            Some(ValType::I32),
+            vec![],
+            vec![
+                Instruction::LocalGet(0),
+                Instruction::If(BlockType::Value(ValType::I32)),
+                Instruction::LocalGet(0),
+                Instruction::LocalGet(0),
+                Instruction::I32Const(-1),
+                Instruction::I32Add,
+                Instruction::CallFunc(0),
+                Instruction::I32Add,
+                Instruction::Else,
+                Instruction::I32Const(0),
+                Instruction::End,
+            ],
+        );
+        let mut m = instance(vec![sum], None);
+        assert_eq!(m.call(0, vec![Value::I32(4)]), Ok(vec![Value::I32(10)]));
+    }
+    #[test]
+    fn call_depth_exhaustion() {
+        // Unbounded recursion runs out of frames, not out of Rust stack.
+        let forever = func(vec![], None, vec![], vec![Instruction::CallFunc(0)]);
+        let mut m = instance(vec![forever], None);
+        assert_eq!(m.call(0, vec![]), Err(Trap::CallStackExhausted));
+
+        // countdown(n) = if n { countdown(n - 1) }, which takes n + 1 frames.
+        let countdown = func(
+            vec![ValType::I32],
+            None,
+            vec![],
+            vec![
+                Instruction::LocalGet(0),
+                Instruction::If(BlockType::Empty),
+                Instruction::LocalGet(0),
+                Instruction::I32Const(-1),
+                Instruction::I32Add,
+                Instruction::CallFunc(0),
+                Instruction::End,
+            ],
+        );
+        let mut m = instance(vec![countdown], None);
+        let frames = |n: usize| vec![Value::I32(n as i32 - 1)];
+        assert_eq!(m.call(0, frames(DEFAULT_MAX_CALL_DEPTH)), Ok(vec![]));
+        assert_eq!(
+            m.call(0, frames(DEFAULT_MAX_CALL_DEPTH + 1)),
+            Err(Trap::CallStackExhausted)
+        );
+
+        m.set_max_call_depth(5).unwrap();
+        assert_eq!(m.call(0, frames(5)), Ok(vec![]));
+        assert_eq!(m.call(0, frames(6)), Err(Trap::CallStackExhausted));
+        assert_eq!(m.pop(), Ok(None));
+    }
+    #[test]
+    fn mutable_locals() {
+        // Sums 1..=n into a declared local, counting the parameter down.
+        let sum = func(
+            vec![ValType::I32],
+            Some(ValType::I32),
+            vec![ValType::I32],
+            vec![
+                Instruction::Loop(BlockType::Empty),
+                Instruction::LocalGet(0),
+                Instruction::If(BlockType::Empty),
+                Instruction::LocalGet(1),
+                Instruction::LocalGet(0),
+                Instruction::I32Add,
+                Instruction::LocalSet(1),
+                Instruction::LocalGet(0),
+                Instruction::I32Const(-1),
+                Instruction::I32Add,
+                Instruction::LocalTee(0),
+                Instruction::Drop,
+                Instruction::Br(1),
+                Instruction::End,
+                Instruction::End,
+                Instruction::LocalGet(1),
+            ],
+        );
+        let mut m = instance(vec![sum], None);
+        assert_eq!(m.call(0, vec![Value::I32(5)]), Ok(vec![Value::I32(15)]));
+        assert_eq!(m.call(0, vec![Value::I64(5)]), Err(Trap::TypeMismatch));
+    }
+    #[test]
+    fn if_else_and_br_table() {
+        let classify = func(
+            vec![ValType::I32],
+            Some(ValType::I32),
+            vec![],
+            vec![
+                Instruction::Block(BlockType::Empty),
+                Instruction::Block(BlockType::Empty),
+                Instruction::LocalGet(0),
+                Instruction::BrTable(vec![0, 1], 1),
+                Instruction::End,
+                Instruction::I32Const(10),
+                Instruction::Return,
+                Instruction::End,
+                Instruction::LocalGet(0),
+                Instruction::I32Const(-1),
+                Instruction::I32Add,
+                Instruction::If(BlockType::Value(ValType::I32)),
+                Instruction::I32Const(20),
+                Instruction::Else,
+                Instruction::I32Const(30),
+                Instruction::End,
+                Instruction::Return,
+            ],
+        );
+        // Each call's result is scaled so the sum shows which branch ran.
+        let code = vec![
+            Instruction::I32Const(0),
+            Instruction::CallFunc(0),
+            Instruction::I32Const(1),
+            Instruction::CallFunc(0),
+            Instruction::I32Const(100),
+            Instruction::I32Mul,
+            Instruction::I32Add,
+            Instruction::I32Const(7),
+            Instruction::CallFunc(0),
+            Instruction::I32Const(10000),
+            Instruction::I32Mul,
+            Instruction::I32Add,
+        ];
+        let functions = vec![classify, main(Some(ValType::I32), code)];
+        let mut m = instance(functions, None);
+        assert_eq!(m.call(1, vec![]), Ok(vec![Value::I32(200_000 + 3000 + 10)]));
     }
 }
@@ -0,0 +1,267 @@
+//! Instantiating modules that import from each other.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::{Caller, Error, Extern, FuncType, HostFunc, Instance, Module, Store, Trap, Value};
+
+/// Instantiates modules into a shared store, resolving their imports against
+/// host functions and the exports of instances registered under a module
+/// name.
+#[derive(Default)]
+pub struct Linker {
+    store: Rc<RefCell<Store>>,
+    defs: HashMap<(String, String), Extern>,
+}
+
+impl Linker {
+    pub fn new() -> Self {
+        Linker::default()
+    }
+
+    /// Provides `func` as the import `name` from `module`, replacing any
+    /// definition already registered there. While it runs, using any of
+    /// this linker's instances fails with `Trap::ReentrantCall`.
+    ///
+    /// # Panics
+    ///
+    /// If called from a host function while the linker's store is running.
+    pub fn func<F>(&mut self, module: &str, name: &str, ty: FuncType, func: F) -> &mut Self
+    where
+        F: Fn(&mut Caller, &[Value]) -> Result<Vec<Value>, Trap> + 'static,
+    {
+        let addr = self.store.borrow_mut().alloc_host(HostFunc::new(ty, func));
+        let key = (module.to_string(), name.to_string());
+        self.defs.insert(key, Extern::Func(addr));
+        self
+    }
+
+    /// Makes everything `instance` exports available to later modules as
+    /// imports from `module`, replacing any definitions already registered
+    /// under the same names.
+    ///
+    /// # Panics
+    ///
+    /// If `instance` wasn't created by this linker.
+    pub fn register(&mut self, module: &str, instance: &Instance) -> Result<&mut Self, Trap> {
+        assert!(
+            instance.in_store(&self.store),
+            "instance belongs to a different store"
+        );
+        for name in instance.export_names()? {
+            if let Some(ext) = instance.export_extern(&name)? {
+                self.defs.insert((module.to_string(), name), ext);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Creates an instance of `module` in the linker's store, with its
+    /// imports resolved against the definitions registered so far.
+    pub fn instantiate(&mut self, module: impl Into<Rc<Module>>) -> Result<Instance, Error> {
+        let module = module.into();
+        let mut externs = Vec::with_capacity(module.imports.len());
+        for import in &module.imports {
+            match self.defs.get(&(import.module.clone(), import.name.clone())) {
+                Some(ext) => externs.push(*ext),
+                None => {
+                    return Err(Error::UnknownImport {
+                        module: import.module.clone(),
+                        name: import.name.clone(),
+                    })
+                }
+            }
+        }
+        let index = self
+            .store
+            .try_borrow_mut()
+            .map_err(|_| Trap::ReentrantCall)?
+            .instantiate(module, &externs)?;
+        Ok(Instance::from_store(Rc::clone(&self.store), index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+    use crate::ValType;
+
+    fn module(src: &str) -> Module {
+        Module::from_text(src).unwrap()
+    }
+
+    #[test]
+    fn links_instances() {
+        let mut linker = Linker::new();
+        linker.func(
+            "host",
+            "double",
+            FuncType {
+                params: vec![ValType::I32],
+                results: vec![ValType::I32],
+            },
+            |_, args| Ok(vec![Value::I32(i32::try_from(args[0]).unwrap() * 2)]),
+        );
+        let lib = linker
+            .instantiate(module(
+                r#"(import "host" "double" (func $double (param i32) (result i32)))
+                   (memory (export "mem") 1)
+                   (table (export "table") funcref (elem $get))
+                   (global $count (export "count") (mut i32) (i32.const 0))
+                   (func $get (export "get") (result i32)
+                     (call $double (i32.load (i32.const 0))))
+                   (func (export "bump")
+                     (global.set $count (i32.add (global.get $count) (i32.const 1))))"#,
+            ))
+            .unwrap();
+        linker.register("lib", &lib).unwrap();
+
+        let mut app = linker
+            .instantiate(module(
+                r#"(import "lib" "mem" (memory 1))
+                   (import "lib" "table" (table 1 funcref))
+                   (import "lib" "count" (global $count (mut i32)))
+                   (import "lib" "bump" (func $bump))
+                   (func (export "run") (param i32) (result i32)
+                     (i32.store (i32.const 0) (local.get 0))
+                     (call $bump)
+                     (global.set $count (i32.add (global.get $count) (i32.const 10)))
+                     (call_indirect (result i32) (i32.const 0)))"#,
+            ))
+            .unwrap();
+        assert_eq!(
+            app.invoke("run", &[Value::I32(21)]),
+            Ok(vec![Value::I32(42)])
+        );
+        assert_eq!(lib.exported_global("count"), Ok(Some(Value::I32(11))));
+        assert_eq!(lib.load::<i32>(0), Ok(21));
+
+        // The exports of an instance that imports them are just as usable.
+        let mut lib = lib;
+        assert_eq!(lib.invoke("get", &[]), Ok(vec![Value::I32(42)]));
+    }
+
+    #[test]
+    fn rejects_reentrant_calls() {
+        let mut linker = Linker::new();
+        let other: Rc<RefCell<Option<Instance>>> = Rc::default();
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        let (callee, seen) = (Rc::clone(&other), Rc::clone(&errors));
+        linker.func("host", "reenter", FuncType::default(), move |_, _| {
+            let mut callee = callee.borrow_mut();
+            let callee = callee.as_mut().unwrap();
+            let mut seen = seen.borrow_mut();
+            seen.push(callee.invoke("noop", &[]).err());
+            seen.push(callee.load::<u8>(0).err().map(Error::from));
+            seen.push(callee.exported_global("g").err().map(Error::from));
+            seen.push(callee.memory_mut().err().map(Error::from));
+            seen.push(callee.set_max_call_depth(10).err().map(Error::from));
+            Ok(vec![])
+        });
+        let lib = linker
+            .instantiate(module(
+                r#"(memory 1) (global (export "g") i32 (i32.const 7)) (func (export "noop"))"#,
+            ))
+            .unwrap();
+        *other.borrow_mut() = Some(lib);
+        let mut app = linker
+            .instantiate(module(
+                r#"(import "host" "reenter" (func $reenter))
+                   (func (export "run") (call $reenter))"#,
+            ))
+            .unwrap();
+
+        assert_eq!(app.invoke("run", &[]), Ok(vec![]));
+        let reentrant = Some(Error::Trap(Trap::ReentrantCall));
+        assert_eq!(*errors.borrow(), vec![reentrant; 5]);
+
+        // Outside the call the instance is usable again.
+        let mut lib = other.borrow_mut().take().unwrap();
+        assert_eq!(lib.invoke("noop", &[]), Ok(vec![]));
+        assert_eq!(lib.load::<u8>(0), Ok(0));
+        assert_eq!(lib.exported_global("g"), Ok(Some(Value::I32(7))));
+    }
+
+    #[test]
+    fn reports_link_errors() {
+        let mut linker = Linker::new();
+        let lib = linker
+            .instantiate(module(
+                r#"(memory (export "mem") 1 2)
+                   (global (export "const") i32 (i32.const 0))
+                   (func (export "f") (param i32))"#,
+            ))
+            .unwrap();
+        linker.register("lib", &lib).unwrap();
+
+        let mut link = |src: &str| linker.instantiate(module(src)).err();
+        let error = |name: &str| {
+            Some(Error::IncompatibleImportType {
+                module: "lib".to_string(),
+                name: name.to_string(),
+            })
+        };
+        assert_eq!(link(r#"(import "lib" "mem" (memory 1 2))"#), None);
+        assert_eq!(
+            link(r#"(import "lib" "g" (global i32))"#),
+            Some(Error::UnknownImport {
+                module: "lib".to_string(),
+                name: "g".to_string()
+            })
+        );
+        assert_eq!(link(r#"(import "lib" "f" (func))"#), error("f"));
+        assert_eq!(link(r#"(import "lib" "f" (memory 1))"#), error("f"));
+        assert_eq!(link(r#"(import "lib" "mem" (memory 2))"#), error("mem"));
+        assert_eq!(link(r#"(import "lib" "mem" (memory 1 1))"#), error("mem"));
+        assert_eq!(
+            link(r#"(import "lib" "const" (global (mut i32)))"#),
+            error("const")
+        );
+    }
+
+    #[test]
+    fn failed_instantiation() {
+        let mut linker = Linker::new();
+        let mut lib = linker
+            .instantiate(module(
+                r#"(table (export "table") 1 funcref)
+                   (func (export "call") (result i32)
+                     (call_indirect (result i32) (i32.const 0)))"#,
+            ))
+            .unwrap();
+        linker.register("lib", &lib).unwrap();
+        let sizes = |linker: &Linker| {
+            let store = linker.store.borrow();
+            (
+                store.funcs.len(),
+                store.tables.len(),
+                store.memories.len(),
+                store.globals.len(),
+                store.instances.len(),
+            )
+        };
+
+        // Nothing is left behind by a module that can't be allocated.
+        let before = sizes(&linker);
+        let result = linker.instantiate(module(
+            r#"(func) (memory 1) (global i32 (i32.const 0)) (table 0xffffffff funcref)"#,
+        ));
+        assert_eq!(result.err(), Some(Error::AllocationFailed("table")));
+        assert_eq!(sizes(&linker), before);
+
+        // A module that traps copying its data still put its function in the
+        // imported table, which keeps working.
+        let result = linker.instantiate(module(
+            r#"(import "lib" "table" (table 1 funcref))
+               (memory 0)
+               (func $answer (result i32) (i32.const 42))
+               (elem (i32.const 0) $answer)
+               (data (i32.const 0) "x")"#,
+        ));
+        assert_eq!(result.err(), Some(Error::Trap(Trap::MemoryOutOfBounds)));
+        assert_eq!(lib.invoke("call", &[]), Ok(vec![Value::I32(42)]));
+    }
+}
@@ -0,0 +1,113 @@
+use std::convert::TryInto;
+use std::ops::Range;
+
+use crate::module::{Limits, MAX_PAGES, PAGE_SIZE};
+use crate::Trap;
+
+/// A value that can be loaded from or stored to linear memory, which is
+/// always little-endian.
+pub trait LittleEndian: Copy {
+    const SIZE: usize;
+
+    /// Reads a value from exactly `SIZE` bytes.
+    fn from_le_slice(bytes: &[u8]) -> Self;
+
+    /// Writes the value to exactly `SIZE` bytes.
+    fn write_le_slice(self, bytes: &mut [u8]);
+}
+
+macro_rules! impl_little_endian {
+    ($($ty:ty),*) => {
+        $(
+            impl LittleEndian for $ty {
+                const SIZE: usize = std::mem::size_of::<$ty>();
+
+                fn from_le_slice(bytes: &[u8]) -> Self {
+                    <$ty>::from_le_bytes(bytes.try_into().unwrap())
+                }
+
+                fn write_le_slice(self, bytes: &mut [u8]) {
+                    bytes.copy_from_slice(&self.to_le_bytes());
+                }
+            }
+        )*
+    };
+}
+
+impl_little_endian!(i8, u8, i16, u16, i32, u32, i64, u64, f32, f64);
+
+/// A linear memory, sized in pages of `PAGE_SIZE` bytes.
+#[derive(Debug)]
+pub struct Memory {
+    data: Vec<u8>,
+    /// Maximum size in pages, if limited below `MAX_PAGES`.
+    max: Option<u32>,
+}
+
+impl Memory {
+    /// A zeroed memory of `limits.min` pages, or `None` if that's more than
+    /// `MAX_PAGES` or can't be allocated.
+    pub fn new(limits: Limits) -> Option<Self> {
+        if limits.min > MAX_PAGES {
+            return None;
+        }
+        let len = limits.min as usize * PAGE_SIZE;
+        let mut data = Vec::new();
+        data.try_reserve_exact(len).ok()?;
+        data.resize(len, 0);
+        Some(Memory {
+            data,
+            max: limits.max,
+        })
+    }
+
+    /// Current size in pages.
+    pub fn size(&self) -> u32 {
+        (self.data.len() / PAGE_SIZE) as u32
+    }
+
+    /// Maximum size in pages, if any.
+    pub fn max(&self) -> Option<u32> {
+        self.max
+    }
+
+    /// Adds `delta` zeroed pages and returns the previous size, or `None` if
+    /// that would exceed the maximum or the pages can't be allocated.
+    pub fn grow(&mut self, delta: u32) -> Option<u32> {
+        let size = self.size();
+        let max = self.max.unwrap_or(MAX_PAGES);
+        let new_size = size
+            .checked_add(delta)
+            .filter(|&new_size| new_size <= max)?;
+        let len = new_size as usize * PAGE_SIZE;
+        self.data.try_reserve_exact(len - self.data.len()).ok()?;
+        self.data.resize(len, 0);
+        Some(size)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    fn range(&self, addr: usize, len: usize) -> Result<Range<usize>, Trap> {
+        match addr.checked_add(len) {
+            Some(end) if end <= self.data.len() => Ok(addr..end),
+            _ => Err(Trap::MemoryOutOfBounds),
+        }
+    }
+
+    pub fn load<T: LittleEndian>(&self, addr: usize) -> Result<T, Trap> {
+        let range = self.range(addr, T::SIZE)?;
+        Ok(T::from_le_slice(&self.data[range]))
+    }
+
+    pub fn store<T: LittleEndian>(&mut self, addr: usize, val: T) -> Result<(), Trap> {
+        let range = self.range(addr, T::SIZE)?;
+        val.write_le_slice(&mut self.data[range]);
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+use crate::validate::{self, ValidationError};
+use crate::{binary, text, Error, Function, RefType, ValType, Value};
+
+pub const PAGE_SIZE: usize = 65536;
+
+/// Largest number of pages a 32-bit memory can have.
+pub const MAX_PAGES: u32 = 65536;
+
+/// Largest number of elements `table.grow` grows a table to, far more than
+/// programs need but few enough to allocate.
+pub const MAX_TABLE_SIZE: u32 = 10_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub min: u32,
+    pub max: Option<u32>,
+}
+
+/// A table of references, whose size is measured in elements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableType {
+    pub elem: RefType,
+    pub limits: Limits,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportDesc {
+    /// A function of the type at this index.
+    Func(usize),
+    Table(TableType),
+    Memory(Limits),
+    Global(GlobalType),
+}
+
+/// A definition supplied by the host or another instance, looked up by module
+/// and field name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Import {
+    pub module: String,
+    pub name: String,
+    pub desc: ImportDesc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportDesc {
+    Func(usize),
+    Table(usize),
+    Memory(usize),
+    Global(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Export {
+    pub name: String,
+    pub desc: ExportDesc,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FuncType {
+    pub params: Vec<ValType>,
+    pub results: Vec<ValType>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GlobalType {
+    pub ty: ValType,
+    pub mutable: bool,
+}
+
+/// An initializer expression, evaluated when the module is instantiated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConstExpr {
+    Const(Value),
+    /// The value of an earlier immutable global.
+    GlobalGet(usize),
+    RefNull(RefType),
+    /// A reference to the function at this index.
+    RefFunc(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Global {
+    pub ty: GlobalType,
+    pub init: ConstExpr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ElemMode {
+    /// Copied into `table` at `offset` when the module is instantiated.
+    Active { table: usize, offset: ConstExpr },
+    /// Only copied by `table.init`.
+    Passive,
+    /// Never copied, only declaring the functions it refers to.
+    Declarative,
+}
+
+/// An element segment of references, each given by an initializer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Elem {
+    pub mode: ElemMode,
+    pub ty: RefType,
+    pub init: Vec<ConstExpr>,
+}
+
+/// A data segment, copied into memory when the module is instantiated if it
+/// is active.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Data {
+    /// Where an active segment is copied to, or `None` for a passive one,
+    /// which is only copied by instructions.
+    pub offset: Option<ConstExpr>,
+    pub init: Vec<u8>,
+}
+
+/// The code and definitions of a WebAssembly module, which any number of
+/// `Instance`s can share by wrapping it in an `Rc`. Outside this crate a
+/// non-empty module can only be built by `from_binary` and `from_text`, so
+/// it is validated once and instances can trust it.
+#[derive(Debug, Default)]
+pub struct Module {
+    /// Signatures referred to by `call_indirect`.
+    pub(crate) types: Vec<FuncType>,
+    /// Imported definitions, which come before those the module defines in
+    /// each index space.
+    pub(crate) imports: Vec<Import>,
+    pub(crate) functions: Vec<Function>,
+    pub(crate) tables: Vec<TableType>,
+    /// The memory the module defines, if it doesn't import one.
+    pub(crate) memory: Option<Limits>,
+    pub(crate) globals: Vec<Global>,
+    pub(crate) exports: Vec<Export>,
+    pub(crate) elems: Vec<Elem>,
+    pub(crate) data: Vec<Data>,
+    /// A function taking and returning nothing, called once the module is
+    /// instantiated.
+    pub(crate) start: Option<usize>,
+}
+
+impl Module {
+    /// Decodes and validates a module in the binary format.
+    pub fn from_binary(bytes: &[u8]) -> Result<Self, Error> {
+        let module = binary::decode(bytes)?;
+        module.validate()?;
+        Ok(module)
+    }
+
+    /// Parses and validates a module in the text format.
+    pub fn from_text(src: &str) -> Result<Self, Error> {
+        let module = text::parse(src)?;
+        module.validate()?;
+        Ok(module)
+    }
+
+    /// Initial size of the module's memory in bytes, or 0 if it has none.
+    pub fn mem_size(&self) -> usize {
+        self.memory
+            .map(|limits| limits.min as usize * PAGE_SIZE)
+            .unwrap_or(0)
+    }
+
+    /// Number of imported functions, at the start of the function index
+    /// space.
+    pub fn imported_funcs(&self) -> usize {
+        self.imports
+            .iter()
+            .filter(|import| matches!(import.desc, ImportDesc::Func(_)))
+            .count()
+    }
+
+    /// Number of functions, imported or defined.
+    pub fn func_count(&self) -> usize {
+        self.imported_funcs() + self.functions.len()
+    }
+
+    /// Type of the function at `index` in the function index space.
+    pub fn func_type(&self, index: usize) -> Option<&FuncType> {
+        let imported = self.imports.iter().filter_map(|import| match import.desc {
+            ImportDesc::Func(ty) => Some(ty),
+            _ => None,
+        });
+        let ty = imported
+            .chain(self.functions.iter().map(Function::ty))
+            .nth(index)?;
+        self.types.get(ty)
+    }
+
+    /// Types of every table, imported ones first.
+    pub fn table_types(&self) -> impl Iterator<Item = TableType> + '_ {
+        let imported = self.imports.iter().filter_map(|import| match import.desc {
+            ImportDesc::Table(ty) => Some(ty),
+            _ => None,
+        });
+        imported.chain(self.tables.iter().copied())
+    }
+
+    /// Number of tables, imported or defined.
+    pub fn table_count(&self) -> usize {
+        self.table_types().count()
+    }
+
+    /// Type of the table at `index` in the table index space.
+    pub fn table_type(&self, index: usize) -> Option<TableType> {
+        self.table_types().nth(index)
+    }
+
+    /// Limits of every memory, the imported one first.
+    pub fn memory_types(&self) -> impl Iterator<Item = Limits> + '_ {
+        let imported = self.imports.iter().filter_map(|import| match import.desc {
+            ImportDesc::Memory(limits) => Some(limits),
+            _ => None,
+        });
+        imported.chain(self.memory)
+    }
+
+    /// Whether the module imports or defines a memory.
+    pub fn has_memory(&self) -> bool {
+        self.memory_types().next().is_some()
+    }
+
+    /// Number of imported globals, at the start of the global index space.
+    pub fn imported_globals(&self) -> usize {
+        self.imports
+            .iter()
+            .filter(|import| matches!(import.desc, ImportDesc::Global(_)))
+            .count()
+    }
+
+    /// Number of globals, imported or defined.
+    pub fn global_count(&self) -> usize {
+        self.imported_globals() + self.globals.len()
+    }
+
+    /// Type of the global at `index` in the global index space.
+    pub fn global_type(&self, index: usize) -> Option<GlobalType> {
+        let imported = self.imports.iter().filter_map(|import| match import.desc {
+            ImportDesc::Global(ty) => Some(ty),
+            _ => None,
+        });
+        imported
+            .chain(self.globals.iter().map(|global| global.ty))
+            .nth(index)
+    }
+
+    /// Type checks the module, so that `Instance` can run its functions without
+    /// encountering ill-typed code.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        validate::validate(self)
+    }
+}
@@ -0,0 +1,76 @@
+//! Numeric operations whose WebAssembly semantics differ from Rust's.
+
+use crate::Trap;
+
+pub(crate) trait Float: Copy {
+    /// The lesser operand, treating -0 as less than +0 and returning NaN if
+    /// either operand is NaN.
+    fn wasm_min(self, other: Self) -> Self;
+
+    /// The greater operand, treating +0 as greater than -0 and returning NaN
+    /// if either operand is NaN.
+    fn wasm_max(self, other: Self) -> Self;
+}
+
+macro_rules! impl_float {
+    ($($float:ty),*) => {
+        $(
+            impl Float for $float {
+                fn wasm_min(self, other: Self) -> Self {
+                    if self.is_nan() || other.is_nan() {
+                        // Arithmetic on a NaN propagates it, quieting it if needed.
+                        self + other
+                    } else if self == other {
+                        // Only differs for zeros, where the sign bit marks -0.
+                        <$float>::from_bits(self.to_bits() | other.to_bits())
+                    } else if self < other {
+                        self
+                    } else {
+                        other
+                    }
+                }
+
+                fn wasm_max(self, other: Self) -> Self {
+                    if self.is_nan() || other.is_nan() {
+                        self + other
+                    } else if self == other {
+                        <$float>::from_bits(self.to_bits() & other.to_bits())
+                    } else if self > other {
+                        self
+                    } else {
+                        other
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_float!(f32, f64);
+
+macro_rules! trunc {
+    ($($name:ident -> $int:ty, ($min:expr, $max:expr);)*) => {
+        $(
+            /// Truncates towards zero, trapping if `val` is NaN or the result
+            /// lies outside the open interval `(min, max)`.
+            pub(crate) fn $name(val: f64) -> Result<$int, Trap> {
+                if val.is_nan() {
+                    return Err(Trap::InvalidConversionToInteger);
+                }
+                if !(val > $min && val < $max) {
+                    return Err(Trap::IntegerOverflow);
+                }
+                Ok(val as $int)
+            }
+        )*
+    };
+}
+
+// Every f32 is exactly representable as an f64, so both widths share these.
+trunc! {
+    trunc_i32 -> i32, (-2147483649.0, 2147483648.0);
+    trunc_u32 -> u32, (-1.0, 4294967296.0);
+    // The next f64 below -2^63 is -2^63 - 2048.
+    trunc_i64 -> i64, (-9223372036854777856.0, 9223372036854775808.0);
+    trunc_u64 -> u64, (-1.0, 18446744073709551616.0);
+}
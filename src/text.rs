@@ -0,0 +1,2370 @@
+//! Parser for the WebAssembly text format.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::module::{
+    ConstExpr, Data, Elem, ElemMode, Export, ExportDesc, FuncType, Global, GlobalType, Import,
+    ImportDesc, Limits, Module, TableType, PAGE_SIZE,
+};
+use crate::{BlockType, Function, Instruction, MemArg, RefType, ValType, Value};
+
+/// A syntax error, with the 1-based line and column it was found at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub col: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.col, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+type Result<T> = std::result::Result<T, ParseError>;
+
+pub(crate) fn parse(src: &str) -> Result<Module> {
+    let mut parser = Parser::new(src)?;
+    let module = parser.module()?;
+    if let Some(token) = parser.peek() {
+        return Err(token.error("unexpected token after module"));
+    }
+    Ok(module)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum TokenKind<'a> {
+    LParen,
+    RParen,
+    /// A keyword, number or other reserved word.
+    Atom(&'a str),
+    /// An identifier, without the leading `$`.
+    Id(&'a str),
+    Str(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Token<'a> {
+    pub kind: TokenKind<'a>,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl<'a> Token<'a> {
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        ParseError {
+            line: self.line,
+            col: self.col,
+            message: message.into(),
+        }
+    }
+}
+
+fn is_idchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-./:<=>?@\\^_`|~".contains(c)
+}
+
+struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+    line: usize,
+    col: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T> {
+        Err(ParseError {
+            line: self.line,
+            col: self.col,
+            message: message.into(),
+        })
+    }
+
+    fn peek_char(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek_char()?;
+        self.pos += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    fn skip_trivia(&mut self) -> Result<()> {
+        loop {
+            let rest = &self.src[self.pos..];
+            if rest.starts_with(";;") {
+                while !matches!(self.bump(), Some('\n') | None) {}
+            } else if rest.starts_with("(;") {
+                let mut depth = 0;
+                loop {
+                    let rest = &self.src[self.pos..];
+                    if rest.starts_with("(;") {
+                        depth += 1;
+                        self.bump();
+                    } else if rest.starts_with(";)") {
+                        depth -= 1;
+                        self.bump();
+                        if depth == 0 {
+                            self.bump();
+                            break;
+                        }
+                    } else if rest.is_empty() {
+                        return self.error("unterminated block comment");
+                    }
+                    self.bump();
+                }
+            } else if matches!(self.peek_char(), Some(c) if c.is_whitespace()) {
+                self.bump();
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        loop {
+            match self.bump() {
+                None => return self.error("unterminated string"),
+                Some('"') => return Ok(bytes),
+                Some('\\') => match self.bump() {
+                    Some('t') => bytes.push(b'\t'),
+                    Some('n') => bytes.push(b'\n'),
+                    Some('r') => bytes.push(b'\r'),
+                    Some('"') => bytes.push(b'"'),
+                    Some('\'') => bytes.push(b'\''),
+                    Some('\\') => bytes.push(b'\\'),
+                    Some('u') => {
+                        if self.bump() != Some('{') {
+                            return self.error("malformed unicode escape");
+                        }
+                        let start = self.pos;
+                        while matches!(self.peek_char(), Some(c) if c != '}') {
+                            self.bump();
+                        }
+                        let digits = &self.src[start..self.pos];
+                        self.bump();
+                        let c = parse_uint(digits, 16)
+                            .and_then(|n| u32::try_from(n).ok())
+                            .and_then(char::from_u32);
+                        match c {
+                            Some(c) => {
+                                let mut buf = [0; 4];
+                                bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                            }
+                            None => return self.error("malformed unicode escape"),
+                        }
+                    }
+                    Some(hi) => {
+                        let lo = self.bump();
+                        match (hi.to_digit(16), lo.and_then(|c| c.to_digit(16))) {
+                            (Some(hi), Some(lo)) => bytes.push((hi * 16 + lo) as u8),
+                            _ => return self.error("malformed escape"),
+                        }
+                    }
+                    None => return self.error("unterminated string"),
+                },
+                Some(c) if (c as u32) < 0x20 || c == '\u{7f}' => {
+                    return self.error("control character in string")
+                }
+                Some(c) => {
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+            }
+        }
+    }
+
+    fn tokens(mut self) -> Result<Vec<Token<'a>>> {
+        let mut tokens = Vec::new();
+        loop {
+            self.skip_trivia()?;
+            let (line, col) = (self.line, self.col);
+            let kind = match self.peek_char() {
+                None => return Ok(tokens),
+                Some('(') => {
+                    self.bump();
+                    TokenKind::LParen
+                }
+                Some(')') => {
+                    self.bump();
+                    TokenKind::RParen
+                }
+                Some('"') => {
+                    self.bump();
+                    TokenKind::Str(self.string()?)
+                }
+                Some(c) if is_idchar(c) => {
+                    let start = self.pos;
+                    while matches!(self.peek_char(), Some(c) if is_idchar(c)) {
+                        self.bump();
+                    }
+                    let text = &self.src[start..self.pos];
+                    match text.strip_prefix('$') {
+                        Some("") => return self.error("empty identifier"),
+                        Some(id) => TokenKind::Id(id),
+                        None => TokenKind::Atom(text),
+                    }
+                }
+                Some(c) => return self.error(format!("unexpected character {:?}", c)),
+            };
+            tokens.push(Token { kind, line, col });
+        }
+    }
+}
+
+/// Whether `text` is a run of digits with optional single `_` separators.
+fn is_digits(text: &str, radix: u32) -> bool {
+    !text.is_empty()
+        && !text.starts_with('_')
+        && !text.ends_with('_')
+        && !text.contains("__")
+        && text.chars().all(|c| c == '_' || c.is_digit(radix))
+}
+
+/// Parses digits with optional `_` separators in the given radix.
+fn parse_uint(text: &str, radix: u32) -> Option<u64> {
+    if !is_digits(text, radix) {
+        return None;
+    }
+    let mut value: u64 = 0;
+    for c in text.chars().filter(|c| *c != '_') {
+        let digit = c.to_digit(radix)?;
+        value = value
+            .checked_mul(u64::from(radix))?
+            .checked_add(u64::from(digit))?;
+    }
+    Some(value)
+}
+
+fn split_sign(text: &str) -> (bool, &str) {
+    match text.as_bytes().first() {
+        Some(b'-') => (true, &text[1..]),
+        Some(b'+') => (false, &text[1..]),
+        _ => (false, text),
+    }
+}
+
+/// Unsigned magnitude of a decimal or hexadecimal integer literal.
+fn parse_nat(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => parse_uint(hex, 16),
+        None => parse_uint(text, 10),
+    }
+}
+
+pub(crate) fn parse_u32(text: &str) -> Option<u32> {
+    if text.starts_with(['+', '-']) {
+        return None;
+    }
+    parse_nat(text).and_then(|n| u32::try_from(n).ok())
+}
+
+pub(crate) fn parse_i32(text: &str) -> Option<i32> {
+    let (negative, digits) = split_sign(text);
+    let magnitude = parse_nat(digits)?;
+    if negative {
+        if magnitude > 1 << 31 {
+            return None;
+        }
+        Some((magnitude as i64).wrapping_neg() as i32)
+    } else {
+        u32::try_from(magnitude).ok().map(|n| n as i32)
+    }
+}
+
+pub(crate) fn parse_i64(text: &str) -> Option<i64> {
+    let (negative, digits) = split_sign(text);
+    let magnitude = parse_nat(digits)?;
+    if negative {
+        if magnitude > 1 << 63 {
+            return None;
+        }
+        Some((magnitude as i64).wrapping_neg())
+    } else {
+        Some(magnitude as i64)
+    }
+}
+
+/// Bit pattern of a float literal for a format with the given number of
+/// explicit mantissa bits and exponent bits.
+fn parse_float_bits(text: &str, mant_bits: u32, exp_bits: u32) -> Option<u64> {
+    let (negative, body) = split_sign(text);
+    let sign = u64::from(negative) << (mant_bits + exp_bits);
+    let exp_mask = ((1u64 << exp_bits) - 1) << mant_bits;
+    if body == "inf" {
+        return Some(sign | exp_mask);
+    }
+    if body == "nan" {
+        return Some(sign | exp_mask | 1 << (mant_bits - 1));
+    }
+    if let Some(payload) = body.strip_prefix("nan:0x") {
+        let payload = parse_uint(payload, 16)?;
+        if payload == 0 || payload >= 1 << mant_bits {
+            return None;
+        }
+        return Some(sign | exp_mask | payload);
+    }
+    let magnitude = match body.strip_prefix("0x") {
+        Some(hex) => hex_float_bits(hex, mant_bits, exp_bits)?,
+        None => {
+            // Rust's parser rounds correctly, but accepts a different syntax.
+            let valid = body.starts_with(|c: char| c.is_ascii_digit())
+                && body
+                    .split(['.', 'e', 'E', '+', '-'])
+                    .all(|part| part.is_empty() || is_digits(part, 10));
+            if !valid {
+                return None;
+            }
+            let digits: String = body.chars().filter(|c| *c != '_').collect();
+            let (bits, infinite) = if mant_bits == 23 {
+                let value = digits.parse::<f32>().ok()?;
+                (u64::from(value.to_bits()), value.is_infinite())
+            } else {
+                let value = digits.parse::<f64>().ok()?;
+                (value.to_bits(), value.is_infinite())
+            };
+            if infinite {
+                return None;
+            }
+            bits
+        }
+    };
+    Some(sign | magnitude)
+}
+
+fn hex_float_bits(text: &str, mant_bits: u32, exp_bits: u32) -> Option<u64> {
+    let (mantissa, exponent) = match text.find(['p', 'P']) {
+        Some(i) => (&text[..i], Some(&text[i + 1..])),
+        None => (text, None),
+    };
+    let (int_part, frac_part) = match mantissa.find('.') {
+        Some(i) => (&mantissa[..i], &mantissa[i + 1..]),
+        None => (mantissa, ""),
+    };
+    if !is_digits(int_part, 16) || !frac_part.is_empty() && !is_digits(frac_part, 16) {
+        return None;
+    }
+    let mut exp: i64 = match exponent {
+        Some(e) => {
+            let (negative, digits) = split_sign(e);
+            if !is_digits(digits, 10) {
+                return None;
+            }
+            // Saturate huge exponents, they overflow or underflow anyway.
+            let magnitude = parse_uint(digits, 10).map_or(1 << 32, |n| n.min(1 << 32) as i64);
+            if negative {
+                -magnitude
+            } else {
+                magnitude
+            }
+        }
+        None => 0,
+    };
+
+    // Accumulate up to 124 significant bits, remembering whether any
+    // nonzero digits were dropped for rounding.
+    let mut m: u128 = 0;
+    let mut sticky = false;
+    for (c, is_frac) in int_part
+        .chars()
+        .map(|c| (c, false))
+        .chain(frac_part.chars().map(|c| (c, true)))
+    {
+        let digit = match c.to_digit(16) {
+            Some(d) => d,
+            None => continue,
+        };
+        if m >> 120 == 0 {
+            m = m << 4 | u128::from(digit);
+            if is_frac {
+                exp -= 4;
+            }
+        } else {
+            sticky |= digit != 0;
+            if !is_frac {
+                exp += 4;
+            }
+        }
+    }
+    if m == 0 {
+        return Some(0);
+    }
+
+    let bias = (1i64 << (exp_bits - 1)) - 1;
+    let emin = 1 - bias;
+    let top = 127 - i64::from(m.leading_zeros());
+    let e = top + exp;
+    let mut scale = e.max(emin) - i64::from(mant_bits);
+    let shift = scale - exp;
+    let mut q = if shift <= 0 {
+        m << (-shift) as u32
+    } else if shift >= 128 {
+        0
+    } else {
+        let shift = shift as u32;
+        let dropped = m & ((1u128 << shift) - 1);
+        let half = 1u128 << (shift - 1);
+        let mut q = m >> shift;
+        if dropped > half || dropped == half && (sticky || q & 1 == 1) {
+            q += 1;
+        }
+        q
+    };
+    if q >> (mant_bits + 1) != 0 {
+        q >>= 1;
+        scale += 1;
+    }
+    let implicit = 1u128 << mant_bits;
+    let bits = if q >= implicit {
+        let biased = scale + i64::from(mant_bits) + bias;
+        if biased >= (1 << exp_bits) - 1 {
+            return None;
+        }
+        (biased as u64) << mant_bits | (q - implicit) as u64
+    } else {
+        q as u64
+    };
+    Some(bits)
+}
+
+pub(crate) fn parse_f32(text: &str) -> Option<f32> {
+    parse_float_bits(text, 23, 8).map(|bits| f32::from_bits(bits as u32))
+}
+
+pub(crate) fn parse_f64(text: &str) -> Option<f64> {
+    parse_float_bits(text, 52, 11).map(f64::from_bits)
+}
+
+fn val_type(name: &str) -> Option<ValType> {
+    match name {
+        "i32" => Some(ValType::I32),
+        "i64" => Some(ValType::I64),
+        "f32" => Some(ValType::F32),
+        "f64" => Some(ValType::F64),
+        "funcref" => Some(ValType::FuncRef),
+        "externref" => Some(ValType::ExternRef),
+        _ => None,
+    }
+}
+
+fn ref_type(name: &str) -> Option<RefType> {
+    match name {
+        "funcref" => Some(RefType::Func),
+        "externref" => Some(RefType::Extern),
+        _ => None,
+    }
+}
+
+/// Index of the first of `types` equal to `ty`, which is added to the end
+/// if there is none.
+fn type_index(types: &mut Vec<FuncType>, ty: FuncType) -> usize {
+    match types.iter().position(|t| *t == ty) {
+        Some(index) => index,
+        None => {
+            types.push(ty);
+            types.len() - 1
+        }
+    }
+}
+
+/// Names visible while parsing a module.
+#[derive(Default)]
+struct Names {
+    funcs: HashMap<String, usize>,
+    tables: HashMap<String, usize>,
+    memories: HashMap<String, usize>,
+    globals: HashMap<String, usize>,
+    types: HashMap<String, usize>,
+    elems: HashMap<String, usize>,
+    data: HashMap<String, usize>,
+}
+
+/// Names visible while parsing a function body.
+struct FuncCtx<'n> {
+    module: &'n Names,
+    /// The module's types, which type uses may add to.
+    types: &'n mut Vec<FuncType>,
+    locals: HashMap<String, usize>,
+    /// Enclosing block labels, innermost last.
+    labels: Vec<Option<String>>,
+}
+
+pub(crate) struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+    /// Position reported for errors at the end of input.
+    end: (usize, usize),
+}
+
+impl<'a> Parser<'a> {
+    pub(crate) fn new(src: &'a str) -> Result<Self> {
+        let lexer = Lexer {
+            src,
+            pos: 0,
+            line: 1,
+            col: 1,
+        };
+        let tokens = lexer.tokens()?;
+        let end = src
+            .lines()
+            .enumerate()
+            .last()
+            .map_or((1, 1), |(i, line)| (i + 1, line.chars().count() + 1));
+        Ok(Parser {
+            tokens,
+            pos: 0,
+            end,
+        })
+    }
+
+    pub(crate) fn peek(&self) -> Option<&Token<'a>> {
+        self.tokens.get(self.pos)
+    }
+
+    /// The cursor, for returning to with `rewind`.
+    pub(crate) fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub(crate) fn rewind(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+
+    fn peek_at(&self, n: usize) -> Option<&TokenKind<'a>> {
+        self.tokens.get(self.pos + n).map(|t| &t.kind)
+    }
+
+    pub(crate) fn error<T>(&self, message: impl Into<String>) -> Result<T> {
+        Err(match self.peek() {
+            Some(token) => token.error(message),
+            None => ParseError {
+                line: self.end.0,
+                col: self.end.1,
+                message: message.into(),
+            },
+        })
+    }
+
+    fn next(&mut self) -> Result<TokenKind<'a>> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token.kind.clone())
+            }
+            None => self.error("unexpected end of input"),
+        }
+    }
+
+    pub(crate) fn lparen(&mut self) -> Result<()> {
+        match self.peek_at(0) {
+            Some(TokenKind::LParen) => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => self.error("expected '('"),
+        }
+    }
+
+    pub(crate) fn rparen(&mut self) -> Result<()> {
+        match self.peek_at(0) {
+            Some(TokenKind::RParen) => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => self.error("expected ')'"),
+        }
+    }
+
+    pub(crate) fn at_rparen(&self) -> bool {
+        matches!(self.peek_at(0), Some(TokenKind::RParen) | None)
+    }
+
+    pub(crate) fn peek_atom(&self) -> Option<&'a str> {
+        match self.peek_at(0) {
+            Some(TokenKind::Atom(s)) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The keyword of the s-expression starting at the cursor, if any.
+    pub(crate) fn peek_form(&self) -> Option<&'a str> {
+        match (self.peek_at(0), self.peek_at(1)) {
+            (Some(TokenKind::LParen), Some(TokenKind::Atom(s))) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn atom(&mut self) -> Result<&'a str> {
+        match self.peek_at(0) {
+            Some(TokenKind::Atom(s)) => {
+                let s = *s;
+                self.pos += 1;
+                Ok(s)
+            }
+            _ => self.error("expected keyword"),
+        }
+    }
+
+    pub(crate) fn keyword(&mut self, keyword: &str) -> Result<()> {
+        match self.peek_atom() {
+            Some(s) if s == keyword => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => self.error(format!("expected '{}'", keyword)),
+        }
+    }
+
+    /// Consumes `(keyword` if the cursor is at such a form.
+    pub(crate) fn form(&mut self, keyword: &str) -> bool {
+        if self.peek_form() == Some(keyword) {
+            self.pos += 2;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn id(&mut self) -> Option<&'a str> {
+        match self.peek_at(0) {
+            Some(TokenKind::Id(s)) => {
+                let s = *s;
+                self.pos += 1;
+                Some(s)
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn string(&mut self) -> Result<Vec<u8>> {
+        match self.peek_at(0) {
+            Some(TokenKind::Str(bytes)) => {
+                let bytes = bytes.clone();
+                self.pos += 1;
+                Ok(bytes)
+            }
+            _ => self.error("expected string"),
+        }
+    }
+
+    pub(crate) fn name(&mut self) -> Result<String> {
+        let bytes = self.string()?;
+        match String::from_utf8(bytes) {
+            Ok(name) => Ok(name),
+            Err(_) => {
+                self.pos -= 1;
+                self.error("malformed UTF-8 encoding")
+            }
+        }
+    }
+
+    fn number<T>(&mut self, what: &str, parse: impl FnOnce(&str) -> Option<T>) -> Result<T> {
+        match self.peek_atom().and_then(parse) {
+            Some(n) => {
+                self.pos += 1;
+                Ok(n)
+            }
+            None => self.error(format!("expected {}", what)),
+        }
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        self.number("unsigned integer", parse_u32)
+    }
+
+    pub(crate) fn i32(&mut self) -> Result<i32> {
+        self.number("i32 literal", parse_i32)
+    }
+
+    pub(crate) fn i64(&mut self) -> Result<i64> {
+        self.number("i64 literal", parse_i64)
+    }
+
+    pub(crate) fn f32(&mut self) -> Result<f32> {
+        self.number("f32 literal", parse_f32)
+    }
+
+    pub(crate) fn f64(&mut self) -> Result<f64> {
+        self.number("f64 literal", parse_f64)
+    }
+
+    fn val_type(&mut self) -> Result<ValType> {
+        match self.peek_atom().and_then(val_type) {
+            Some(ty) => {
+                self.pos += 1;
+                Ok(ty)
+            }
+            None => self.error("expected value type"),
+        }
+    }
+
+    fn ref_type(&mut self) -> Result<RefType> {
+        match self.peek_atom().and_then(ref_type) {
+            Some(ty) => {
+                self.pos += 1;
+                Ok(ty)
+            }
+            None => self.error("expected reference type"),
+        }
+    }
+
+    /// The `func` or `extern` of `ref.null`.
+    fn heap_type(&mut self) -> Result<RefType> {
+        let ty = match self.peek_atom() {
+            Some("func") => RefType::Func,
+            Some("extern") => RefType::Extern,
+            _ => return self.error("expected heap type"),
+        };
+        self.pos += 1;
+        Ok(ty)
+    }
+
+    /// A numeric index or a `$name` looked up in `names`.
+    fn index(&mut self, names: &HashMap<String, usize>, what: &str) -> Result<usize> {
+        if let Some(id) = self.id() {
+            return match names.get(id) {
+                Some(index) => Ok(*index),
+                None => {
+                    self.pos -= 1;
+                    self.error(format!("unknown {} ${}", what, id))
+                }
+            };
+        }
+        Ok(self.u32()? as usize)
+    }
+
+    /// Whether the s-expression starting at the cursor directly contains a
+    /// `(keyword ...)` form.
+    fn contains_form(&self, keyword: &str) -> bool {
+        let mut depth = 0;
+        for (pos, token) in self.tokens.iter().enumerate().skip(self.pos) {
+            match token.kind {
+                TokenKind::LParen => {
+                    let kind = self.tokens.get(pos + 1).map(|t| &t.kind);
+                    if depth == 1 && kind == Some(&TokenKind::Atom(keyword)) {
+                        return true;
+                    }
+                    depth += 1;
+                }
+                TokenKind::RParen if depth == 1 => return false,
+                TokenKind::RParen => depth -= 1,
+                _ => {}
+            }
+        }
+        false
+    }
+
+    /// Whether the token `n` ahead of the cursor is an identifier or an
+    /// unsigned integer, as a possibly optional index would be.
+    fn at_index(&self, n: usize) -> bool {
+        match self.peek_at(n) {
+            Some(TokenKind::Id(_)) => true,
+            Some(TokenKind::Atom(atom)) => is_digits(atom, 10),
+            _ => false,
+        }
+    }
+
+    /// Skips over a complete s-expression.
+    pub(crate) fn skip_form(&mut self) -> Result<()> {
+        self.lparen()?;
+        let mut depth = 1;
+        while depth > 0 {
+            match self.next()? {
+                TokenKind::LParen => depth += 1,
+                TokenKind::RParen => depth -= 1,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses a `(module ...)` form, or a bare sequence of module fields.
+    pub(crate) fn module(&mut self) -> Result<Module> {
+        let wrapped = self.form("module");
+        if wrapped {
+            self.id();
+        }
+        let mut module = Module::default();
+        let start = self.pos;
+        let names = self.collect_names(&mut module)?;
+        self.pos = start;
+
+        while self.peek_at(0) == Some(&TokenKind::LParen) {
+            match self.peek_form() {
+                Some("type") => self.skip_form()?,
+                Some("import") => self.import(&names, &mut module)?,
+                Some("func") => self.func(&names, &mut module)?,
+                Some("table") => self.table(&names, &mut module)?,
+                Some("memory") => self.memory(&mut module)?,
+                Some("global") => self.global(&names, &mut module)?,
+                Some("export") => {
+                    let export = self.export(&names)?;
+                    module.exports.push(export);
+                }
+                Some("elem") => {
+                    let elem = self.elem(&names)?;
+                    module.elems.push(elem);
+                }
+                Some("data") => {
+                    let data = self.data(&names)?;
+                    module.data.push(data);
+                }
+                Some("start") => {
+                    self.form("start");
+                    if module.start.is_some() {
+                        return self.error("multiple start sections");
+                    }
+                    module.start = Some(self.index(&names.funcs, "function")?);
+                    self.rparen()?;
+                }
+                Some(field) => return self.error(format!("unsupported module field '{}'", field)),
+                None => return self.error("expected module field"),
+            }
+        }
+        if wrapped {
+            self.rparen()?;
+        }
+        Ok(module)
+    }
+
+    /// First pass over the module fields, assigning indices to names so that
+    /// fields can refer to ones defined later. Type definitions are parsed
+    /// here, so that the implicit types of later type uses follow them.
+    fn collect_names(&mut self, module: &mut Module) -> Result<Names> {
+        let mut names = Names::default();
+        let mut counts = (0, 0, 0, 0, 0, 0, 0);
+        while self.peek_at(0) == Some(&TokenKind::LParen) {
+            let field = self.pos;
+            // Where the field's identifier would be.
+            let mut id_pos = 2;
+            let (map, count) = match self.peek_form() {
+                Some("func") => (&mut names.funcs, &mut counts.0),
+                Some("table") => (&mut names.tables, &mut counts.1),
+                Some("memory") => (&mut names.memories, &mut counts.2),
+                Some("global") => (&mut names.globals, &mut counts.3),
+                Some("type") => (&mut names.types, &mut counts.4),
+                Some("elem") => (&mut names.elems, &mut counts.5),
+                Some("data") => (&mut names.data, &mut counts.6),
+                // `(import "module" "name" (func $id ...))`, and likewise for
+                // the other kinds of import.
+                Some("import") if self.peek_at(4) == Some(&TokenKind::LParen) => {
+                    id_pos = 6;
+                    match self.peek_at(5) {
+                        Some(TokenKind::Atom("func")) => (&mut names.funcs, &mut counts.0),
+                        Some(TokenKind::Atom("table")) => (&mut names.tables, &mut counts.1),
+                        Some(TokenKind::Atom("memory")) => (&mut names.memories, &mut counts.2),
+                        Some(TokenKind::Atom("global")) => (&mut names.globals, &mut counts.3),
+                        _ => {
+                            self.skip_form()?;
+                            continue;
+                        }
+                    }
+                }
+                _ => {
+                    self.skip_form()?;
+                    continue;
+                }
+            };
+            self.pos += id_pos;
+            if let Some(id) = self.id() {
+                if map.insert(id.to_string(), *count).is_some() {
+                    self.pos -= 1;
+                    return self.error(format!("duplicate identifier ${}", id));
+                }
+            }
+            *count += 1;
+            self.pos = field;
+            // Segments given inline in a table or memory are numbered too.
+            match self.peek_form() {
+                Some("table") if self.contains_form("elem") => counts.5 += 1,
+                Some("memory") if self.contains_form("data") => counts.6 += 1,
+                _ => {}
+            }
+            if self.peek_form() == Some("type") {
+                module.types.push(self.type_def()?);
+            } else {
+                self.skip_form()?;
+            }
+        }
+        Ok(names)
+    }
+
+    fn type_def(&mut self) -> Result<FuncType> {
+        self.form("type");
+        self.id();
+        self.lparen()?;
+        self.keyword("func")?;
+        let mut locals = HashMap::new();
+        let params = self.params(&mut locals)?;
+        let results = self.results()?;
+        self.rparen()?;
+        self.rparen()?;
+        Ok(FuncType { params, results })
+    }
+
+    /// `(type idx)? (param ...)* (result ...)*`, returning the index of the
+    /// type used. Without an explicit index, the first matching type is
+    /// used, or one is added to the end of `types`.
+    fn type_use(
+        &mut self,
+        names: &Names,
+        types: &mut Vec<FuncType>,
+        locals: &mut HashMap<String, usize>,
+    ) -> Result<usize> {
+        let declared = if self.form("type") {
+            let index = self.index(&names.types, "type")?;
+            self.rparen()?;
+            if index >= types.len() {
+                return self.error("unknown type");
+            }
+            Some(index)
+        } else {
+            None
+        };
+        let ty = FuncType {
+            params: self.params(locals)?,
+            results: self.results()?,
+        };
+        match declared {
+            Some(index) if ty == FuncType::default() || ty == types[index] => Ok(index),
+            Some(_) => self.error("inconsistent type"),
+            None => Ok(type_index(types, ty)),
+        }
+    }
+
+    /// `(param ...)*`, recording the names of named parameters.
+    fn params(&mut self, names: &mut HashMap<String, usize>) -> Result<Vec<ValType>> {
+        let mut params = Vec::new();
+        while self.form("param") {
+            if let Some(id) = self.id() {
+                if names.insert(id.to_string(), params.len()).is_some() {
+                    self.pos -= 1;
+                    return self.error(format!("duplicate local ${}", id));
+                }
+                params.push(self.val_type()?);
+            } else {
+                while !self.at_rparen() {
+                    params.push(self.val_type()?);
+                }
+            }
+            self.rparen()?;
+        }
+        Ok(params)
+    }
+
+    fn results(&mut self) -> Result<Vec<ValType>> {
+        let mut results = Vec::new();
+        while self.form("result") {
+            while !self.at_rparen() {
+                results.push(self.val_type()?);
+            }
+            self.rparen()?;
+        }
+        Ok(results)
+    }
+
+    /// Inline `(export "name")` abbreviations on a definition.
+    fn inline_exports(&mut self) -> Result<Vec<String>> {
+        let mut exports = Vec::new();
+        while self.form("export") {
+            exports.push(self.name()?);
+            self.rparen()?;
+        }
+        Ok(exports)
+    }
+
+    fn func(&mut self, names: &Names, module: &mut Module) -> Result<()> {
+        self.form("func");
+        self.id();
+        let index = module.func_count();
+        for name in self.inline_exports()? {
+            module.exports.push(Export {
+                name,
+                desc: ExportDesc::Func(index),
+            });
+        }
+        if self.form("import") {
+            let module_name = self.name()?;
+            let name = self.name()?;
+            self.rparen()?;
+            return self.func_import(names, module, module_name, name);
+        }
+
+        let mut locals = HashMap::new();
+        let ty = self.type_use(names, &mut module.types, &mut locals)?;
+        let params = module.types[ty].params.len();
+        let mut declared = Vec::new();
+        while self.form("local") {
+            if let Some(id) = self.id() {
+                let index = params + declared.len();
+                if locals.insert(id.to_string(), index).is_some() {
+                    self.pos -= 1;
+                    return self.error(format!("duplicate local ${}", id));
+                }
+                declared.push(self.val_type()?);
+            } else {
+                while !self.at_rparen() {
+                    declared.push(self.val_type()?);
+                }
+            }
+            self.rparen()?;
+        }
+
+        let mut ctx = FuncCtx {
+            module: names,
+            types: &mut module.types,
+            locals,
+            labels: Vec::new(),
+        };
+        let mut code = Vec::new();
+        self.instrs(&mut ctx, &mut code)?;
+        if !ctx.labels.is_empty() {
+            return self.error("unclosed block");
+        }
+        self.rparen()?;
+        module.functions.push(Function::new(ty, declared, code));
+        Ok(())
+    }
+
+    /// An `(import "module" "name" (kind $id? type))` field.
+    fn import(&mut self, names: &Names, module: &mut Module) -> Result<()> {
+        self.form("import");
+        let module_name = self.name()?;
+        let name = self.name()?;
+        match self.peek_form() {
+            Some("func") => {
+                self.form("func");
+                self.id();
+                self.func_import(names, module, module_name, name)?;
+            }
+            Some(kind @ ("table" | "memory" | "global")) => {
+                self.form(kind);
+                self.id();
+                self.def_import(kind, module, module_name, name)?;
+            }
+            _ => return self.error("expected import kind"),
+        }
+        self.rparen()
+    }
+
+    /// The type of an imported table, memory or global, up to the closing
+    /// paren of its `kind` form.
+    fn def_import(
+        &mut self,
+        kind: &str,
+        module: &mut Module,
+        module_name: String,
+        name: String,
+    ) -> Result<()> {
+        let desc = match kind {
+            "table" if !module.tables.is_empty() => return self.error("import after table"),
+            "table" => ImportDesc::Table(self.table_type()?),
+            "memory" if module.has_memory() => {
+                return self.error("multiple memories are not supported")
+            }
+            "memory" => ImportDesc::Memory(self.memory_type()?),
+            "global" if !module.globals.is_empty() => return self.error("import after global"),
+            _ => ImportDesc::Global(self.global_type()?),
+        };
+        self.rparen()?;
+        module.imports.push(Import {
+            module: module_name,
+            name,
+            desc,
+        });
+        Ok(())
+    }
+
+    /// `(import "module" "name")` abbreviating the import of a table,
+    /// memory or global being defined.
+    fn inline_import(&mut self, kind: &str, module: &mut Module) -> Result<bool> {
+        if !self.form("import") {
+            return Ok(false);
+        }
+        let module_name = self.name()?;
+        let name = self.name()?;
+        self.rparen()?;
+        self.def_import(kind, module, module_name, name)?;
+        Ok(true)
+    }
+
+    /// Table limits followed by the element type.
+    fn table_type(&mut self) -> Result<TableType> {
+        let min = self.u32()?;
+        let max = if self.peek_atom().and_then(ref_type).is_some() {
+            None
+        } else {
+            Some(self.u32()?)
+        };
+        let elem = self.ref_type()?;
+        Ok(TableType {
+            elem,
+            limits: Limits { min, max },
+        })
+    }
+
+    fn memory_type(&mut self) -> Result<Limits> {
+        let min = self.u32()?;
+        let max = if self.at_rparen() {
+            None
+        } else {
+            Some(self.u32()?)
+        };
+        Ok(Limits { min, max })
+    }
+
+    /// A value type, wrapped in `(mut ...)` if the global is mutable.
+    fn global_type(&mut self) -> Result<GlobalType> {
+        if self.form("mut") {
+            let ty = self.val_type()?;
+            self.rparen()?;
+            Ok(GlobalType { ty, mutable: true })
+        } else {
+            Ok(GlobalType {
+                ty: self.val_type()?,
+                mutable: false,
+            })
+        }
+    }
+
+    /// The type of an imported function, up to the closing paren of its
+    /// `func` form.
+    fn func_import(
+        &mut self,
+        names: &Names,
+        module: &mut Module,
+        module_name: String,
+        name: String,
+    ) -> Result<()> {
+        if !module.functions.is_empty() {
+            return self.error("import after function");
+        }
+        let ty = self.type_use(names, &mut module.types, &mut HashMap::new())?;
+        self.rparen()?;
+        module.imports.push(Import {
+            module: module_name,
+            name,
+            desc: ImportDesc::Func(ty),
+        });
+        Ok(())
+    }
+
+    /// A table given its type or, abbreviated, its element type and the
+    /// elements to fill it with.
+    fn table(&mut self, names: &Names, module: &mut Module) -> Result<()> {
+        self.form("table");
+        self.id();
+        let index = module.table_count();
+        for name in self.inline_exports()? {
+            module.exports.push(Export {
+                name,
+                desc: ExportDesc::Table(index),
+            });
+        }
+        if self.inline_import("table", module)? {
+            return Ok(());
+        }
+        if let Some(ty) = self.peek_atom().and_then(ref_type) {
+            self.pos += 1;
+            if !self.form("elem") {
+                return self.error("expected table limits");
+            }
+            // Initializers are written out, otherwise the elements are
+            // function indices.
+            let init = if self.peek_at(0) == Some(&TokenKind::LParen) {
+                self.elem_exprs(names)?
+            } else {
+                self.func_refs(names)?
+            };
+            self.rparen()?;
+            let len = init.len() as u32;
+            module.tables.push(TableType {
+                elem: ty,
+                limits: Limits {
+                    min: len,
+                    max: Some(len),
+                },
+            });
+            module.elems.push(Elem {
+                mode: ElemMode::Active {
+                    table: index,
+                    offset: ConstExpr::Const(Value::I32(0)),
+                },
+                ty,
+                init,
+            });
+        } else {
+            let ty = self.table_type()?;
+            module.tables.push(ty);
+        }
+        self.rparen()
+    }
+
+    /// Function indices, as references to the functions.
+    fn func_refs(&mut self, names: &Names) -> Result<Vec<ConstExpr>> {
+        let mut init = Vec::new();
+        while !self.at_rparen() {
+            init.push(ConstExpr::RefFunc(self.index(&names.funcs, "function")?));
+        }
+        Ok(init)
+    }
+
+    /// Element initializers, each either `(item expr)` or abbreviated to a
+    /// single folded instruction.
+    fn elem_exprs(&mut self, names: &Names) -> Result<Vec<ConstExpr>> {
+        let mut init = Vec::new();
+        while !self.at_rparen() {
+            if self.form("item") {
+                init.push(self.const_expr(names, false)?);
+                self.rparen()?;
+            } else {
+                init.push(self.const_expr(names, true)?);
+            }
+        }
+        Ok(init)
+    }
+
+    /// The elements of a segment: `func` and function indices, or a
+    /// reference type and initializers. The original syntax of an active
+    /// segment leaves out `func`.
+    fn elem_list(&mut self, names: &Names) -> Result<(RefType, Vec<ConstExpr>)> {
+        if let Some(ty) = self.peek_atom().and_then(ref_type) {
+            self.pos += 1;
+            return Ok((ty, self.elem_exprs(names)?));
+        }
+        if self.peek_atom() == Some("func") {
+            self.pos += 1;
+        }
+        Ok((RefType::Func, self.func_refs(names)?))
+    }
+
+    fn memory(&mut self, module: &mut Module) -> Result<()> {
+        self.form("memory");
+        self.id();
+        if module.has_memory() {
+            return self.error("multiple memories are not supported");
+        }
+        for name in self.inline_exports()? {
+            module.exports.push(Export {
+                name,
+                desc: ExportDesc::Memory(0),
+            });
+        }
+        if self.inline_import("memory", module)? {
+            return Ok(());
+        }
+        if self.form("data") {
+            let init = self.data_string()?;
+            let pages = init.len().div_ceil(PAGE_SIZE) as u32;
+            module.memory = Some(Limits {
+                min: pages,
+                max: Some(pages),
+            });
+            module.data.push(Data {
+                offset: Some(ConstExpr::Const(Value::I32(0))),
+                init,
+            });
+        } else {
+            module.memory = Some(self.memory_type()?);
+        }
+        self.rparen()
+    }
+
+    fn global(&mut self, names: &Names, module: &mut Module) -> Result<()> {
+        self.form("global");
+        self.id();
+        let index = module.global_count();
+        for name in self.inline_exports()? {
+            module.exports.push(Export {
+                name,
+                desc: ExportDesc::Global(index),
+            });
+        }
+        if self.inline_import("global", module)? {
+            return Ok(());
+        }
+        let ty = self.global_type()?;
+        let init = self.const_expr(names, false)?;
+        self.rparen()?;
+        module.globals.push(Global { ty, init });
+        Ok(())
+    }
+
+    /// An initializer, which must be a single constant instruction. If
+    /// `folded`, it must be written as one folded instruction, as in the
+    /// abbreviated offset of a data segment.
+    fn const_expr(&mut self, names: &Names, folded: bool) -> Result<ConstExpr> {
+        let mut ctx = FuncCtx {
+            module: names,
+            types: &mut Vec::new(),
+            locals: HashMap::new(),
+            labels: Vec::new(),
+        };
+        let mut expr = Vec::new();
+        if folded {
+            self.folded(&mut ctx, &mut expr)?;
+        } else {
+            self.instrs(&mut ctx, &mut expr)?;
+        }
+        match expr.as_slice() {
+            [Instruction::I32Const(val)] => Ok(ConstExpr::Const(Value::I32(*val))),
+            [Instruction::I64Const(val)] => Ok(ConstExpr::Const(Value::I64(*val))),
+            [Instruction::F32Const(val)] => Ok(ConstExpr::Const(Value::F32(*val))),
+            [Instruction::F64Const(val)] => Ok(ConstExpr::Const(Value::F64(*val))),
+            [Instruction::GlobalGet(index)] => Ok(ConstExpr::GlobalGet(*index)),
+            [Instruction::RefNull(ty)] => Ok(ConstExpr::RefNull(*ty)),
+            [Instruction::RefFunc(index)] => Ok(ConstExpr::RefFunc(*index)),
+            _ => self.error("constant expression required"),
+        }
+    }
+
+    fn export(&mut self, names: &Names) -> Result<Export> {
+        self.form("export");
+        let name = self.name()?;
+        self.lparen()?;
+        let desc = match self.atom()? {
+            "func" => ExportDesc::Func(self.index(&names.funcs, "function")?),
+            "table" => ExportDesc::Table(self.index(&names.tables, "table")?),
+            "memory" => ExportDesc::Memory(self.index(&names.memories, "memory")?),
+            "global" => ExportDesc::Global(self.index(&names.globals, "global")?),
+            kind => {
+                self.pos -= 1;
+                return self.error(format!("unsupported export kind '{}'", kind));
+            }
+        };
+        self.rparen()?;
+        self.rparen()?;
+        Ok(Export { name, desc })
+    }
+
+    /// An element segment. The table of an active one may be given as
+    /// `(table idx)`, or as a bare index as in the original syntax, where a
+    /// lone `$name` names the table rather than the segment.
+    fn elem(&mut self, names: &Names) -> Result<Elem> {
+        self.form("elem");
+        let id = self.id();
+        let passive = match self.peek_atom() {
+            Some("declare") => {
+                self.pos += 1;
+                Some(ElemMode::Declarative)
+            }
+            Some("func" | "funcref" | "externref") => Some(ElemMode::Passive),
+            _ => None,
+        };
+        if let Some(mode) = passive {
+            let ty = self.peek_atom().and_then(ref_type);
+            if ty.is_none() && self.peek_atom() != Some("func") {
+                return self.error("expected 'func' or reference type");
+            }
+            let (ty, init) = self.elem_list(names)?;
+            self.rparen()?;
+            return Ok(Elem { mode, ty, init });
+        }
+        let table = if self.form("table") {
+            let table = self.index(&names.tables, "table")?;
+            self.rparen()?;
+            table
+        } else if self.peek_atom().is_some() {
+            self.index(&names.tables, "table")?
+        } else {
+            id.and_then(|id| names.tables.get(id)).copied().unwrap_or(0)
+        };
+        let offset = if self.form("offset") {
+            let offset = self.const_expr(names, false)?;
+            self.rparen()?;
+            offset
+        } else {
+            self.const_expr(names, true)?
+        };
+        let (ty, init) = self.elem_list(names)?;
+        self.rparen()?;
+        Ok(Elem {
+            mode: ElemMode::Active { table, offset },
+            ty,
+            init,
+        })
+    }
+
+    fn data(&mut self, names: &Names) -> Result<Data> {
+        self.form("data");
+        self.id();
+        if let Some(TokenKind::Str(_)) | Some(TokenKind::RParen) = self.peek_at(0) {
+            let init = self.data_string()?;
+            return Ok(Data { offset: None, init });
+        }
+        if self.form("memory") {
+            if self.index(&names.memories, "memory")? != 0 {
+                return self.error("multiple memories are not supported");
+            }
+            self.rparen()?;
+        }
+        let offset = if self.form("offset") {
+            let offset = self.const_expr(names, false)?;
+            self.rparen()?;
+            offset
+        } else {
+            self.const_expr(names, true)?
+        };
+        let init = self.data_string()?;
+        Ok(Data {
+            offset: Some(offset),
+            init,
+        })
+    }
+
+    /// The strings making up a data segment, up to the closing paren.
+    fn data_string(&mut self) -> Result<Vec<u8>> {
+        let mut init = Vec::new();
+        while !self.at_rparen() {
+            init.extend(self.string()?);
+        }
+        self.rparen()?;
+        Ok(init)
+    }
+
+    /// A sequence of plain and folded instructions, up to a closing paren.
+    fn instrs(&mut self, ctx: &mut FuncCtx, code: &mut Vec<Instruction>) -> Result<()> {
+        loop {
+            match self.peek_at(0) {
+                Some(TokenKind::LParen) => self.folded(ctx, code)?,
+                Some(TokenKind::Atom(_)) => {
+                    let instruction = self.plain(ctx)?;
+                    code.push(instruction);
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// `(op immediates operands*)`, emitted with the operands first, or a
+    /// folded `block`, `loop` or `if`.
+    fn folded(&mut self, ctx: &mut FuncCtx, code: &mut Vec<Instruction>) -> Result<()> {
+        self.lparen()?;
+        match self.peek_atom() {
+            Some("block") | Some("loop") => {
+                let instruction = self.plain(ctx)?;
+                code.push(instruction);
+                self.instrs(ctx, code)?;
+                ctx.labels.pop();
+                code.push(Instruction::End);
+            }
+            Some("if") => {
+                let instruction = self.plain(ctx)?;
+                // The condition runs before the block opens, so branches in
+                // it can't target the `if` itself.
+                let label = ctx.labels.pop().unwrap_or_default();
+                while self.peek_at(0) == Some(&TokenKind::LParen)
+                    && self.peek_form() != Some("then")
+                {
+                    self.folded(ctx, code)?;
+                }
+                ctx.labels.push(label);
+                code.push(instruction);
+                if !self.form("then") {
+                    return self.error("expected '(then'");
+                }
+                self.instrs(ctx, code)?;
+                self.rparen()?;
+                if self.form("else") {
+                    code.push(Instruction::Else);
+                    self.instrs(ctx, code)?;
+                    self.rparen()?;
+                }
+                ctx.labels.pop();
+                code.push(Instruction::End);
+            }
+            _ => {
+                let instruction = self.plain(ctx)?;
+                self.instrs(ctx, code)?;
+                code.push(instruction);
+            }
+        }
+        self.rparen()
+    }
+
+    /// An optional label and block type, opening a new block.
+    fn block(&mut self, ctx: &mut FuncCtx) -> Result<BlockType> {
+        let label = self.id().map(String::from);
+        let ty = match self.peek_form() {
+            Some("type") | Some("param") => {
+                BlockType::Type(self.type_use(ctx.module, ctx.types, &mut HashMap::new())?)
+            }
+            _ => {
+                let results = self.results()?;
+                match results.as_slice() {
+                    [] => BlockType::Empty,
+                    [ty] => BlockType::Value(*ty),
+                    _ => BlockType::Type(type_index(
+                        ctx.types,
+                        FuncType {
+                            params: vec![],
+                            results,
+                        },
+                    )),
+                }
+            }
+        };
+        ctx.labels.push(label);
+        Ok(ty)
+    }
+
+    /// The optional label repeated after `else` or `end`.
+    fn end_label(&mut self, ctx: &FuncCtx) -> Result<()> {
+        if let Some(id) = self.id() {
+            if ctx.labels.last().and_then(Option::as_deref) != Some(id) {
+                self.pos -= 1;
+                return self.error("mismatching label");
+            }
+        }
+        Ok(())
+    }
+
+    /// A branch target, as a relative depth or a `$label`.
+    fn label(&mut self, ctx: &FuncCtx) -> Result<usize> {
+        if let Some(id) = self.id() {
+            return match ctx
+                .labels
+                .iter()
+                .rev()
+                .position(|l| l.as_deref() == Some(id))
+            {
+                Some(depth) => Ok(depth),
+                None => {
+                    self.pos -= 1;
+                    self.error(format!("unknown label ${}", id))
+                }
+            };
+        }
+        Ok(self.u32()? as usize)
+    }
+
+    fn at_label(&self) -> bool {
+        match self.peek_at(0) {
+            Some(TokenKind::Id(_)) => true,
+            Some(TokenKind::Atom(s)) => parse_u32(s).is_some(),
+            _ => false,
+        }
+    }
+
+    /// Parses the optional `offset=` and `align=` of an access of `width`
+    /// bytes, which is aligned naturally by default.
+    fn memarg(&mut self, width: u32) -> Result<MemArg> {
+        let mut memarg = MemArg {
+            align: width.trailing_zeros(),
+            offset: 0,
+        };
+        if let Some(offset) = self.peek_atom().and_then(|s| s.strip_prefix("offset=")) {
+            memarg.offset = match parse_u32(offset) {
+                Some(offset) => offset,
+                None => return self.error("malformed memory offset"),
+            };
+            self.pos += 1;
+        }
+        if let Some(align) = self.peek_atom().and_then(|s| s.strip_prefix("align=")) {
+            memarg.align = match parse_u32(align) {
+                Some(align) if align.is_power_of_two() => align.trailing_zeros(),
+                _ => return self.error("alignment must be a power of two"),
+            };
+            self.pos += 1;
+        }
+        Ok(memarg)
+    }
+
+    /// The table an instruction operates on, table 0 if not given.
+    fn table_use(&mut self, names: &Names) -> Result<usize> {
+        if self.at_index(0) {
+            self.index(&names.tables, "table")
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn plain(&mut self, ctx: &mut FuncCtx) -> Result<Instruction> {
+        let name = self.atom()?;
+        let instruction = match name {
+            "unreachable" => Instruction::Unreachable,
+            "nop" => Instruction::Nop,
+            "block" => Instruction::Block(self.block(ctx)?),
+            "loop" => Instruction::Loop(self.block(ctx)?),
+            "if" => Instruction::If(self.block(ctx)?),
+            "else" => {
+                self.end_label(ctx)?;
+                Instruction::Else
+            }
+            "end" => {
+                self.end_label(ctx)?;
+                if ctx.labels.pop().is_none() {
+                    self.pos -= 1;
+                    return self.error("unexpected 'end'");
+                }
+                Instruction::End
+            }
+            "br" => Instruction::Br(self.label(ctx)?),
+            "br_if" => Instruction::BrIf(self.label(ctx)?),
+            "br_table" => {
+                let mut table = vec![self.label(ctx)?];
+                while self.at_label() {
+                    table.push(self.label(ctx)?);
+                }
+                let default = table.pop().unwrap();
+                Instruction::BrTable(table, default)
+            }
+            "return" => Instruction::Return,
+            "drop" => Instruction::Drop,
+            "select" if self.peek_form() == Some("result") => {
+                Instruction::SelectTyped(self.results()?)
+            }
+            "select" => Instruction::Select,
+            "i32.const" => Instruction::I32Const(self.i32()?),
+            "i64.const" => Instruction::I64Const(self.i64()?),
+            "f32.const" => Instruction::F32Const(self.f32()?),
+            "f64.const" => Instruction::F64Const(self.f64()?),
+            "ref.null" => Instruction::RefNull(self.heap_type()?),
+            "ref.is_null" => Instruction::RefIsNull,
+            "ref.func" => Instruction::RefFunc(self.index(&ctx.module.funcs, "function")?),
+            "i32.eqz" => Instruction::I32Eqz,
+            "i32.eq" => Instruction::I32Eq,
+            "i32.ne" => Instruction::I32Ne,
+            "i32.lt_s" => Instruction::I32LtS,
+            "i32.lt_u" => Instruction::I32LtU,
+            "i32.gt_s" => Instruction::I32GtS,
+            "i32.gt_u" => Instruction::I32GtU,
+            "i32.le_s" => Instruction::I32LeS,
+            "i32.le_u" => Instruction::I32LeU,
+            "i32.ge_s" => Instruction::I32GeS,
+            "i32.ge_u" => Instruction::I32GeU,
+            "i64.eqz" => Instruction::I64Eqz,
+            "i64.eq" => Instruction::I64Eq,
+            "i64.ne" => Instruction::I64Ne,
+            "i64.lt_s" => Instruction::I64LtS,
+            "i64.lt_u" => Instruction::I64LtU,
+            "i64.gt_s" => Instruction::I64GtS,
+            "i64.gt_u" => Instruction::I64GtU,
+            "i64.le_s" => Instruction::I64LeS,
+            "i64.le_u" => Instruction::I64LeU,
+            "i64.ge_s" => Instruction::I64GeS,
+            "i64.ge_u" => Instruction::I64GeU,
+            "f32.eq" => Instruction::F32Eq,
+            "f32.ne" => Instruction::F32Ne,
+            "f32.lt" => Instruction::F32Lt,
+            "f32.gt" => Instruction::F32Gt,
+            "f32.le" => Instruction::F32Le,
+            "f32.ge" => Instruction::F32Ge,
+            "f64.eq" => Instruction::F64Eq,
+            "f64.ne" => Instruction::F64Ne,
+            "f64.lt" => Instruction::F64Lt,
+            "f64.gt" => Instruction::F64Gt,
+            "f64.le" => Instruction::F64Le,
+            "f64.ge" => Instruction::F64Ge,
+            "i32.clz" => Instruction::I32Clz,
+            "i32.ctz" => Instruction::I32Ctz,
+            "i32.popcnt" => Instruction::I32Popcnt,
+            "i32.add" => Instruction::I32Add,
+            "i32.sub" => Instruction::I32Sub,
+            "i32.mul" => Instruction::I32Mul,
+            "i32.div_s" => Instruction::I32DivS,
+            "i32.div_u" => Instruction::I32DivU,
+            "i32.rem_s" => Instruction::I32RemS,
+            "i32.rem_u" => Instruction::I32RemU,
+            "i32.and" => Instruction::I32And,
+            "i32.or" => Instruction::I32Or,
+            "i32.xor" => Instruction::I32Xor,
+            "i32.shl" => Instruction::I32Shl,
+            "i32.shr_s" => Instruction::I32ShrS,
+            "i32.shr_u" => Instruction::I32ShrU,
+            "i32.rotl" => Instruction::I32Rotl,
+            "i32.rotr" => Instruction::I32Rotr,
+            "i64.clz" => Instruction::I64Clz,
+            "i64.ctz" => Instruction::I64Ctz,
+            "i64.popcnt" => Instruction::I64Popcnt,
+            "i64.add" => Instruction::I64Add,
+            "i64.sub" => Instruction::I64Sub,
+            "i64.mul" => Instruction::I64Mul,
+            "i64.div_s" => Instruction::I64DivS,
+            "i64.div_u" => Instruction::I64DivU,
+            "i64.rem_s" => Instruction::I64RemS,
+            "i64.rem_u" => Instruction::I64RemU,
+            "i64.and" => Instruction::I64And,
+            "i64.or" => Instruction::I64Or,
+            "i64.xor" => Instruction::I64Xor,
+            "i64.shl" => Instruction::I64Shl,
+            "i64.shr_s" => Instruction::I64ShrS,
+            "i64.shr_u" => Instruction::I64ShrU,
+            "i64.rotl" => Instruction::I64Rotl,
+            "i64.rotr" => Instruction::I64Rotr,
+            "f32.abs" => Instruction::F32Abs,
+            "f32.neg" => Instruction::F32Neg,
+            "f32.ceil" => Instruction::F32Ceil,
+            "f32.floor" => Instruction::F32Floor,
+            "f32.trunc" => Instruction::F32Trunc,
+            "f32.nearest" => Instruction::F32Nearest,
+            "f32.sqrt" => Instruction::F32Sqrt,
+            "f32.add" => Instruction::F32Add,
+            "f32.sub" => Instruction::F32Sub,
+            "f32.mul" => Instruction::F32Mul,
+            "f32.div" => Instruction::F32Div,
+            "f32.min" => Instruction::F32Min,
+            "f32.max" => Instruction::F32Max,
+            "f32.copysign" => Instruction::F32Copysign,
+            "f64.abs" => Instruction::F64Abs,
+            "f64.neg" => Instruction::F64Neg,
+            "f64.ceil" => Instruction::F64Ceil,
+            "f64.floor" => Instruction::F64Floor,
+            "f64.trunc" => Instruction::F64Trunc,
+            "f64.nearest" => Instruction::F64Nearest,
+            "f64.sqrt" => Instruction::F64Sqrt,
+            "f64.add" => Instruction::F64Add,
+            "f64.sub" => Instruction::F64Sub,
+            "f64.mul" => Instruction::F64Mul,
+            "f64.div" => Instruction::F64Div,
+            "f64.min" => Instruction::F64Min,
+            "f64.max" => Instruction::F64Max,
+            "f64.copysign" => Instruction::F64Copysign,
+            "i32.wrap_i64" => Instruction::I32WrapI64,
+            "i32.trunc_f32_s" => Instruction::I32TruncF32S,
+            "i32.trunc_f32_u" => Instruction::I32TruncF32U,
+            "i32.trunc_f64_s" => Instruction::I32TruncF64S,
+            "i32.trunc_f64_u" => Instruction::I32TruncF64U,
+            "i64.extend_i32_s" => Instruction::I64ExtendI32S,
+            "i64.extend_i32_u" => Instruction::I64ExtendI32U,
+            "i64.trunc_f32_s" => Instruction::I64TruncF32S,
+            "i64.trunc_f32_u" => Instruction::I64TruncF32U,
+            "i64.trunc_f64_s" => Instruction::I64TruncF64S,
+            "i64.trunc_f64_u" => Instruction::I64TruncF64U,
+            "f32.convert_i32_s" => Instruction::F32ConvertI32S,
+            "f32.convert_i32_u" => Instruction::F32ConvertI32U,
+            "f32.convert_i64_s" => Instruction::F32ConvertI64S,
+            "f32.convert_i64_u" => Instruction::F32ConvertI64U,
+            "f32.demote_f64" => Instruction::F32DemoteF64,
+            "f64.convert_i32_s" => Instruction::F64ConvertI32S,
+            "f64.convert_i32_u" => Instruction::F64ConvertI32U,
+            "f64.convert_i64_s" => Instruction::F64ConvertI64S,
+            "f64.convert_i64_u" => Instruction::F64ConvertI64U,
+            "f64.promote_f32" => Instruction::F64PromoteF32,
+            "i32.reinterpret_f32" => Instruction::I32ReinterpretF32,
+            "i64.reinterpret_f64" => Instruction::I64ReinterpretF64,
+            "f32.reinterpret_i32" => Instruction::F32ReinterpretI32,
+            "f64.reinterpret_i64" => Instruction::F64ReinterpretI64,
+            "i32.extend8_s" => Instruction::I32Extend8S,
+            "i32.extend16_s" => Instruction::I32Extend16S,
+            "i64.extend8_s" => Instruction::I64Extend8S,
+            "i64.extend16_s" => Instruction::I64Extend16S,
+            "i64.extend32_s" => Instruction::I64Extend32S,
+            "i32.trunc_sat_f32_s" => Instruction::I32TruncSatF32S,
+            "i32.trunc_sat_f32_u" => Instruction::I32TruncSatF32U,
+            "i32.trunc_sat_f64_s" => Instruction::I32TruncSatF64S,
+            "i32.trunc_sat_f64_u" => Instruction::I32TruncSatF64U,
+            "i64.trunc_sat_f32_s" => Instruction::I64TruncSatF32S,
+            "i64.trunc_sat_f32_u" => Instruction::I64TruncSatF32U,
+            "i64.trunc_sat_f64_s" => Instruction::I64TruncSatF64S,
+            "i64.trunc_sat_f64_u" => Instruction::I64TruncSatF64U,
+            "i32.load" => Instruction::I32Load(self.memarg(4)?),
+            "i64.load" => Instruction::I64Load(self.memarg(8)?),
+            "f32.load" => Instruction::F32Load(self.memarg(4)?),
+            "f64.load" => Instruction::F64Load(self.memarg(8)?),
+            "i32.load8_s" => Instruction::I32Load8S(self.memarg(1)?),
+            "i32.load8_u" => Instruction::I32Load8U(self.memarg(1)?),
+            "i32.load16_s" => Instruction::I32Load16S(self.memarg(2)?),
+            "i32.load16_u" => Instruction::I32Load16U(self.memarg(2)?),
+            "i64.load8_s" => Instruction::I64Load8S(self.memarg(1)?),
+            "i64.load8_u" => Instruction::I64Load8U(self.memarg(1)?),
+            "i64.load16_s" => Instruction::I64Load16S(self.memarg(2)?),
+            "i64.load16_u" => Instruction::I64Load16U(self.memarg(2)?),
+            "i64.load32_s" => Instruction::I64Load32S(self.memarg(4)?),
+            "i64.load32_u" => Instruction::I64Load32U(self.memarg(4)?),
+            "i32.store" => Instruction::I32Store(self.memarg(4)?),
+            "i64.store" => Instruction::I64Store(self.memarg(8)?),
+            "f32.store" => Instruction::F32Store(self.memarg(4)?),
+            "f64.store" => Instruction::F64Store(self.memarg(8)?),
+            "i32.store8" => Instruction::I32Store8(self.memarg(1)?),
+            "i32.store16" => Instruction::I32Store16(self.memarg(2)?),
+            "i64.store8" => Instruction::I64Store8(self.memarg(1)?),
+            "i64.store16" => Instruction::I64Store16(self.memarg(2)?),
+            "i64.store32" => Instruction::I64Store32(self.memarg(4)?),
+            "memory.size" => Instruction::MemorySize,
+            "memory.grow" => Instruction::MemoryGrow,
+            "memory.init" => Instruction::MemoryInit(self.index(&ctx.module.data, "data segment")?),
+            "data.drop" => Instruction::DataDrop(self.index(&ctx.module.data, "data segment")?),
+            "memory.copy" => Instruction::MemoryCopy,
+            "memory.fill" => Instruction::MemoryFill,
+            "table.init" => {
+                let table = if self.at_index(1) {
+                    self.index(&ctx.module.tables, "table")?
+                } else {
+                    0
+                };
+                let elem = self.index(&ctx.module.elems, "elem segment")?;
+                Instruction::TableInit(elem, table)
+            }
+            "elem.drop" => Instruction::ElemDrop(self.index(&ctx.module.elems, "elem segment")?),
+            "table.copy" => {
+                if self.at_index(0) {
+                    let dst = self.index(&ctx.module.tables, "table")?;
+                    let src = self.index(&ctx.module.tables, "table")?;
+                    Instruction::TableCopy(dst, src)
+                } else {
+                    Instruction::TableCopy(0, 0)
+                }
+            }
+            "table.get" => Instruction::TableGet(self.table_use(ctx.module)?),
+            "table.set" => Instruction::TableSet(self.table_use(ctx.module)?),
+            "table.size" => Instruction::TableSize(self.table_use(ctx.module)?),
+            "table.grow" => Instruction::TableGrow(self.table_use(ctx.module)?),
+            "table.fill" => Instruction::TableFill(self.table_use(ctx.module)?),
+            "local.get" => Instruction::LocalGet(self.index(&ctx.locals, "local")?),
+            "local.set" => Instruction::LocalSet(self.index(&ctx.locals, "local")?),
+            "local.tee" => Instruction::LocalTee(self.index(&ctx.locals, "local")?),
+            "global.get" => Instruction::GlobalGet(self.index(&ctx.module.globals, "global")?),
+            "global.set" => Instruction::GlobalSet(self.index(&ctx.module.globals, "global")?),
+            "call" => Instruction::CallFunc(self.index(&ctx.module.funcs, "function")?),
+            "call_indirect" => {
+                let table = self.table_use(ctx.module)?;
+                let ty = self.type_use(ctx.module, ctx.types, &mut HashMap::new())?;
+                Instruction::CallIndirect(ty, table)
+            }
+            _ => {
+                self.pos -= 1;
+                return self.error(format!("unknown operator '{}'", name));
+            }
+        };
+        Ok(instruction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Instance, Trap, Value};
+
+    #[test]
+    fn parses_flat_and_folded_functions() {
+        let module = parse(
+            r#"
+            (module
+              (memory (export "mem") 1)
+              (func $update (export "update")
+                (param $x f64) (param $v f64) (param $dt f64) (result f64)
+                local.get $x
+                (f64.mul (local.get $v) (local.get $dt))
+                f64.add)
+              (func $main
+                (f64.store (i32.const 8) (call $update (f64.const 2) (f64.const 3) (f64.const 0.5))))
+              (data (i32.const 16) "\01\02" "ab"))
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(module.functions.len(), 2);
+        assert_eq!(module.memory, Some(Limits { min: 1, max: None }));
+        assert_eq!(
+            module.exports,
+            vec![
+                Export {
+                    name: "mem".to_string(),
+                    desc: ExportDesc::Memory(0)
+                },
+                Export {
+                    name: "update".to_string(),
+                    desc: ExportDesc::Func(0)
+                },
+            ]
+        );
+        assert_eq!(
+            module.data,
+            vec![Data {
+                offset: Some(ConstExpr::Const(Value::I32(16))),
+                init: vec![1, 2, b'a', b'b']
+            }]
+        );
+
+        let mut m = Instance::new(module).unwrap();
+        m.call(1, vec![]).unwrap();
+        assert_eq!(m.load::<f64>(8).unwrap(), 3.5);
+    }
+
+    #[test]
+    fn parses_structured_control() {
+        let module = parse(
+            r#"
+            (func $fac (param $n i32) (result i32)
+              (if (result i32) (local.get $n)
+                (then (i32.mul (local.get $n) (call $fac (i32.add (local.get $n) (i32.const -1)))))
+                (else (i32.const 1))))
+            (func (param i32) (result i32)
+              block $outer
+                block $inner
+                  local.get 0
+                  br_table $inner $outer 0
+                end $inner
+                i32.const 10
+                return
+              end
+              (loop $l (br_if $l (i32.const 0)))
+              i32.const 20)
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            module.functions[1].code,
+            vec![
+                Instruction::Block(BlockType::Empty),
+                Instruction::Block(BlockType::Empty),
+                Instruction::LocalGet(0),
+                Instruction::BrTable(vec![0, 1], 0),
+                Instruction::End,
+                Instruction::I32Const(10),
+                Instruction::Return,
+                Instruction::End,
+                Instruction::Loop(BlockType::Empty),
+                Instruction::I32Const(0),
+                Instruction::BrIf(0),
+                Instruction::End,
+                Instruction::I32Const(20),
+            ]
+        );
+
+        let mut m = Instance::new(module).unwrap();
+        assert_eq!(
+            m.call(0, vec![Value::I32(5)]).unwrap(),
+            vec![Value::I32(120)]
+        );
+        assert_eq!(
+            m.call(1, vec![Value::I32(0)]).unwrap(),
+            vec![Value::I32(10)]
+        );
+        assert_eq!(
+            m.call(1, vec![Value::I32(1)]).unwrap(),
+            vec![Value::I32(20)]
+        );
+    }
+
+    #[test]
+    fn resolves_labels_in_folded_if_conditions() {
+        let module = parse(
+            "(func (result i32)
+              (block $o (result i32)
+                (i32.add
+                  (i32.const 100)
+                  (block $b (result i32)
+                    (if $i (result i32) (br_if $b (i32.const 7) (i32.const 1))
+                      (then (i32.const 1))
+                      (else (i32.const 2)))))))",
+        )
+        .unwrap();
+        assert!(module.functions[0].code.contains(&Instruction::BrIf(0)));
+        let mut m = Instance::new(module).unwrap();
+        assert_eq!(m.call(0, vec![]).unwrap(), vec![Value::I32(107)]);
+    }
+
+    #[test]
+    fn parses_multiple_results() {
+        let module = parse(
+            "(type $swap (func (param i32 i64) (result i64 i32)))
+             (func (param i32 i64) (result i64 i32)
+               (local.get 0) (local.get 1)
+               (block (type $swap) (param i32 i64) (result i64 i32)
+                 (local.set 1) (local.set 0) (local.get 1) (local.get 0)))
+             (func (result i32 i32 i32)
+               (block (result i32 i32) (i32.const 1) (i32.const 2))
+               (call 0 (i32.const 3) (i64.const 4))
+               drop i32.wrap_i64)",
+        )
+        .unwrap();
+        module.validate().unwrap();
+        assert_eq!(
+            module.functions[0].code[2],
+            Instruction::Block(BlockType::Type(0))
+        );
+        assert_eq!(
+            module.types[2],
+            FuncType {
+                params: vec![],
+                results: vec![ValType::I32; 2]
+            }
+        );
+
+        let mut m = Instance::new(module).unwrap();
+        let results = m.call(0, vec![Value::I32(1), Value::I64(2)]);
+        assert_eq!(results, Ok(vec![Value::I64(2), Value::I32(1)]));
+        let results = m.call(1, vec![]);
+        assert_eq!(
+            results,
+            Ok(vec![Value::I32(1), Value::I32(2), Value::I32(4)])
+        );
+    }
+
+    #[test]
+    fn parses_declared_locals() {
+        let module = parse(
+            "(func (param $n i32) (result i32) (local $acc i32) (local f64 f64)
+               (local.set $acc (local.tee $n (i32.add (local.get $n) (i32.const 1))))
+               (i32.add (local.get $acc) (local.get 0)))",
+        )
+        .unwrap();
+        let func = &module.functions[0];
+        let ty = &module.types[func.ty()];
+        assert_eq!(func.local(ty, 1), Some(ValType::I32));
+        assert_eq!(func.local(ty, 3), Some(ValType::F64));
+        assert_eq!(func.local(ty, 4), None);
+        let mut m = Instance::new(module).unwrap();
+        let result = m.call(0, vec![Value::I32(2)]).unwrap();
+        assert_eq!(result, vec![Value::I32(6)]);
+    }
+
+    #[test]
+    fn parses_type_uses() {
+        let module = parse(
+            "(type $t (func (param i32) (result i32)))
+             (func (type $t) local.get 0)",
+        )
+        .unwrap();
+        let mut m = Instance::new(module).unwrap();
+        let result = m.call(0, vec![Value::I32(7)]).unwrap();
+        assert_eq!(result, vec![Value::I32(7)]);
+    }
+
+    #[test]
+    fn parses_tables_and_call_indirect() {
+        let module = parse(
+            r#"(type $unary (func (param i32) (result i32)))
+               (table $fns (export "fns") funcref (elem $inc $dec))
+               (func $inc (type $unary) (i32.add (local.get 0) (i32.const 1)))
+               (func $dec (type $unary) (i32.sub (local.get 0) (i32.const 1)))
+               (func (param i32 i32) (result i32)
+                 (call_indirect $fns (type $unary) (local.get 1) (local.get 0)))
+               (func (call_indirect (param f64) (f64.const 0) (i32.const 0)))"#,
+        )
+        .unwrap();
+        module.validate().unwrap();
+        assert_eq!(
+            module.tables,
+            vec![TableType {
+                elem: RefType::Func,
+                limits: Limits {
+                    min: 2,
+                    max: Some(2)
+                }
+            }]
+        );
+        assert_eq!(module.exports[0].desc, ExportDesc::Table(0));
+        assert_eq!(module.functions[2].code[2], Instruction::CallIndirect(0, 0));
+        assert_eq!(module.functions[3].code[2], Instruction::CallIndirect(3, 0));
+        assert_eq!(module.types[3].params, vec![ValType::F64]);
+
+        let mut m = Instance::new(module).unwrap();
+        let result = m.call(2, vec![Value::I32(1), Value::I32(10)]);
+        assert_eq!(result, Ok(vec![Value::I32(9)]));
+        assert_eq!(m.call(3, vec![]), Err(Trap::IndirectCallTypeMismatch));
+
+        let module = parse(
+            "(table 1 4 funcref)
+             (elem (table 0) (offset (i32.const 0)) func 0)
+             (func)",
+        )
+        .unwrap();
+        assert_eq!(
+            module.tables[0].limits,
+            Limits {
+                min: 1,
+                max: Some(4)
+            }
+        );
+        assert_eq!(module.elems[0].init, vec![ConstExpr::RefFunc(0)]);
+    }
+
+    #[test]
+    fn parses_imports() {
+        let module = parse(
+            r#"(import "env" "add" (func $add (param i32 i32) (result i32)))
+               (func $twice (export "twice") (import "env" "twice") (param i32) (result i32))
+               (func (export "run") (result i32)
+                 (call $twice (call $add (i32.const 1) (i32.const 2))))"#,
+        )
+        .unwrap();
+        module.validate().unwrap();
+        assert_eq!(module.imports[1].name, "twice");
+        assert_eq!(module.imports[1].desc, ImportDesc::Func(1));
+        assert_eq!(module.exports[0].desc, ExportDesc::Func(1));
+        assert_eq!(module.exports[1].desc, ExportDesc::Func(2));
+        assert_eq!(module.functions[0].code[3], Instruction::CallFunc(1));
+
+        // Arguments are checked against the signature before the call.
+        let arg = |val: Value| i32::try_from(val).unwrap();
+        let mut imports = crate::Imports::new();
+        let ty = module.func_type(0).unwrap().clone();
+        imports.func("env", "add", ty, move |_, args| {
+            Ok(vec![Value::I32(arg(args[0]) + arg(args[1]))])
+        });
+        let ty = module.func_type(1).unwrap().clone();
+        imports.func("env", "twice", ty, move |_, args| {
+            Ok(vec![Value::I32(arg(args[0]) * 2)])
+        });
+        let mut m = Instance::with_imports(module, &imports).unwrap();
+        assert_eq!(m.call(2, vec![]), Ok(vec![Value::I32(6)]));
+
+        let err = parse(r#"(func) (import "env" "f" (func))"#).unwrap_err();
+        assert_eq!(err.message, "import after function");
+    }
+
+    #[test]
+    fn parses_bulk_instructions() {
+        let module = parse(
+            r#"(table $t funcref (elem $f))
+               (table $u 1 funcref)
+               (memory (data "inline"))
+               (elem $e declare func $f)
+               (data $d "")
+               (func $f
+                 (table.init $u $e (i32.const 0) (i32.const 0) (i32.const 0))
+                 (table.init 1 (i32.const 0) (i32.const 0) (i32.const 0))
+                 (table.copy $u $t (i32.const 0) (i32.const 0) (i32.const 0))
+                 (table.copy (i32.const 0) (i32.const 0) (i32.const 0))
+                 elem.drop $e
+                 (memory.init $d (i32.const 0) (i32.const 0) (i32.const 0))
+                 data.drop 0)"#,
+        )
+        .unwrap();
+        module.validate().unwrap();
+        assert_eq!(module.elems[1].mode, ElemMode::Declarative);
+        let bulk: Vec<_> = module.functions[0]
+            .code
+            .iter()
+            .filter(|instruction| !matches!(instruction, Instruction::I32Const(_)))
+            .cloned()
+            .collect();
+        assert_eq!(
+            bulk,
+            vec![
+                Instruction::TableInit(1, 1),
+                Instruction::TableInit(1, 0),
+                Instruction::TableCopy(1, 0),
+                Instruction::TableCopy(0, 0),
+                Instruction::ElemDrop(1),
+                Instruction::MemoryInit(1),
+                Instruction::DataDrop(0),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_table_memory_and_global_imports() {
+        let module = parse(
+            r#"(import "env" "table" (table $t 2 funcref))
+               (memory (export "mem") (import "env" "mem") 1 2)
+               (import "env" "base" (global $base i32))
+               (global $g (import "env" "g") (mut i64))
+               (global $next i32 (global.get $base))
+               (table $own 1 funcref)
+               (func (result i32)
+                 (global.set $g (i64.const 1))
+                 (call_indirect $own (result i32) (i32.load (global.get $next))))"#,
+        )
+        .unwrap();
+        module.validate().unwrap();
+        let descs: Vec<_> = module.imports.iter().map(|import| import.desc).collect();
+        assert_eq!(
+            descs,
+            vec![
+                ImportDesc::Table(TableType {
+                    elem: RefType::Func,
+                    limits: Limits { min: 2, max: None }
+                }),
+                ImportDesc::Memory(Limits {
+                    min: 1,
+                    max: Some(2)
+                }),
+                ImportDesc::Global(GlobalType {
+                    ty: ValType::I32,
+                    mutable: false
+                }),
+                ImportDesc::Global(GlobalType {
+                    ty: ValType::I64,
+                    mutable: true
+                }),
+            ]
+        );
+        assert_eq!(module.memory, None);
+        assert_eq!(module.globals[0].init, ConstExpr::GlobalGet(0));
+        assert_eq!(module.exports[0].desc, ExportDesc::Memory(0));
+        let code = &module.functions[0].code;
+        assert_eq!(code[1], Instruction::GlobalSet(1));
+        assert_eq!(code[2], Instruction::GlobalGet(2));
+        assert_eq!(code[4], Instruction::CallIndirect(0, 1));
+
+        let err =
+            parse(r#"(global i32 (i32.const 0)) (import "env" "g" (global i32))"#).unwrap_err();
+        assert_eq!(err.message, "import after global");
+        let err = parse(r#"(memory 1) (memory (import "env" "mem") 1)"#).unwrap_err();
+        assert_eq!(err.message, "multiple memories are not supported");
+    }
+
+    #[test]
+    fn parses_reference_types() {
+        let module = parse(
+            r#"(table $fs 2 funcref)
+               (table $hs externref (elem (ref.null extern) (item ref.null extern)))
+               (elem (table $fs) (i32.const 0) funcref (item ref.func $f) (ref.null func))
+               (elem $passive externref)
+               (global (mut externref) (ref.null extern))
+               (func $f (param externref) (result externref)
+                 (drop (table.get (i32.const 0)))
+                 (drop (table.size $hs))
+                 (select (result externref) (local.get 0) (ref.null extern) (i32.const 0)))"#,
+        )
+        .unwrap();
+        module.validate().unwrap();
+        assert_eq!(module.tables[1].elem, RefType::Extern);
+        assert_eq!(module.tables[1].limits.min, 2);
+        assert_eq!(
+            module.elems[0].init,
+            vec![ConstExpr::RefNull(RefType::Extern); 2]
+        );
+        assert_eq!(
+            module.elems[1].init,
+            vec![ConstExpr::RefFunc(0), ConstExpr::RefNull(RefType::Func)]
+        );
+        assert_eq!(
+            (module.elems[2].mode.clone(), module.elems[2].ty),
+            (ElemMode::Passive, RefType::Extern)
+        );
+        assert_eq!(module.globals[0].init, ConstExpr::RefNull(RefType::Extern));
+        assert_eq!(
+            module.functions[0].code,
+            vec![
+                Instruction::I32Const(0),
+                Instruction::TableGet(0),
+                Instruction::Drop,
+                Instruction::TableSize(1),
+                Instruction::Drop,
+                Instruction::LocalGet(0),
+                Instruction::RefNull(RefType::Extern),
+                Instruction::I32Const(0),
+                Instruction::SelectTyped(vec![ValType::ExternRef]),
+            ]
+        );
+
+        let err = parse("(func (ref.null any))").unwrap_err();
+        assert_eq!(err.message, "expected heap type");
+    }
+
+    #[test]
+    fn parses_memargs() {
+        let module = parse(
+            "(memory 1)
+             (func
+               (i64.store16 offset=0x10 align=1 (i32.const 0) (i64.const 1))
+               (drop (f32.load (i32.const 0))))",
+        )
+        .unwrap();
+        assert_eq!(
+            module.functions[0].code[2],
+            Instruction::I64Store16(MemArg {
+                align: 0,
+                offset: 16
+            })
+        );
+        assert_eq!(
+            module.functions[0].code[4],
+            Instruction::F32Load(MemArg {
+                align: 2,
+                offset: 0
+            })
+        );
+
+        let err = parse("(func (drop (i32.load align=3 (i32.const 0))))").unwrap_err();
+        assert_eq!(err.message, "alignment must be a power of two");
+    }
+
+    #[test]
+    fn parses_globals() {
+        let module = parse(
+            r#"(global $k (export "k") i64 (i64.const 7))
+               (global $copy i64 (global.get $k))
+               (global $sp (mut i32) (i32.const 1024))
+               (func (param i32) (result i32)
+                 (global.set $sp (i32.sub (global.get $sp) (local.get 0)))
+                 (global.get $sp))"#,
+        )
+        .unwrap();
+        module.validate().unwrap();
+        assert_eq!(
+            module.globals[2],
+            Global {
+                ty: GlobalType {
+                    ty: ValType::I32,
+                    mutable: true
+                },
+                init: ConstExpr::Const(Value::I32(1024))
+            }
+        );
+        assert_eq!(module.globals[1].init, ConstExpr::GlobalGet(0));
+        assert_eq!(module.exports[0].desc, ExportDesc::Global(0));
+
+        let mut m = Instance::new(module).unwrap();
+        assert_eq!(m.call(0, vec![Value::I32(16)]), Ok(vec![Value::I32(1008)]));
+        assert_eq!(m.call(0, vec![Value::I32(8)]), Ok(vec![Value::I32(1000)]));
+        assert_eq!(m.global(1), Ok(Some(Value::I64(7))));
+        assert_eq!(m.global(2), Ok(Some(Value::I32(1000))));
+        assert_eq!(m.global(3), Ok(None));
+
+        let err = parse("(global i32 (i32.const 1) (i32.const 2))").unwrap_err();
+        assert_eq!(err.message, "constant expression required");
+    }
+
+    #[test]
+    fn reports_error_positions() {
+        let err = parse("(module\n  (func (result i32)\n    i32.const 1 i32.frob))").unwrap_err();
+        assert_eq!((err.line, err.col), (3, 17));
+        assert_eq!(err.message, "unknown operator 'i32.frob'");
+
+        let err = parse("(module (func (call $missing)))").unwrap_err();
+        assert_eq!(err.message, "unknown function $missing");
+
+        let err = parse("(module (func) (start 0) (start 0))").unwrap_err();
+        assert_eq!(err.message, "multiple start sections");
+    }
+
+    #[test]
+    fn parses_integer_literals() {
+        assert_eq!(parse_i32("0xffff_ffff"), Some(-1));
+        assert_eq!(parse_i32("-2147483648"), Some(i32::MIN));
+        assert_eq!(parse_i32("4294967296"), None);
+        assert_eq!(parse_i32("1__0"), None);
+        assert_eq!(parse_i64("-0x8000000000000000"), Some(i64::MIN));
+        assert_eq!(parse_u32("-1"), None);
+    }
+
+    #[test]
+    fn parses_float_literals() {
+        assert_eq!(parse_f64("1_000.5e-1"), Some(100.05));
+        assert_eq!(parse_f64("0x1.8p1"), Some(3.0));
+        assert_eq!(parse_f64("-0x1p-1074"), Some(-f64::from_bits(1)));
+        assert_eq!(parse_f64("0x1p-1075"), Some(0.0));
+        assert_eq!(
+            parse_f64("0x1.0000000000000fffp0"),
+            Some(1.0 + f64::EPSILON)
+        );
+        assert_eq!(parse_f64("0x1p1024"), None);
+        assert_eq!(parse_f32("0x1.fffffefffp127"), Some(f32::MAX));
+        assert_eq!(parse_f32("1e39"), None);
+        assert_eq!(
+            parse_f32("nan:0x200000").map(f32::to_bits),
+            Some(0x7fa0_0000)
+        );
+        assert_eq!(parse_f32("-inf"), Some(f32::NEG_INFINITY));
+        assert_eq!(parse_f32(".5"), None);
+    }
+}
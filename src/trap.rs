@@ -0,0 +1,51 @@
+use std::fmt;
+
+/// An error that aborts execution of guest code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    Unreachable,
+    MemoryOutOfBounds,
+    StackUnderflow,
+    IntegerDivideByZero,
+    IntegerOverflow,
+    InvalidConversionToInteger,
+    CallStackExhausted,
+    UndefinedElement,
+    UninitializedElement,
+    IndirectCallTypeMismatch,
+    TableOutOfBounds,
+    /// A host function calling back into the store running it.
+    ReentrantCall,
+    /// An operand of the wrong type, which validation would have rejected.
+    TypeMismatch,
+    UndefinedLocal(usize),
+    UndefinedFunction(usize),
+    UndefinedGlobal(usize),
+    UndefinedType(usize),
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trap::Unreachable => write!(f, "unreachable"),
+            Trap::MemoryOutOfBounds => write!(f, "out of bounds memory access"),
+            Trap::StackUnderflow => write!(f, "stack underflow"),
+            Trap::IntegerDivideByZero => write!(f, "integer divide by zero"),
+            Trap::IntegerOverflow => write!(f, "integer overflow"),
+            Trap::InvalidConversionToInteger => write!(f, "invalid conversion to integer"),
+            Trap::CallStackExhausted => write!(f, "call stack exhausted"),
+            Trap::UndefinedElement => write!(f, "undefined element"),
+            Trap::UninitializedElement => write!(f, "uninitialized element"),
+            Trap::IndirectCallTypeMismatch => write!(f, "indirect call type mismatch"),
+            Trap::TableOutOfBounds => write!(f, "out of bounds table access"),
+            Trap::ReentrantCall => write!(f, "re-entrant call into a running store"),
+            Trap::TypeMismatch => write!(f, "type mismatch"),
+            Trap::UndefinedLocal(index) => write!(f, "undefined local {}", index),
+            Trap::UndefinedFunction(index) => write!(f, "undefined function {}", index),
+            Trap::UndefinedGlobal(index) => write!(f, "undefined global {}", index),
+            Trap::UndefinedType(index) => write!(f, "undefined type {}", index),
+        }
+    }
+}
+
+impl std::error::Error for Trap {}
@@ -0,0 +1,1064 @@
+//! Type checking of modules, following the algorithm in the appendix of the
+//! WebAssembly specification.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::module::{
+    ConstExpr, ElemMode, ExportDesc, FuncType, GlobalType, ImportDesc, Module, MAX_PAGES,
+};
+use crate::{BlockType, Function, Instruction, MemArg, RefType, ValType};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    /// The function and instruction index the error was found at, if any.
+    pub func: Option<usize>,
+    pub pc: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.func, self.pc) {
+            (Some(func), Some(pc)) => write!(f, "function {}, instruction {}: ", func, pc)?,
+            (Some(func), None) => write!(f, "function {}: ", func)?,
+            _ => {}
+        }
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+type Result<T> = std::result::Result<T, String>;
+
+pub(crate) fn validate(module: &Module) -> std::result::Result<(), ValidationError> {
+    let error = |message: String| ValidationError {
+        func: None,
+        pc: None,
+        message,
+    };
+
+    if module.memory_types().count() > 1 {
+        return Err(error("multiple memories".to_string()));
+    }
+    for limits in module.memory_types() {
+        if limits.min > MAX_PAGES || limits.max.is_some_and(|max| max > MAX_PAGES) {
+            return Err(error(
+                "memory size must be at most 65536 pages (4GiB)".to_string(),
+            ));
+        }
+        if limits.max.is_some_and(|max| max < limits.min) {
+            return Err(error(
+                "size minimum must not be greater than maximum".to_string(),
+            ));
+        }
+    }
+    for limits in module.table_types().map(|ty| ty.limits) {
+        if limits.max.is_some_and(|max| max < limits.min) {
+            return Err(error(
+                "size minimum must not be greater than maximum".to_string(),
+            ));
+        }
+    }
+    if !module.has_memory() && module.data.iter().any(|data| data.offset.is_some()) {
+        return Err(error("unknown memory 0".to_string()));
+    }
+
+    // Globals are numbered after the imported ones, and may only be
+    // initialized from globals before them.
+    let imported_globals = module.imported_globals();
+    for (index, global) in module.globals.iter().enumerate() {
+        let index = imported_globals + index;
+        let ty = const_expr_type(module, &global.init, index).map_err(error)?;
+        if ty != global.ty.ty {
+            return Err(error(format!(
+                "type mismatch: global {} is {} but its initializer is {}",
+                index, global.ty.ty, ty
+            )));
+        }
+    }
+    for elem in &module.elems {
+        if let ElemMode::Active { table, offset } = &elem.mode {
+            let table = match module.table_type(*table) {
+                Some(table) => table,
+                None => return Err(error(format!("unknown table {}", table))),
+            };
+            ref_types_match(table.elem, elem.ty).map_err(error)?;
+            let ty = const_expr_type(module, offset, module.global_count()).map_err(error)?;
+            if ty != ValType::I32 {
+                return Err(error(format!(
+                    "type mismatch: element offset must be i32, found {}",
+                    ty
+                )));
+            }
+        }
+        for init in &elem.init {
+            let ty = const_expr_type(module, init, module.global_count()).map_err(error)?;
+            if ty != elem.ty.into() {
+                return Err(error(format!(
+                    "type mismatch: element segment of {} has an element of type {}",
+                    elem.ty, ty
+                )));
+            }
+        }
+    }
+    for offset in module.data.iter().filter_map(|data| data.offset.as_ref()) {
+        let ty = const_expr_type(module, offset, module.global_count()).map_err(error)?;
+        if ty != ValType::I32 {
+            return Err(error(format!(
+                "type mismatch: data offset must be i32, found {}",
+                ty
+            )));
+        }
+    }
+
+    if let Some(start) = module.start {
+        match module.func_type(start) {
+            Some(ty) if *ty == FuncType::default() => {}
+            Some(_) => {
+                return Err(error(
+                    "start function must take no arguments and return nothing".to_string(),
+                ))
+            }
+            None => return Err(error(format!("unknown function {}", start))),
+        }
+    }
+
+    let mut names = HashSet::new();
+    for export in &module.exports {
+        if !names.insert(&export.name) {
+            return Err(error(format!("duplicate export name {:?}", export.name)));
+        }
+        match export.desc {
+            ExportDesc::Func(index) if index >= module.func_count() => {
+                return Err(error(format!("unknown function {}", index)));
+            }
+            ExportDesc::Memory(index) if index > 0 || !module.has_memory() => {
+                return Err(error(format!("unknown memory {}", index)));
+            }
+            ExportDesc::Table(index) if index >= module.table_count() => {
+                return Err(error(format!("unknown table {}", index)));
+            }
+            ExportDesc::Global(index) if index >= module.global_count() => {
+                return Err(error(format!("unknown global {}", index)));
+            }
+            _ => {}
+        }
+    }
+
+    // Functions are numbered after the imported ones.
+    let imported = module.imported_funcs();
+    for import in &module.imports {
+        match import.desc {
+            ImportDesc::Func(ty) if ty >= module.types.len() => {
+                return Err(error(format!("unknown type {}", ty)));
+            }
+            _ => {}
+        }
+    }
+    for (index, func) in module.functions.iter().enumerate() {
+        if func.ty() >= module.types.len() {
+            return Err(ValidationError {
+                func: Some(imported + index),
+                pc: None,
+                message: format!("unknown type {}", func.ty()),
+            });
+        }
+    }
+    // Functions may only be referenced from code if they are referenced
+    // outside of it as well.
+    let inits = module.globals.iter().map(|global| &global.init);
+    let elems = module.elems.iter().flat_map(|elem| &elem.init);
+    let mut refs: HashSet<usize> = inits
+        .chain(elems)
+        .filter_map(|expr| match expr {
+            ConstExpr::RefFunc(index) => Some(*index),
+            _ => None,
+        })
+        .collect();
+    refs.extend(
+        module
+            .exports
+            .iter()
+            .filter_map(|export| match export.desc {
+                ExportDesc::Func(index) => Some(index),
+                _ => None,
+            }),
+    );
+    for (index, func) in module.functions.iter().enumerate() {
+        let mut validator = FuncValidator {
+            module,
+            refs: &refs,
+            func,
+            ty: &module.types[func.ty()],
+            vals: Vec::new(),
+            ctrls: Vec::new(),
+        };
+        validator
+            .validate()
+            .map_err(|(pc, message)| ValidationError {
+                func: Some(imported + index),
+                pc,
+                message,
+            })?;
+    }
+    Ok(())
+}
+
+/// The type of an initializer that may only refer to the first `globals`
+/// globals, which must be immutable.
+fn const_expr_type(module: &Module, expr: &ConstExpr, globals: usize) -> Result<ValType> {
+    match *expr {
+        ConstExpr::Const(val) => Ok(val.ty()),
+        ConstExpr::GlobalGet(index) if index >= globals => Err(format!("unknown global {}", index)),
+        ConstExpr::GlobalGet(index) => match module.global_type(index) {
+            Some(ty) if !ty.mutable => Ok(ty.ty),
+            Some(_) => Err("constant expression required".to_string()),
+            None => Err(format!("unknown global {}", index)),
+        },
+        ConstExpr::RefNull(ty) => Ok(ty.into()),
+        ConstExpr::RefFunc(index) if index >= module.func_count() => {
+            Err(format!("unknown function {}", index))
+        }
+        ConstExpr::RefFunc(_) => Ok(ValType::FuncRef),
+    }
+}
+
+/// Checks that references of type `actual` can be stored where `expected`
+/// ones are.
+fn ref_types_match(expected: RefType, actual: RefType) -> Result<()> {
+    if expected != actual {
+        return Err(format!(
+            "type mismatch: expected {}, found {}",
+            expected, actual
+        ));
+    }
+    Ok(())
+}
+
+/// An operand type, or `None` for an unknown type in unreachable code.
+type Operand = Option<ValType>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FrameKind {
+    Func,
+    Block,
+    Loop,
+    If,
+    Else,
+}
+
+struct Ctrl {
+    kind: FrameKind,
+    start_types: Vec<ValType>,
+    end_types: Vec<ValType>,
+    height: usize,
+    unreachable: bool,
+}
+
+impl Ctrl {
+    /// The types a branch to this frame must provide.
+    fn label_types(&self) -> &[ValType] {
+        if self.kind == FrameKind::Loop {
+            &self.start_types
+        } else {
+            &self.end_types
+        }
+    }
+}
+
+struct FuncValidator<'a> {
+    module: &'a Module,
+    /// Functions that `ref.func` may refer to.
+    refs: &'a HashSet<usize>,
+    func: &'a Function,
+    ty: &'a FuncType,
+    vals: Vec<Operand>,
+    ctrls: Vec<Ctrl>,
+}
+
+impl<'a> FuncValidator<'a> {
+    fn push_val(&mut self, ty: Operand) {
+        self.vals.push(ty);
+    }
+
+    fn pop_val(&mut self) -> Result<Operand> {
+        let frame = self.ctrls.last().unwrap();
+        if self.vals.len() == frame.height {
+            if frame.unreachable {
+                return Ok(None);
+            }
+            return Err("type mismatch: operand stack is empty".to_string());
+        }
+        Ok(self.vals.pop().unwrap())
+    }
+
+    fn pop_expect(&mut self, expected: ValType) -> Result<Operand> {
+        match self.pop_val()? {
+            Some(actual) if actual != expected => Err(format!(
+                "type mismatch: expected {}, found {}",
+                expected, actual
+            )),
+            Some(actual) => Ok(Some(actual)),
+            None => Ok(Some(expected)),
+        }
+    }
+
+    fn push_vals(&mut self, types: &[ValType]) {
+        self.vals.extend(types.iter().map(|ty| Some(*ty)));
+    }
+
+    fn pop_vals(&mut self, types: &[ValType]) -> Result<()> {
+        for ty in types.iter().rev() {
+            self.pop_expect(*ty)?;
+        }
+        Ok(())
+    }
+
+    fn push_ctrl(&mut self, kind: FrameKind, start_types: Vec<ValType>, end_types: Vec<ValType>) {
+        let height = self.vals.len();
+        self.push_vals(&start_types);
+        self.ctrls.push(Ctrl {
+            kind,
+            start_types,
+            end_types,
+            height,
+            unreachable: false,
+        });
+    }
+
+    fn pop_ctrl(&mut self) -> Result<Ctrl> {
+        let end_types = self.ctrls.last().unwrap().end_types.clone();
+        self.pop_vals(&end_types)?;
+        let frame = self.ctrls.last().unwrap();
+        if self.vals.len() != frame.height {
+            return Err("type mismatch: values remaining on stack at end of block".to_string());
+        }
+        Ok(self.ctrls.pop().unwrap())
+    }
+
+    /// Opens a block of type `ty`, taking its parameters off the stack.
+    fn block(&mut self, kind: FrameKind, ty: BlockType) -> Result<()> {
+        let (params, results) = match ty {
+            BlockType::Empty => (vec![], vec![]),
+            BlockType::Value(ty) => (vec![], vec![ty]),
+            BlockType::Type(index) => match self.module.types.get(index) {
+                Some(ty) => (ty.params.clone(), ty.results.clone()),
+                None => return Err(format!("unknown type {}", index)),
+            },
+        };
+        self.pop_vals(&params)?;
+        self.push_ctrl(kind, params, results);
+        Ok(())
+    }
+
+    fn label_types(&self, depth: usize) -> Result<Vec<ValType>> {
+        if depth >= self.ctrls.len() {
+            return Err(format!("unknown label {}", depth));
+        }
+        Ok(self.ctrls[self.ctrls.len() - 1 - depth]
+            .label_types()
+            .to_vec())
+    }
+
+    fn unreachable(&mut self) {
+        let frame = self.ctrls.last_mut().unwrap();
+        self.vals.truncate(frame.height);
+        frame.unreachable = true;
+    }
+
+    fn local(&self, index: usize) -> Result<ValType> {
+        match self.func.local(self.ty, index) {
+            Some(ty) => Ok(ty),
+            None => Err(format!("unknown local {}", index)),
+        }
+    }
+
+    fn global(&self, index: usize) -> Result<GlobalType> {
+        match self.module.global_type(index) {
+            Some(ty) => Ok(ty),
+            None => Err(format!("unknown global {}", index)),
+        }
+    }
+
+    fn memory(&self) -> Result<()> {
+        if !self.module.has_memory() {
+            return Err("unknown memory 0".to_string());
+        }
+        Ok(())
+    }
+
+    /// The element type of the table at `index`.
+    fn table(&self, index: usize) -> Result<RefType> {
+        match self.module.table_type(index) {
+            Some(ty) => Ok(ty.elem),
+            None => Err(format!("unknown table {}", index)),
+        }
+    }
+
+    /// The type of the references in the element segment at `index`.
+    fn elem(&self, index: usize) -> Result<RefType> {
+        match self.module.elems.get(index) {
+            Some(elem) => Ok(elem.ty),
+            None => Err(format!("unknown elem segment {}", index)),
+        }
+    }
+
+    fn data(&self, index: usize) -> Result<()> {
+        if index >= self.module.data.len() {
+            return Err(format!("unknown data segment {}", index));
+        }
+        Ok(())
+    }
+
+    /// Checks that a memory exists and that the alignment of an access of
+    /// `width` bytes is at most natural.
+    fn memarg(&self, memarg: &MemArg, width: u32) -> Result<()> {
+        self.memory()?;
+        if memarg.align >= 32 || 1 << memarg.align > width {
+            return Err("alignment must not be larger than natural".to_string());
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, memarg: &MemArg, width: u32, ty: ValType) -> Result<()> {
+        self.memarg(memarg, width)?;
+        self.pop_expect(ValType::I32)?;
+        self.push_val(Some(ty));
+        Ok(())
+    }
+
+    fn store(&mut self, memarg: &MemArg, width: u32, ty: ValType) -> Result<()> {
+        self.memarg(memarg, width)?;
+        self.pop_expect(ty)?;
+        self.pop_expect(ValType::I32)?;
+        Ok(())
+    }
+
+    fn unop(&mut self, ty: ValType) -> Result<()> {
+        self.pop_expect(ty)?;
+        self.push_val(Some(ty));
+        Ok(())
+    }
+
+    fn binop(&mut self, ty: ValType) -> Result<()> {
+        self.pop_expect(ty)?;
+        self.unop(ty)
+    }
+
+    fn cvtop(&mut self, from: ValType, to: ValType) -> Result<()> {
+        self.pop_expect(from)?;
+        self.push_val(Some(to));
+        Ok(())
+    }
+
+    fn testop(&mut self, ty: ValType) -> Result<()> {
+        self.pop_expect(ty)?;
+        self.push_val(Some(ValType::I32));
+        Ok(())
+    }
+
+    fn relop(&mut self, ty: ValType) -> Result<()> {
+        self.pop_expect(ty)?;
+        self.testop(ty)
+    }
+
+    fn validate(&mut self) -> std::result::Result<(), (Option<usize>, String)> {
+        let results = self.ty.results.clone();
+        self.push_ctrl(FrameKind::Func, Vec::new(), results);
+        for (pc, instruction) in self.func.code.iter().enumerate() {
+            self.instruction(instruction)
+                .map_err(|message| (Some(pc), message))?;
+        }
+        if self.ctrls.len() > 1 {
+            return Err((None, "unclosed block at end of function".to_string()));
+        }
+        self.pop_ctrl().map_err(|message| (None, message))?;
+        Ok(())
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) -> Result<()> {
+        use ValType::*;
+
+        match instruction {
+            Instruction::Unreachable => self.unreachable(),
+            Instruction::Nop => {}
+            Instruction::Block(ty) => self.block(FrameKind::Block, *ty)?,
+            Instruction::Loop(ty) => self.block(FrameKind::Loop, *ty)?,
+            Instruction::If(ty) => {
+                self.pop_expect(I32)?;
+                self.block(FrameKind::If, *ty)?;
+            }
+            Instruction::Else => {
+                if self.ctrls.last().unwrap().kind != FrameKind::If {
+                    return Err("else without matching if".to_string());
+                }
+                let frame = self.pop_ctrl()?;
+                self.push_ctrl(FrameKind::Else, frame.start_types, frame.end_types);
+            }
+            Instruction::End => {
+                if self.ctrls.len() == 1 {
+                    return Err("end without matching block".to_string());
+                }
+                let frame = self.pop_ctrl()?;
+                if frame.kind == FrameKind::If && frame.start_types != frame.end_types {
+                    return Err(
+                        "type mismatch: if without else must not produce values".to_string()
+                    );
+                }
+                self.push_vals(&frame.end_types);
+            }
+            Instruction::Br(depth) => {
+                let types = self.label_types(*depth)?;
+                self.pop_vals(&types)?;
+                self.unreachable();
+            }
+            Instruction::BrIf(depth) => {
+                self.pop_expect(I32)?;
+                let types = self.label_types(*depth)?;
+                self.pop_vals(&types)?;
+                self.push_vals(&types);
+            }
+            Instruction::BrTable(table, default) => {
+                self.pop_expect(I32)?;
+                let default_types = self.label_types(*default)?;
+                for depth in table {
+                    let types = self.label_types(*depth)?;
+                    if types.len() != default_types.len() {
+                        return Err(
+                            "type mismatch: br_table targets have different arities".to_string()
+                        );
+                    }
+                    // Check the operands against each target without consuming them.
+                    let saved = self.vals.clone();
+                    self.pop_vals(&types)?;
+                    self.vals = saved;
+                }
+                self.pop_vals(&default_types)?;
+                self.unreachable();
+            }
+            Instruction::Return => {
+                let types = self.ctrls[0].end_types.clone();
+                self.pop_vals(&types)?;
+                self.unreachable();
+            }
+            Instruction::Drop => {
+                self.pop_val()?;
+            }
+            Instruction::Select => {
+                self.pop_expect(I32)?;
+                let first = self.pop_val()?;
+                let second = self.pop_val()?;
+                match (first, second) {
+                    (Some(a), Some(b)) if a != b => {
+                        return Err(format!(
+                            "type mismatch: select operands are {} and {}",
+                            b, a
+                        ));
+                    }
+                    _ if first.or(second).is_some_and(ValType::is_ref) => {
+                        return Err("type mismatch: select of references needs a type".to_string());
+                    }
+                    _ => self.push_val(first.or(second)),
+                }
+            }
+            Instruction::SelectTyped(types) => {
+                let ty = match types.as_slice() {
+                    [ty] => *ty,
+                    _ => return Err("invalid result arity".to_string()),
+                };
+                self.pop_expect(I32)?;
+                self.pop_expect(ty)?;
+                self.pop_expect(ty)?;
+                self.push_val(Some(ty));
+            }
+            Instruction::I32Const(_) => self.push_val(Some(I32)),
+            Instruction::I64Const(_) => self.push_val(Some(I64)),
+            Instruction::F32Const(_) => self.push_val(Some(F32)),
+            Instruction::F64Const(_) => self.push_val(Some(F64)),
+            Instruction::RefNull(ty) => self.push_val(Some((*ty).into())),
+            Instruction::RefIsNull => {
+                if let Some(ty) = self.pop_val()? {
+                    if !ty.is_ref() {
+                        return Err(format!("type mismatch: expected reference, found {}", ty));
+                    }
+                }
+                self.push_val(Some(I32));
+            }
+            Instruction::RefFunc(index) => {
+                if *index >= self.module.func_count() {
+                    return Err(format!("unknown function {}", index));
+                }
+                if !self.refs.contains(index) {
+                    return Err("undeclared function reference".to_string());
+                }
+                self.push_val(Some(FuncRef));
+            }
+            Instruction::I32Eqz => self.testop(I32)?,
+            Instruction::I32Eq
+            | Instruction::I32Ne
+            | Instruction::I32LtS
+            | Instruction::I32LtU
+            | Instruction::I32GtS
+            | Instruction::I32GtU
+            | Instruction::I32LeS
+            | Instruction::I32LeU
+            | Instruction::I32GeS
+            | Instruction::I32GeU => self.relop(I32)?,
+            Instruction::I32Clz | Instruction::I32Ctz | Instruction::I32Popcnt => self.unop(I32)?,
+            Instruction::I32Add
+            | Instruction::I32Sub
+            | Instruction::I32Mul
+            | Instruction::I32DivS
+            | Instruction::I32DivU
+            | Instruction::I32RemS
+            | Instruction::I32RemU
+            | Instruction::I32And
+            | Instruction::I32Or
+            | Instruction::I32Xor
+            | Instruction::I32Shl
+            | Instruction::I32ShrS
+            | Instruction::I32ShrU
+            | Instruction::I32Rotl
+            | Instruction::I32Rotr => self.binop(I32)?,
+            Instruction::I64Eqz => self.testop(I64)?,
+            Instruction::I64Eq
+            | Instruction::I64Ne
+            | Instruction::I64LtS
+            | Instruction::I64LtU
+            | Instruction::I64GtS
+            | Instruction::I64GtU
+            | Instruction::I64LeS
+            | Instruction::I64LeU
+            | Instruction::I64GeS
+            | Instruction::I64GeU => self.relop(I64)?,
+            Instruction::I64Clz | Instruction::I64Ctz | Instruction::I64Popcnt => self.unop(I64)?,
+            Instruction::I64Add
+            | Instruction::I64Sub
+            | Instruction::I64Mul
+            | Instruction::I64DivS
+            | Instruction::I64DivU
+            | Instruction::I64RemS
+            | Instruction::I64RemU
+            | Instruction::I64And
+            | Instruction::I64Or
+            | Instruction::I64Xor
+            | Instruction::I64Shl
+            | Instruction::I64ShrS
+            | Instruction::I64ShrU
+            | Instruction::I64Rotl
+            | Instruction::I64Rotr => self.binop(I64)?,
+            Instruction::F32Eq
+            | Instruction::F32Ne
+            | Instruction::F32Lt
+            | Instruction::F32Gt
+            | Instruction::F32Le
+            | Instruction::F32Ge => self.relop(F32)?,
+            Instruction::F32Abs
+            | Instruction::F32Neg
+            | Instruction::F32Ceil
+            | Instruction::F32Floor
+            | Instruction::F32Trunc
+            | Instruction::F32Nearest
+            | Instruction::F32Sqrt => self.unop(F32)?,
+            Instruction::F32Add
+            | Instruction::F32Sub
+            | Instruction::F32Mul
+            | Instruction::F32Div
+            | Instruction::F32Min
+            | Instruction::F32Max
+            | Instruction::F32Copysign => self.binop(F32)?,
+            Instruction::F64Eq
+            | Instruction::F64Ne
+            | Instruction::F64Lt
+            | Instruction::F64Gt
+            | Instruction::F64Le
+            | Instruction::F64Ge => self.relop(F64)?,
+            Instruction::F64Abs
+            | Instruction::F64Neg
+            | Instruction::F64Ceil
+            | Instruction::F64Floor
+            | Instruction::F64Trunc
+            | Instruction::F64Nearest
+            | Instruction::F64Sqrt => self.unop(F64)?,
+            Instruction::F64Add
+            | Instruction::F64Sub
+            | Instruction::F64Mul
+            | Instruction::F64Div
+            | Instruction::F64Min
+            | Instruction::F64Max
+            | Instruction::F64Copysign => self.binop(F64)?,
+            Instruction::I32WrapI64 => self.cvtop(I64, I32)?,
+            Instruction::I32TruncF32S
+            | Instruction::I32TruncF32U
+            | Instruction::I32ReinterpretF32
+            | Instruction::I32TruncSatF32S
+            | Instruction::I32TruncSatF32U => self.cvtop(F32, I32)?,
+            Instruction::I32TruncF64S
+            | Instruction::I32TruncF64U
+            | Instruction::I32TruncSatF64S
+            | Instruction::I32TruncSatF64U => self.cvtop(F64, I32)?,
+            Instruction::I64ExtendI32S | Instruction::I64ExtendI32U => self.cvtop(I32, I64)?,
+            Instruction::I64TruncF32S
+            | Instruction::I64TruncF32U
+            | Instruction::I64TruncSatF32S
+            | Instruction::I64TruncSatF32U => self.cvtop(F32, I64)?,
+            Instruction::I64TruncF64S
+            | Instruction::I64TruncF64U
+            | Instruction::I64ReinterpretF64
+            | Instruction::I64TruncSatF64S
+            | Instruction::I64TruncSatF64U => self.cvtop(F64, I64)?,
+            Instruction::F32ConvertI32S
+            | Instruction::F32ConvertI32U
+            | Instruction::F32ReinterpretI32 => self.cvtop(I32, F32)?,
+            Instruction::F32ConvertI64S | Instruction::F32ConvertI64U => self.cvtop(I64, F32)?,
+            Instruction::F32DemoteF64 => self.cvtop(F64, F32)?,
+            Instruction::F64ConvertI32S | Instruction::F64ConvertI32U => self.cvtop(I32, F64)?,
+            Instruction::F64ConvertI64S
+            | Instruction::F64ConvertI64U
+            | Instruction::F64ReinterpretI64 => self.cvtop(I64, F64)?,
+            Instruction::F64PromoteF32 => self.cvtop(F32, F64)?,
+            Instruction::I32Extend8S | Instruction::I32Extend16S => self.unop(I32)?,
+            Instruction::I64Extend8S | Instruction::I64Extend16S | Instruction::I64Extend32S => {
+                self.unop(I64)?
+            }
+            Instruction::I32Load(memarg) => self.load(memarg, 4, I32)?,
+            Instruction::I64Load(memarg) => self.load(memarg, 8, I64)?,
+            Instruction::F32Load(memarg) => self.load(memarg, 4, F32)?,
+            Instruction::F64Load(memarg) => self.load(memarg, 8, F64)?,
+            Instruction::I32Load8S(memarg) => self.load(memarg, 1, I32)?,
+            Instruction::I32Load8U(memarg) => self.load(memarg, 1, I32)?,
+            Instruction::I32Load16S(memarg) => self.load(memarg, 2, I32)?,
+            Instruction::I32Load16U(memarg) => self.load(memarg, 2, I32)?,
+            Instruction::I64Load8S(memarg) => self.load(memarg, 1, I64)?,
+            Instruction::I64Load8U(memarg) => self.load(memarg, 1, I64)?,
+            Instruction::I64Load16S(memarg) => self.load(memarg, 2, I64)?,
+            Instruction::I64Load16U(memarg) => self.load(memarg, 2, I64)?,
+            Instruction::I64Load32S(memarg) => self.load(memarg, 4, I64)?,
+            Instruction::I64Load32U(memarg) => self.load(memarg, 4, I64)?,
+            Instruction::I32Store(memarg) => self.store(memarg, 4, I32)?,
+            Instruction::I64Store(memarg) => self.store(memarg, 8, I64)?,
+            Instruction::F32Store(memarg) => self.store(memarg, 4, F32)?,
+            Instruction::F64Store(memarg) => self.store(memarg, 8, F64)?,
+            Instruction::I32Store8(memarg) => self.store(memarg, 1, I32)?,
+            Instruction::I32Store16(memarg) => self.store(memarg, 2, I32)?,
+            Instruction::I64Store8(memarg) => self.store(memarg, 1, I64)?,
+            Instruction::I64Store16(memarg) => self.store(memarg, 2, I64)?,
+            Instruction::I64Store32(memarg) => self.store(memarg, 4, I64)?,
+            Instruction::MemorySize => {
+                self.memory()?;
+                self.push_val(Some(I32));
+            }
+            Instruction::MemoryGrow => {
+                self.memory()?;
+                self.unop(I32)?;
+            }
+            Instruction::LocalGet(index) => {
+                let ty = self.local(*index)?;
+                self.push_val(Some(ty));
+            }
+            Instruction::LocalSet(index) => {
+                let ty = self.local(*index)?;
+                self.pop_expect(ty)?;
+            }
+            Instruction::LocalTee(index) => {
+                let ty = self.local(*index)?;
+                self.unop(ty)?;
+            }
+            Instruction::GlobalGet(index) => {
+                let global = self.global(*index)?;
+                self.push_val(Some(global.ty));
+            }
+            Instruction::GlobalSet(index) => {
+                let global = self.global(*index)?;
+                if !global.mutable {
+                    return Err("global is immutable".to_string());
+                }
+                self.pop_expect(global.ty)?;
+            }
+            Instruction::CallFunc(index) => {
+                let ty = match self.module.func_type(*index) {
+                    Some(ty) => ty,
+                    None => return Err(format!("unknown function {}", index)),
+                };
+                self.pop_vals(&ty.params)?;
+                self.push_vals(&ty.results);
+            }
+            Instruction::CallIndirect(ty, table) => {
+                ref_types_match(RefType::Func, self.table(*table)?)?;
+                let ty = match self.module.types.get(*ty) {
+                    Some(ty) => ty,
+                    None => return Err(format!("unknown type {}", ty)),
+                };
+                self.pop_expect(ValType::I32)?;
+                self.pop_vals(&ty.params)?;
+                self.push_vals(&ty.results);
+            }
+            Instruction::MemoryInit(data) => {
+                self.memory()?;
+                self.data(*data)?;
+                self.pop_vals(&[I32, I32, I32])?;
+            }
+            Instruction::DataDrop(data) => self.data(*data)?,
+            Instruction::MemoryCopy | Instruction::MemoryFill => {
+                self.memory()?;
+                self.pop_vals(&[I32, I32, I32])?;
+            }
+            Instruction::TableInit(elem, table) => {
+                let table = self.table(*table)?;
+                ref_types_match(table, self.elem(*elem)?)?;
+                self.pop_vals(&[I32, I32, I32])?;
+            }
+            Instruction::ElemDrop(elem) => {
+                self.elem(*elem)?;
+            }
+            Instruction::TableCopy(dst, src) => {
+                let dst = self.table(*dst)?;
+                ref_types_match(dst, self.table(*src)?)?;
+                self.pop_vals(&[I32, I32, I32])?;
+            }
+            Instruction::TableGet(table) => {
+                let ty = self.table(*table)?.into();
+                self.pop_expect(I32)?;
+                self.push_val(Some(ty));
+            }
+            Instruction::TableSet(table) => {
+                let ty = self.table(*table)?.into();
+                self.pop_vals(&[I32, ty])?;
+            }
+            Instruction::TableSize(table) => {
+                self.table(*table)?;
+                self.push_val(Some(I32));
+            }
+            Instruction::TableGrow(table) => {
+                let ty = self.table(*table)?.into();
+                self.pop_vals(&[ty, I32])?;
+                self.push_val(Some(I32));
+            }
+            Instruction::TableFill(table) => {
+                let ty = self.table(*table)?.into();
+                self.pop_vals(&[I32, ty, I32])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text::parse;
+
+    fn check(src: &str) -> std::result::Result<(), ValidationError> {
+        parse(src).unwrap().validate()
+    }
+
+    #[test]
+    fn accepts_valid_module() {
+        check(
+            r#"(memory 1)
+            (func $f (export "f") (param i32) (result f64)
+              (block $b (result f64)
+                (br_if $b (f64.const 1) (local.get 0))
+                (drop (f64.load (i32.const 0)))
+                (if (local.get 0) (then (return (f64.const 2))))
+                (loop (br 1 (f64.const 3))))
+              (drop (select (i32.const 1) (i32.const 2) (i32.const 0))))
+            (func (result i32) (i32.const 1) (return (i32.const 2)) (i32.add))"#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn rejects_type_mismatches() {
+        let err = check("(func (result i32) (i32.add (i32.const 1)))").unwrap_err();
+        assert_eq!((err.func, err.pc), (Some(0), Some(1)));
+        assert_eq!(err.message, "type mismatch: operand stack is empty");
+
+        let err = check("(func (i64.add (i64.const 1) (i32.const 2)) drop)").unwrap_err();
+        assert_eq!(err.message, "type mismatch: expected i64, found i32");
+
+        let err = check("(func (result i32) (i64.const 1))").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "function 0: type mismatch: expected i32, found i64"
+        );
+
+        let err = check("(func (i32.const 1))").unwrap_err();
+        assert!(err.message.starts_with("type mismatch"));
+
+        let err =
+            check("(func (if (result i32) (i32.const 1) (then (i32.const 1))) drop)").unwrap_err();
+        assert!(err.message.starts_with("type mismatch"));
+
+        let err =
+            check("(func (select (i32.const 1) (f32.const 2) (i32.const 0)) drop)").unwrap_err();
+        assert!(err.message.starts_with("type mismatch"));
+
+        let err = check("(func (block (param i64) (drop)) (i32.const 1))").unwrap_err();
+        assert_eq!(err.message, "type mismatch: operand stack is empty");
+
+        let err = check("(func (result i32 i32) (i32.const 1) (i64.const 2))").unwrap_err();
+        assert_eq!(err.message, "type mismatch: expected i32, found i64");
+    }
+
+    #[test]
+    fn rejects_invalid_globals() {
+        let err =
+            check("(global i32 (i32.const 0)) (func (global.set 0 (i32.const 1)))").unwrap_err();
+        assert_eq!(err.message, "global is immutable");
+
+        let err = check("(global (mut f32) (f32.const 0)) (func (global.set 0 (f64.const 1)))")
+            .unwrap_err();
+        assert_eq!(err.message, "type mismatch: expected f32, found f64");
+
+        let err = check("(global i32 (i64.const 0))").unwrap_err();
+        assert!(err.message.starts_with("type mismatch"));
+
+        let err =
+            check("(global (mut i32) (i32.const 0)) (global i32 (global.get 0))").unwrap_err();
+        assert_eq!(err.message, "constant expression required");
+
+        let err = check("(global i32 (global.get 0))").unwrap_err();
+        assert_eq!(err.message, "unknown global 0");
+
+        let err = check("(memory 1) (global i64 (i64.const 0)) (data (global.get 0))").unwrap_err();
+        assert_eq!(
+            err.message,
+            "type mismatch: data offset must be i32, found i64"
+        );
+    }
+
+    #[test]
+    fn rejects_overaligned_memory_access() {
+        check("(memory 1) (func (i64.store32 align=4 (i32.const 0) (i64.const 1)))").unwrap();
+        let err = check("(memory 1) (func (i32.load16_u align=4 (i32.const 0)) drop)").unwrap_err();
+        assert_eq!(err.message, "alignment must not be larger than natural");
+    }
+
+    #[test]
+    fn checks_reference_types() {
+        check(
+            r#"(table $t 1 externref)
+               (table $fs 1 funcref)
+               (elem $e (table $fs) (i32.const 0) funcref (ref.func $f))
+               (global (mut funcref) (ref.func $g))
+               (func $f (param externref) (result i32)
+                 (table.set $t (i32.const 0) (local.get 0))
+                 (drop (table.grow $t (ref.null extern) (i32.const 1)))
+                 (table.fill $t (i32.const 0) (table.get $t (i32.const 0)) (table.size $t))
+                 (drop (select (result funcref) (ref.func $f) (ref.func $g) (i32.const 0)))
+                 (table.init $fs $e (i32.const 0) (i32.const 0) (i32.const 0))
+                 (ref.is_null (local.get 0)))
+               (func $g (export "g") (drop (ref.func $g)))"#,
+        )
+        .unwrap();
+
+        let err = check("(func $f (drop (ref.func $f)))").unwrap_err();
+        assert_eq!(err.message, "undeclared function reference");
+
+        let err = check("(func (ref.is_null (i32.const 0)) drop)").unwrap_err();
+        assert_eq!(err.message, "type mismatch: expected reference, found i32");
+
+        let err = check("(func (select (ref.null func) (ref.null func) (i32.const 1)) drop)")
+            .unwrap_err();
+        assert_eq!(
+            err.message,
+            "type mismatch: select of references needs a type"
+        );
+
+        let err =
+            check("(func (select (result i32 i32) (i32.const 1) (i32.const 1) (i32.const 1)))")
+                .unwrap_err();
+        assert_eq!(err.message, "invalid result arity");
+
+        let err = check("(table 1 externref) (func (call_indirect (i32.const 0)))").unwrap_err();
+        assert_eq!(
+            err.message,
+            "type mismatch: expected funcref, found externref"
+        );
+
+        let err = check("(table 1 funcref) (func (table.set (i32.const 0) (ref.null extern)))")
+            .unwrap_err();
+        assert_eq!(
+            err.message,
+            "type mismatch: expected funcref, found externref"
+        );
+
+        let err = check("(table $a 1 funcref) (table $b 1 externref) (func (table.copy $a $b (i32.const 0) (i32.const 0) (i32.const 0)))")
+            .unwrap_err();
+        assert_eq!(
+            err.message,
+            "type mismatch: expected funcref, found externref"
+        );
+
+        let err = check("(table 1 funcref) (elem (i32.const 0) externref (ref.null extern))")
+            .unwrap_err();
+        assert_eq!(
+            err.message,
+            "type mismatch: expected funcref, found externref"
+        );
+
+        let err = check("(elem funcref (ref.null extern))").unwrap_err();
+        assert_eq!(
+            err.message,
+            "type mismatch: element segment of funcref has an element of type externref"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_indices() {
+        let err = check("(func (local.get 0) drop)").unwrap_err();
+        assert_eq!(err.message, "unknown local 0");
+
+        let err = check("(func (call 1))").unwrap_err();
+        assert_eq!(err.message, "unknown function 1");
+
+        let err = check("(func (br 1))").unwrap_err();
+        assert_eq!(err.message, "unknown label 1");
+
+        let err = check("(func (f64.load (i32.const 0)) drop)").unwrap_err();
+        assert_eq!(err.message, "unknown memory 0");
+
+        let err = check("(func (call_indirect (i32.const 0)))").unwrap_err();
+        assert_eq!(err.message, "unknown table 0");
+
+        let err = check("(table 1 funcref) (elem (i32.const 0) 1) (func)").unwrap_err();
+        assert_eq!(err.message, "unknown function 1");
+
+        let err = check(r#"(import "m" "f" (func)) (func (call 0 (i32.const 1)))"#).unwrap_err();
+        assert_eq!(err.func, Some(1));
+        assert!(err.message.starts_with("type mismatch"));
+
+        let err =
+            check("(memory 1) (func (memory.init 0 (i32.const 0) (i32.const 0) (i32.const 0)))")
+                .unwrap_err();
+        assert_eq!(err.message, "unknown data segment 0");
+
+        let err = check("(table 1 funcref) (func (elem.drop 0))").unwrap_err();
+        assert_eq!(err.message, "unknown elem segment 0");
+
+        let err = check("(start 1) (func)").unwrap_err();
+        assert_eq!(err.message, "unknown function 1");
+
+        let err = check("(start 0) (func (param i32))").unwrap_err();
+        assert_eq!(
+            err.message,
+            "start function must take no arguments and return nothing"
+        );
+
+        let err = check(r#"(func (export "a")) (func (export "a"))"#).unwrap_err();
+        assert_eq!(err.message, "duplicate export name \"a\"");
+
+        let untyped = Module {
+            functions: vec![Function::new(0, vec![], vec![])],
+            ..Module::default()
+        };
+        let err = untyped.validate().unwrap_err();
+        assert_eq!(
+            (err.func, err.message.as_str()),
+            (Some(0), "unknown type 0")
+        );
+    }
+}
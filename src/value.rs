@@ -0,0 +1,156 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValType {
+    I32,
+    I64,
+    F32,
+    F64,
+    FuncRef,
+    ExternRef,
+}
+
+impl ValType {
+    /// Whether values of the type are references rather than numbers.
+    pub fn is_ref(self) -> bool {
+        matches!(self, ValType::FuncRef | ValType::ExternRef)
+    }
+}
+
+impl fmt::Display for ValType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ValType::I32 => "i32",
+            ValType::I64 => "i64",
+            ValType::F32 => "f32",
+            ValType::F64 => "f64",
+            ValType::FuncRef => "funcref",
+            ValType::ExternRef => "externref",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The kind of reference a table holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefType {
+    Func,
+    Extern,
+}
+
+impl From<RefType> for ValType {
+    fn from(ty: RefType) -> Self {
+        match ty {
+            RefType::Func => ValType::FuncRef,
+            RefType::Extern => ValType::ExternRef,
+        }
+    }
+}
+
+impl fmt::Display for RefType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        ValType::from(*self).fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    /// The store address of a function, or null.
+    FuncRef(Option<usize>),
+    /// A handle chosen by the host for an object of its own, such as a file
+    /// or socket, which guest code can only pass around; or null.
+    ExternRef(Option<usize>),
+}
+
+impl Value {
+    /// The zero value of a type, used to initialize locals.
+    pub fn default(ty: ValType) -> Self {
+        match ty {
+            ValType::I32 => Value::I32(0),
+            ValType::I64 => Value::I64(0),
+            ValType::F32 => Value::F32(0.0),
+            ValType::F64 => Value::F64(0.0),
+            ValType::FuncRef => Value::FuncRef(None),
+            ValType::ExternRef => Value::ExternRef(None),
+        }
+    }
+
+    /// A reference of type `ty` to `addr`, or a null one.
+    pub fn reference(ty: RefType, addr: Option<usize>) -> Self {
+        match ty {
+            RefType::Func => Value::FuncRef(addr),
+            RefType::Extern => Value::ExternRef(addr),
+        }
+    }
+
+    /// What a reference refers to, or `None` if the value is a number.
+    pub fn ref_addr(&self) -> Option<Option<usize>> {
+        match *self {
+            Value::FuncRef(addr) | Value::ExternRef(addr) => Some(addr),
+            _ => None,
+        }
+    }
+
+    pub fn ty(&self) -> ValType {
+        match self {
+            Value::I32(_) => ValType::I32,
+            Value::I64(_) => ValType::I64,
+            Value::F32(_) => ValType::F32,
+            Value::F64(_) => ValType::F64,
+            Value::FuncRef(_) => ValType::FuncRef,
+            Value::ExternRef(_) => ValType::ExternRef,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::I32(v) => write!(f, "i32:{}", v),
+            Value::I64(v) => write!(f, "i64:{}", v),
+            Value::F32(v) => write!(f, "f32:{}", v),
+            Value::F64(v) => write!(f, "f64:{}", v),
+            Value::FuncRef(None) | Value::ExternRef(None) => write!(f, "{}:null", self.ty()),
+            Value::FuncRef(Some(addr)) | Value::ExternRef(Some(addr)) => {
+                write!(f, "{}:{}", self.ty(), addr)
+            }
+        }
+    }
+}
+
+macro_rules! impl_conversions {
+    ($($native:ty => $variant:ident),*) => {
+        $(
+            impl From<$native> for Value {
+                fn from(val: $native) -> Self {
+                    Value::$variant(val)
+                }
+            }
+
+            impl TryFrom<Value> for $native {
+                type Error = Value;
+
+                fn try_from(val: Value) -> Result<Self, Value> {
+                    match val {
+                        Value::$variant(v) => Ok(v),
+                        other => Err(other),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_conversions!(i32 => I32, i64 => I64, f32 => F32, f64 => F64);
+
+/// Booleans are represented as an `i32` that is 1 or 0.
+impl From<bool> for Value {
+    fn from(val: bool) -> Self {
+        Value::I32(val as i32)
+    }
+}
@@ -0,0 +1,568 @@
+//! Runner for `.wast` scripts, the format of the WebAssembly spec test suite:
+//! modules to instantiate, interleaved with assertions about how they behave.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::text::{ParseError, Parser};
+use crate::{Error, Instance, Linker, Module, RefType, ValType, Value};
+
+/// The `spectest` module the test suite imports from. Its print functions
+/// do nothing.
+const SPECTEST: &str = r#"
+(module
+  (global (export "global_i32") i32 (i32.const 666))
+  (global (export "global_i64") i64 (i64.const 666))
+  (global (export "global_f32") f32 (f32.const 666.6))
+  (global (export "global_f64") f64 (f64.const 666.6))
+  (table (export "table") 10 20 funcref)
+  (memory (export "memory") 1 2)
+  (func (export "print"))
+  (func (export "print_i32") (param i32))
+  (func (export "print_i64") (param i64))
+  (func (export "print_f32") (param f32))
+  (func (export "print_f64") (param f64))
+  (func (export "print_i32_f32") (param i32 f32))
+  (func (export "print_f64_f64") (param f64 f64)))
+"#;
+
+/// A command that didn't behave as the script expects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    /// The 1-based line the command starts on.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.line, self.message)
+    }
+}
+
+/// Outcome of running a script.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Number of commands that behaved as expected.
+    pub passed: usize,
+    pub failures: Vec<Failure>,
+}
+
+impl Report {
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Runs the commands of a script, carrying on past those that fail or that
+/// the runner doesn't support. Only a script that isn't a sequence of
+/// s-expressions is an error.
+///
+/// The messages of `assert_invalid` and `assert_malformed` aren't compared,
+/// as this crate words its errors differently from the reference
+/// interpreter; a trap matches when its message starts with the expected one.
+pub fn run(src: &str) -> Result<Report, ParseError> {
+    let mut parser = Parser::new(src)?;
+    let mut runner = Runner::new();
+    let mut report = Report::default();
+    while let Some(token) = parser.peek() {
+        let line = token.line;
+        let start = parser.position();
+        match runner.command(&mut parser) {
+            Ok(Ok(())) => report.passed += 1,
+            Ok(Err(message)) => report.failures.push(Failure { line, message }),
+            Err(err) => {
+                parser.rewind(start);
+                parser.skip_form()?;
+                report.failures.push(Failure {
+                    line: err.line,
+                    message: err.message,
+                });
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Whether a command behaved as expected, or else why not.
+type Outcome<T = ()> = Result<T, String>;
+
+enum ModuleSource {
+    /// A module in the text format, or the error parsing it.
+    Text(Result<Module, ParseError>),
+    Binary(Vec<u8>),
+    /// Source text for a module, which may be malformed.
+    Quote(Vec<u8>),
+}
+
+impl ModuleSource {
+    fn load(self) -> Result<Module, Error> {
+        match self {
+            ModuleSource::Text(module) => {
+                let module = module?;
+                module.validate()?;
+                Ok(module)
+            }
+            ModuleSource::Binary(bytes) => Module::from_binary(&bytes),
+            ModuleSource::Quote(src) => match String::from_utf8(src) {
+                Ok(src) => Module::from_text(&src),
+                Err(_) => Err(Error::Parse(ParseError {
+                    line: 1,
+                    col: 1,
+                    message: "malformed UTF-8 encoding".to_string(),
+                })),
+            },
+        }
+    }
+}
+
+enum Action {
+    Invoke {
+        module: Option<String>,
+        name: String,
+        args: Vec<Value>,
+    },
+    Get {
+        module: Option<String>,
+        name: String,
+    },
+}
+
+/// A result an assertion expects.
+enum Expected {
+    /// A value, with floats compared bit for bit.
+    Value(Value),
+    CanonicalNan(ValType),
+    ArithmeticNan(ValType),
+    /// Any non-null reference of the type.
+    NonNull(RefType),
+}
+
+impl Expected {
+    fn matches(&self, value: &Value) -> bool {
+        match (self, value) {
+            (Expected::Value(Value::F32(e)), Value::F32(v)) => e.to_bits() == v.to_bits(),
+            (Expected::Value(Value::F64(e)), Value::F64(v)) => e.to_bits() == v.to_bits(),
+            (Expected::Value(e), v) => e == v,
+            (Expected::CanonicalNan(ValType::F32), Value::F32(v)) => {
+                v.to_bits() & 0x7fff_ffff == 0x7fc0_0000
+            }
+            (Expected::CanonicalNan(ValType::F64), Value::F64(v)) => {
+                v.to_bits() & 0x7fff_ffff_ffff_ffff == 0x7ff8_0000_0000_0000
+            }
+            (Expected::ArithmeticNan(ValType::F32), Value::F32(v)) => {
+                v.is_nan() && v.to_bits() & 0x0040_0000 != 0
+            }
+            (Expected::ArithmeticNan(ValType::F64), Value::F64(v)) => {
+                v.is_nan() && v.to_bits() & 0x0008_0000_0000_0000 != 0
+            }
+            (Expected::NonNull(RefType::Func), Value::FuncRef(addr))
+            | (Expected::NonNull(RefType::Extern), Value::ExternRef(addr)) => addr.is_some(),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expected::Value(value) => value.fmt(f),
+            Expected::CanonicalNan(ty) => write!(f, "{}:nan:canonical", ty),
+            Expected::ArithmeticNan(ty) => write!(f, "{}:nan:arithmetic", ty),
+            Expected::NonNull(ty) => write!(f, "{}:non-null", ty),
+        }
+    }
+}
+
+fn join<T: fmt::Display>(items: &[T]) -> String {
+    let items: Vec<_> = items.iter().map(T::to_string).collect();
+    items.join(" ")
+}
+
+/// Instances defined so far, linked through a shared store.
+struct Runner {
+    linker: Linker,
+    instances: Vec<Instance>,
+    /// Indices into `instances` of the modules defined with a name.
+    names: HashMap<String, usize>,
+}
+
+impl Runner {
+    fn new() -> Self {
+        let mut linker = Linker::new();
+        let spectest = Module::from_text(SPECTEST).expect("spectest module is valid");
+        let spectest = linker
+            .instantiate(spectest)
+            .expect("spectest module instantiates");
+        linker
+            .register("spectest", &spectest)
+            .expect("spectest module registers");
+        Runner {
+            linker,
+            instances: Vec::new(),
+            names: HashMap::new(),
+        }
+    }
+
+    fn command(&mut self, parser: &mut Parser) -> Result<Outcome, ParseError> {
+        let outcome = match parser.peek_form() {
+            Some("module") => {
+                let (id, source) = module(parser)?;
+                self.define(id, source)
+            }
+            Some("register") => {
+                parser.form("register");
+                let name = parser.name()?;
+                let id = parser.id();
+                parser.rparen()?;
+                self.instance(id).and_then(|index| {
+                    let instance = &self.instances[index];
+                    match self.linker.register(&name, instance) {
+                        Ok(_) => Ok(()),
+                        Err(trap) => Err(trap.to_string()),
+                    }
+                })
+            }
+            Some("invoke") | Some("get") => {
+                let action = action(parser)?;
+                self.perform(&action)
+                    .and_then(|result| result.map(drop).map_err(|err| err.to_string()))
+            }
+            Some("assert_return") => {
+                parser.form("assert_return");
+                let action = action(parser)?;
+                let mut expected = Vec::new();
+                while !parser.at_rparen() {
+                    expected.push(result(parser)?);
+                }
+                parser.rparen()?;
+                self.assert_return(&action, &expected)
+            }
+            Some("assert_trap") => {
+                parser.form("assert_trap");
+                let result = if parser.peek_form() == Some("module") {
+                    let (_, source) = module(parser)?;
+                    Ok(self.instantiate(source).map(drop))
+                } else {
+                    let action = action(parser)?;
+                    self.perform(&action).map(|result| result.map(drop))
+                };
+                let message = message(parser)?;
+                result.and_then(|result| expect_trap(result, &message))
+            }
+            Some("assert_exhaustion") => {
+                parser.form("assert_exhaustion");
+                let action = action(parser)?;
+                let message = message(parser)?;
+                self.perform(&action)
+                    .and_then(|result| expect_trap(result, &message))
+            }
+            Some("assert_invalid") => {
+                parser.form("assert_invalid");
+                let (_, source) = module(parser)?;
+                message(parser)?;
+                match source.load() {
+                    Err(Error::Invalid(_)) => Ok(()),
+                    Err(err) => Err(format!("expected invalid module, found error: {}", err)),
+                    Ok(_) => Err("expected invalid module, but it validated".to_string()),
+                }
+            }
+            Some("assert_malformed") => {
+                parser.form("assert_malformed");
+                let (_, source) = module(parser)?;
+                message(parser)?;
+                match source.load() {
+                    Err(Error::Decode(_)) | Err(Error::Parse(_)) => Ok(()),
+                    Err(err) => Err(format!("expected malformed module, found error: {}", err)),
+                    Ok(_) => Err("expected malformed module, but it loaded".to_string()),
+                }
+            }
+            Some("assert_unlinkable") => {
+                parser.form("assert_unlinkable");
+                let (_, source) = module(parser)?;
+                message(parser)?;
+                match self.instantiate(source) {
+                    Err(Error::UnknownImport { .. })
+                    | Err(Error::IncompatibleImportType { .. }) => Ok(()),
+                    Err(err) => Err(format!("expected unlinkable module, found error: {}", err)),
+                    Ok(_) => Err("expected unlinkable module, but it linked".to_string()),
+                }
+            }
+            Some(command) => return parser.error(format!("unsupported command '{}'", command)),
+            None => return parser.error("expected command"),
+        };
+        Ok(outcome)
+    }
+
+    fn instantiate(&mut self, source: ModuleSource) -> Result<Instance, Error> {
+        let module = source.load()?;
+        self.linker.instantiate(module)
+    }
+
+    fn define(&mut self, id: Option<&str>, source: ModuleSource) -> Outcome {
+        let instance = self.instantiate(source).map_err(|err| err.to_string())?;
+        self.instances.push(instance);
+        if let Some(id) = id {
+            self.names.insert(id.to_string(), self.instances.len() - 1);
+        }
+        Ok(())
+    }
+
+    /// Index of the module named `id`, or of the latest one without a name.
+    fn instance(&self, id: Option<&str>) -> Outcome<usize> {
+        match id {
+            Some(id) => self
+                .names
+                .get(id)
+                .copied()
+                .ok_or_else(|| format!("unknown module ${}", id)),
+            None => match self.instances.len() {
+                0 => Err("no module defined".to_string()),
+                len => Ok(len - 1),
+            },
+        }
+    }
+
+    fn perform(&mut self, action: &Action) -> Outcome<Result<Vec<Value>, Error>> {
+        Ok(match action {
+            Action::Invoke { module, name, args } => {
+                let index = self.instance(module.as_deref())?;
+                self.instances[index].invoke(name, args)
+            }
+            Action::Get { module, name } => {
+                let index = self.instance(module.as_deref())?;
+                match self.instances[index].exported_global(name) {
+                    Ok(Some(value)) => Ok(vec![value]),
+                    Ok(None) => Err(Error::UnknownExport(name.clone())),
+                    Err(trap) => Err(trap.into()),
+                }
+            }
+        })
+    }
+
+    fn assert_return(&mut self, action: &Action, expected: &[Expected]) -> Outcome {
+        let results = self.perform(action)?.map_err(|err| err.to_string())?;
+        let matches = results.len() == expected.len()
+            && expected.iter().zip(&results).all(|(e, v)| e.matches(v));
+        if matches {
+            Ok(())
+        } else {
+            Err(format!(
+                "expected [{}], found [{}]",
+                join(expected),
+                join(&results)
+            ))
+        }
+    }
+}
+
+fn expect_trap<T>(result: Result<T, Error>, message: &str) -> Outcome {
+    match result {
+        Err(Error::Trap(trap)) if trap.to_string().starts_with(message) => Ok(()),
+        Err(err) => Err(format!("expected trap {:?}, found error: {}", message, err)),
+        Ok(_) => Err(format!("expected trap {:?}, but it returned", message)),
+    }
+}
+
+/// Parses the expected message closing an assertion.
+fn message(parser: &mut Parser) -> Result<String, ParseError> {
+    let message = parser.string()?;
+    parser.rparen()?;
+    Ok(String::from_utf8_lossy(&message).into_owned())
+}
+
+/// Parses a `(module $id? ...)` form. Errors in the fields of a text module
+/// are part of the source, for assertions about malformed modules.
+fn module<'a>(parser: &mut Parser<'a>) -> Result<(Option<&'a str>, ModuleSource), ParseError> {
+    let start = parser.position();
+    if !parser.form("module") {
+        return parser.error("expected module");
+    }
+    let id = parser.id();
+    let source = match parser.peek_atom() {
+        Some("binary") => ModuleSource::Binary(strings(parser)?),
+        Some("quote") => ModuleSource::Quote(strings(parser)?),
+        _ => match parser.module() {
+            Ok(module) => ModuleSource::Text(Ok(module)),
+            Err(err) => {
+                parser.rewind(start);
+                parser.skip_form()?;
+                return Ok((id, ModuleSource::Text(Err(err))));
+            }
+        },
+    };
+    parser.rparen()?;
+    Ok((id, source))
+}
+
+/// Parses a `binary` or `quote` keyword and the strings after it, joined.
+fn strings(parser: &mut Parser) -> Result<Vec<u8>, ParseError> {
+    parser.atom()?;
+    let mut bytes = Vec::new();
+    while !parser.at_rparen() {
+        bytes.extend(parser.string()?);
+    }
+    Ok(bytes)
+}
+
+fn action(parser: &mut Parser) -> Result<Action, ParseError> {
+    if parser.form("invoke") {
+        let module = parser.id().map(str::to_string);
+        let name = parser.name()?;
+        let mut args = Vec::new();
+        while !parser.at_rparen() {
+            match result(parser)? {
+                Expected::Value(value) => args.push(value),
+                _ => return parser.error("expected constant"),
+            }
+        }
+        parser.rparen()?;
+        Ok(Action::Invoke { module, name, args })
+    } else if parser.form("get") {
+        let module = parser.id().map(str::to_string);
+        let name = parser.name()?;
+        parser.rparen()?;
+        Ok(Action::Get { module, name })
+    } else {
+        parser.error("expected action")
+    }
+}
+
+/// Parses a constant, or one of the patterns an assertion may expect.
+fn result(parser: &mut Parser) -> Result<Expected, ParseError> {
+    parser.lparen()?;
+    let expected = match parser.atom()? {
+        "i32.const" => Expected::Value(Value::I32(parser.i32()?)),
+        "i64.const" => Expected::Value(Value::I64(parser.i64()?)),
+        "f32.const" => match nan_pattern(parser, ValType::F32)? {
+            Some(expected) => expected,
+            None => Expected::Value(Value::F32(parser.f32()?)),
+        },
+        "f64.const" => match nan_pattern(parser, ValType::F64)? {
+            Some(expected) => expected,
+            None => Expected::Value(Value::F64(parser.f64()?)),
+        },
+        "ref.null" => {
+            let ty = match parser.atom()? {
+                "func" | "funcref" => RefType::Func,
+                "extern" | "externref" => RefType::Extern,
+                _ => return parser.error("expected heap type"),
+            };
+            Expected::Value(Value::reference(ty, None))
+        }
+        "ref.extern" if parser.at_rparen() => Expected::NonNull(RefType::Extern),
+        "ref.extern" => Expected::Value(Value::ExternRef(Some(parser.u32()? as usize))),
+        "ref.func" => Expected::NonNull(RefType::Func),
+        op => return parser.error(format!("unsupported constant '{}'", op)),
+    };
+    parser.rparen()?;
+    Ok(expected)
+}
+
+/// Parses `nan:canonical` or `nan:arithmetic`, if that's what follows.
+fn nan_pattern(parser: &mut Parser, ty: ValType) -> Result<Option<Expected>, ParseError> {
+    let expected = match parser.peek_atom() {
+        Some("nan:canonical") => Expected::CanonicalNan(ty),
+        Some("nan:arithmetic") => Expected::ArithmeticNan(ty),
+        _ => return Ok(None),
+    };
+    parser.atom()?;
+    Ok(Some(expected))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(src: &str) -> Report {
+        run(src).unwrap()
+    }
+
+    #[test]
+    fn runs_commands() {
+        let report = report(
+            r#"
+            (module $m
+              (global (export "g") i32 (i32.const 7))
+              (func (export "add") (param i32 i32) (result i32)
+                (i32.add (local.get 0) (local.get 1)))
+              (func (export "div") (param i32 i32) (result i32)
+                (i32.div_s (local.get 0) (local.get 1)))
+              (func (export "nan") (result f32) (f32.div (f32.const 0) (f32.const 0)))
+              (func (export "id") (param externref) (result externref) (local.get 0))
+              (func $loop (export "loop") (call $loop)))
+            (register "m" $m)
+            (module
+              (import "m" "add" (func $add (param i32 i32) (result i32)))
+              (import "spectest" "print_i32" (func $print (param i32)))
+              (func (export "inc") (param i32) (result i32)
+                (call $add (local.get 0) (i32.const 1))))
+
+            (assert_return (invoke "inc" (i32.const 41)) (i32.const 42))
+            (assert_return (get $m "g") (i32.const 7))
+            (assert_return (invoke $m "nan") (f32.const nan:canonical))
+            (assert_return (invoke $m "id" (ref.extern 3)) (ref.extern 3))
+            (assert_return (invoke $m "id" (ref.null extern)) (ref.null extern))
+            (invoke $m "add" (i32.const 1) (i32.const 2))
+            (assert_trap (invoke $m "div" (i32.const 1) (i32.const 0)) "integer divide by zero")
+            (assert_exhaustion (invoke $m "loop") "call stack exhausted")
+            (assert_trap (module (func $f unreachable) (start $f)) "unreachable")
+            (assert_invalid (module (func (result i32) (i64.const 0))) "type mismatch")
+            (assert_malformed (module quote "(func (i32.const))") "unexpected token")
+            (assert_malformed (module binary "\00asm" "\02\00\00\00") "unknown binary version")
+            (assert_unlinkable (module (import "m" "missing" (func))) "unknown import")
+            "#,
+        );
+        assert_eq!(report.failures, vec![]);
+        assert_eq!(report.passed, 16);
+    }
+
+    #[test]
+    fn reports_failures_and_carries_on() {
+        let report = report(
+            r#"(module (func (export "one") (result i32) (i32.const 1)))
+            (assert_return (invoke "one") (i32.const 2))
+            (assert_trap (invoke "one") "unreachable")
+            (assert_invalid (module (func)) "type mismatch")
+            (assert_return (invoke "two"))
+            (assert_return (invoke "one") (v128.const i32x4 0 0 0 0))
+            (script)
+            (assert_return (invoke "one") (i32.const 1))"#,
+        );
+        let failures: Vec<_> = report.failures.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            failures,
+            vec![
+                "2: expected [i32:2], found [i32:1]",
+                "3: expected trap \"unreachable\", but it returned",
+                "4: expected invalid module, but it validated",
+                "5: unknown export \"two\"",
+                "6: unsupported constant 'v128.const'",
+                "7: unsupported command 'script'",
+            ]
+        );
+        assert_eq!(report.passed, 2);
+        assert!(!report.is_success());
+    }
+
+    #[test]
+    fn compares_float_bits() {
+        let report = report(
+            r#"(module
+              (func (export "neg_zero") (result f64) (f64.const -0))
+              (func (export "nan") (result f64) (f64.const -nan:0x4)))
+            (assert_return (invoke "neg_zero") (f64.const 0))
+            (assert_return (invoke "neg_zero") (f64.const -0))
+            (assert_return (invoke "nan") (f64.const nan:arithmetic))
+            (assert_return (invoke "nan") (f64.const -nan:0x4))"#,
+        );
+        let lines: Vec<_> = report.failures.iter().map(|f| f.line).collect();
+        assert_eq!(lines, vec![4, 6]);
+    }
+
+    #[test]
+    fn rejects_scripts_that_are_not_s_expressions() {
+        assert!(run("(module) foo").is_err());
+        assert!(run("(module \"unterminated)").is_err());
+    }
+}